@@ -0,0 +1,78 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::{KvEngine, KvStore};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const KEYS: usize = 100;
+
+// a JSON document of about 1KB, as compressible as typical values are
+fn value(i: usize) -> String {
+    let items: Vec<String> = (0..16)
+        .map(|j| {
+            format!(
+                r#"{{"id":{},"name":"item-{}","tags":["red","green"]}}"#,
+                i + j,
+                j
+            )
+        })
+        .collect();
+    format!(r#"{{"user":{},"items":[{}]}}"#, i, items.join(","))
+}
+
+fn open(dir: &Path, threshold: Option<usize>) -> KvStore {
+    let mut store = KvStore::open(dir).unwrap();
+    store.set_compression_threshold(threshold);
+    store
+}
+
+fn disk_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+fn compression(c: &mut Criterion) {
+    for &(name, threshold) in &[("plain", None), ("lz4", Some(256))] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = open(temp_dir.path(), threshold);
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), value(i)).unwrap();
+        }
+        drop(store);
+        // compressed values are stored in base64, a third larger than the LZ4 output
+        println!(
+            "{}: {} bytes on disk, base64 included",
+            name,
+            disk_bytes(temp_dir.path())
+        );
+
+        c.bench_function(&format!("set_{}", name), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    (open(temp_dir.path(), threshold), temp_dir)
+                },
+                |(mut store, _temp_dir)| {
+                    for i in 0..KEYS {
+                        store.set(format!("key{}", i), value(i)).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+
+        let mut store = open(temp_dir.path(), threshold);
+        c.bench_function(&format!("get_{}", name), |b| {
+            b.iter(|| {
+                for i in 0..KEYS {
+                    store.get(format!("key{}", i)).unwrap();
+                }
+            })
+        });
+    }
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
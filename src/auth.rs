@@ -0,0 +1,186 @@
+//! Authentication of the connections to a `KvServer`.
+//!
+//! A server either shares a single token with its clients, or checks user
+//! names and passwords against a file of salted hashes, one user per line:
+//!
+//! ```text
+//! alice:<iterations>:<salt in hex>:<PBKDF2-HMAC-SHA256 of the password in hex>
+//! ```
+//!
+//! Lines are produced by [`hash_password`], or by `kvs passwd USER`.
+//! What each user may then do is restricted by an [`Acl`].
+
+use crate::{KvsErr, Result};
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+mod acl;
+pub use self::acl::{Acl, AclFile, Permission};
+
+// PBKDF2 rounds of the hashes made by `hash_password`
+const ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// What a client presents to authenticate a connection.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Credentials {
+    Token(String),
+    Password { user: String, password: String },
+}
+
+// keep secrets out of the request logs
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Token(_) => write!(f, "Token(..)"),
+            Credentials::Password { user, .. } => write!(f, "Password {{ user: {:?}, .. }}", user),
+        }
+    }
+}
+
+/// How a server checks the credentials of new connections.
+#[derive(Clone)]
+pub enum Auth {
+    /// Every client presents the same token.
+    Token(String),
+    /// Clients log in as one of these users.
+    Users(Users),
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Auth::Token(_) => write!(f, "Token(..)"),
+            Auth::Users(users) => write!(f, "Users({} users)", users.hashes.len()),
+        }
+    }
+}
+
+impl Auth {
+    /// Check `credentials`, failing with `KvsErr::AuthFailed` when they are wrong.
+    /// Return the name of the user logged in, if they give one.
+    pub fn check(&self, credentials: &Credentials) -> Result<Option<String>> {
+        let valid = match (self, credentials) {
+            (Auth::Token(expected), Credentials::Token(token)) => {
+                constant_time_eq(expected.as_bytes(), token.as_bytes())
+            }
+            (Auth::Users(users), Credentials::Password { user, password }) => {
+                users.verify(user, password)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(KvsErr::AuthFailed);
+        }
+        match credentials {
+            Credentials::Token(_) => Ok(None),
+            Credentials::Password { user, .. } => Ok(Some(user.clone())),
+        }
+    }
+}
+
+/// Salted password hashes of the users allowed to connect.
+#[derive(Clone, Default)]
+pub struct Users {
+    // user name to (iterations, salt, hash)
+    hashes: BTreeMap<String, (NonZeroU32, Vec<u8>, Vec<u8>)>,
+}
+
+impl Users {
+    /// Read a users file. Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Users> {
+        let path = path.as_ref();
+        let mut users = Users::default();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                KvsErr::StringErr(format!(
+                    "invalid line {} in users file {}",
+                    number + 1,
+                    path.display()
+                ))
+            };
+            let fields: Vec<&str> = line.split(':').collect();
+            let (user, iterations, salt, hash) = match fields[..] {
+                [user, iterations, salt, hash] => (user, iterations, salt, hash),
+                _ => return Err(invalid()),
+            };
+            let iterations = iterations.parse().map_err(|_| invalid())?;
+            let salt = from_hex(salt).ok_or_else(invalid)?;
+            let hash = from_hex(hash).ok_or_else(invalid)?;
+            users
+                .hashes
+                .insert(user.to_owned(), (iterations, salt, hash));
+        }
+        Ok(users)
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.hashes.get(user) {
+            Some((iterations, salt, hash)) => pbkdf2::verify(
+                PBKDF2_HMAC_SHA256,
+                *iterations,
+                salt,
+                password.as_bytes(),
+                hash,
+            )
+            .is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Return the users file line for `user` logging in with `password`, with a fresh salt.
+pub fn hash_password(user: &str, password: &str) -> Result<String> {
+    if user.is_empty() || user.contains(':') || user.contains(char::is_whitespace) {
+        return Err(KvsErr::StringErr(format!("invalid user name: {:?}", user)));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| KvsErr::StringErr("no randomness available".to_owned()))?;
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}:{}:{}:{}",
+        user,
+        iterations,
+        to_hex(&salt),
+        to_hex(&hash)
+    ))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
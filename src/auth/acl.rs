@@ -0,0 +1,229 @@
+use crate::common::Request;
+use crate::{KvsErr, Result};
+use log::{error, info};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+// how often an `AclFile` looks for changes on disk
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a rule lets its users do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Get,
+    /// Covers single and batched writes.
+    Set,
+    Rm,
+    Scan,
+    /// Backups, info, stats, compaction, flushing and replication.
+    Admin,
+}
+
+/// Access control list: the requests each user may send.
+///
+/// Written in TOML as a list of rules, anything not allowed by one is denied:
+///
+/// ```toml
+/// [[rule]]
+/// user = "alice"
+/// commands = ["get", "set", "rm", "scan"]
+/// prefixes = ["team-a/"]
+///
+/// [[rule]]
+/// user = "*"          # everyone, including connections without a user name
+/// commands = ["get"]  # on every key, since no prefixes are given
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    user: String,
+    commands: Vec<Permission>,
+    #[serde(default = "every_key")]
+    prefixes: Vec<String>,
+}
+
+fn every_key() -> Vec<String> {
+    vec![String::new()]
+}
+
+impl Acl {
+    /// Parse an ACL. Admin requests do not name keys, so rules granting them may
+    /// not be limited to prefixes.
+    pub fn parse(text: &str) -> Result<Acl> {
+        let acl: Acl =
+            toml::from_str(text).map_err(|e| KvsErr::StringErr(format!("invalid ACL: {}", e)))?;
+        if let Some(rule) = acl
+            .rules
+            .iter()
+            .find(|rule| rule.commands.contains(&Permission::Admin) && rule.prefixes != every_key())
+        {
+            return Err(KvsErr::StringErr(format!(
+                "invalid ACL: the rule for {} grants admin but has prefixes, give admin in a rule without them",
+                rule.user
+            )));
+        }
+        Ok(acl)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Acl> {
+        Acl::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Whether `user` may use `permission` on `key`.
+    pub fn allows(&self, user: Option<&str>, permission: Permission, key: &str) -> bool {
+        self.matching(user, permission)
+            .any(|rule| rule.prefixes.iter().any(|prefix| key.starts_with(prefix)))
+    }
+
+    /// The prefixes of the keys `user` may use `permission` on, sorted and without
+    /// the ones inside another.
+    pub(crate) fn prefixes(&self, user: Option<&str>, permission: Permission) -> Vec<String> {
+        let mut all: Vec<&String> = self
+            .matching(user, permission)
+            .flat_map(|rule| &rule.prefixes)
+            .collect();
+        all.sort();
+        let mut prefixes: Vec<String> = Vec::new();
+        for prefix in all {
+            if !prefixes.last().is_some_and(|last| prefix.starts_with(last)) {
+                prefixes.push(prefix.clone());
+            }
+        }
+        prefixes
+    }
+
+    /// Whether `user` may use `permission` on at least some keys.
+    pub fn allows_any(&self, user: Option<&str>, permission: Permission) -> bool {
+        self.matching(user, permission).next().is_some()
+    }
+
+    fn matching<'a>(
+        &'a self,
+        user: Option<&'a str>,
+        permission: Permission,
+    ) -> impl Iterator<Item = &'a Rule> {
+        self.rules.iter().filter(move |rule| {
+            (rule.user == "*" || Some(rule.user.as_str()) == user)
+                && rule.commands.contains(&permission)
+        })
+    }
+
+    /// Refuse `request` unless `user` may send it. Scans are allowed when some
+    /// prefix may be scanned, their results are filtered by the server.
+    pub(crate) fn check(&self, user: Option<&str>, request: &Request) -> Result<()> {
+        let allowed = match request {
+            Request::Get { key } => self.allows(user, Permission::Get, key),
+            Request::Set { key, .. } => self.allows(user, Permission::Set, key),
+            Request::SetBatch { pairs } => pairs
+                .iter()
+                .all(|(key, _)| self.allows(user, Permission::Set, key)),
+            Request::Remove { key } => self.allows(user, Permission::Rm, key),
+            Request::Scan { .. } => self.allows_any(user, Permission::Scan),
+            Request::Auth { .. } => true,
+            Request::Backup { .. }
+            | Request::Info
+            | Request::Stats
+            | Request::Compact
+            | Request::Flush
+            | Request::Replicate { .. } => self.allows_any(user, Permission::Admin),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvsErr::PermissionDenied(format!(
+                "{} may not send this request",
+                user.unwrap_or("anonymous")
+            )))
+        }
+    }
+}
+
+/// An `Acl` read from a file, and read again whenever the file changes.
+#[derive(Debug)]
+pub struct AclFile {
+    path: PathBuf,
+    state: RwLock<Loaded>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+    acl: Arc<Acl>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl AclFile {
+    pub fn open(path: impl Into<PathBuf>) -> Result<AclFile> {
+        let path = path.into();
+        let modified = modified(&path);
+        let acl = Acl::load(&path)?;
+        Ok(AclFile {
+            path,
+            state: RwLock::new(Loaded {
+                acl: Arc::new(acl),
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Read the file again now. On error the rules in use are kept.
+    pub fn reload(&self) -> Result<()> {
+        let modified = modified(&self.path);
+        let acl = Acl::load(&self.path)?;
+        let mut state = self.state()?;
+        state.acl = Arc::new(acl);
+        state.modified = modified;
+        state.checked = Instant::now();
+        Ok(())
+    }
+
+    /// The rules in use, reloaded first if the file changed.
+    pub fn current(&self) -> Result<Arc<Acl>> {
+        {
+            let state = self
+                .state
+                .read()
+                .map_err(|_| KvsErr::StringErr("ACL lock poisoned".to_owned()))?;
+            if state.checked.elapsed() < RELOAD_INTERVAL {
+                return Ok(Arc::clone(&state.acl));
+            }
+        }
+        let mut state = self.state()?;
+        state.checked = Instant::now();
+        let modified = modified(&self.path);
+        if modified != state.modified {
+            match Acl::load(&self.path) {
+                Ok(acl) => {
+                    info!("Reloaded ACL from {}", self.path.display());
+                    state.acl = Arc::new(acl);
+                    state.modified = modified;
+                }
+                // keep the old rules rather than locking everybody out
+                Err(e) => error!("Keeping the previous ACL: {}", e),
+            }
+        }
+        Ok(Arc::clone(&state.acl))
+    }
+
+    fn state(&self) -> Result<std::sync::RwLockWriteGuard<'_, Loaded>> {
+        self.state
+            .write()
+            .map_err(|_| KvsErr::StringErr("ACL lock poisoned".to_owned()))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
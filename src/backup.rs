@@ -0,0 +1,109 @@
+use crate::bulk::{self, Digest};
+use crate::engines::{self, Keyring, ENGINE_MARKER, MANIFEST};
+use crate::{KvsErr, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Replace the data directory `dir` with the backup in `backup`.
+///
+/// The backup is copied next to `dir` and opened there first, so a damaged
+/// backup is rejected before anything in `dir` is touched. Returns the digest
+/// of the restored data. A backup of an encrypted kvs store needs its
+/// `keyring`. No engine may have `dir` open while it is restored.
+pub fn restore(
+    backup: impl AsRef<Path>,
+    dir: impl AsRef<Path>,
+    keyring: Option<&Keyring>,
+) -> Result<Digest> {
+    restore_chain(&[backup], dir, keyring)
+}
+
+/// Like `restore`, for a full backup followed by the increments taken on top of it, in order.
+pub fn restore_chain<P: AsRef<Path>>(
+    backups: &[P],
+    dir: impl AsRef<Path>,
+    keyring: Option<&Keyring>,
+) -> Result<Digest> {
+    let chain: Vec<&Path> = backups.iter().map(AsRef::as_ref).collect();
+    let dir = dir.as_ref();
+    let base = chain
+        .first()
+        .ok_or_else(|| KvsErr::StringErr("no backup to restore".to_owned()))?;
+    let engine = fs::read_to_string(base.join(ENGINE_MARKER))
+        .map_err(|_| KvsErr::StringErr(format!("{} is not a backup directory", base.display())))?;
+
+    let staging = sibling(dir, "restoring")?;
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let staged = if base.join(MANIFEST).exists() {
+        engines::assemble(&chain, &staging)
+    } else if chain.len() > 1 {
+        Err(KvsErr::StringErr(format!(
+            "{} backups can not be incremental",
+            engine.trim()
+        )))
+    } else {
+        copy_dir(base, &staging)
+    };
+    let digest = match staged.and_then(|()| verify(engine.trim(), &staging, keyring)) {
+        Ok(digest) => digest,
+        Err(e) => {
+            fs::remove_dir_all(&staging)?;
+            return Err(e);
+        }
+    };
+
+    // keep the old data until the new directory is in place
+    let old = sibling(dir, "old")?;
+    if dir.exists() {
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        fs::rename(dir, &old)?;
+    }
+    fs::rename(&staging, dir)?;
+    if old.exists() {
+        fs::remove_dir_all(&old)?;
+    }
+    Ok(digest)
+}
+
+// open the staged copy, checking the kvs logs are intact first
+fn verify(engine: &str, dir: &Path, keyring: Option<&Keyring>) -> Result<Digest> {
+    if engine == "kvs" {
+        let report = engines::fsck_with_keyring(dir, keyring, false)?;
+        if !report.is_clean() {
+            return Err(KvsErr::StringErr(format!(
+                "backup is damaged, not restoring:\n{}",
+                report
+            )));
+        }
+    }
+    let mut engine = engines::open_engine_with_keyring(engine, dir, keyring)?;
+    let digest = bulk::digest(&mut engine)?;
+    engine.flush()?;
+    Ok(digest)
+}
+
+// `<dir>.<suffix>`, in the same parent so it can be renamed into place
+fn sibling(dir: &Path, suffix: &str) -> Result<PathBuf> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| KvsErr::StringErr(format!("invalid directory: {}", dir.display())))?;
+    Ok(dir.with_file_name(format!("{}.{}", name.to_string_lossy(), suffix)))
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
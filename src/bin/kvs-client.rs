@@ -1,105 +1,427 @@
-use clap::arg_enum;
-use kvs::engines::KvEngine;
-use kvs::engines::SledKvsEngine;
-use kvs::*;
-use log::LevelFilter;
-use log::{error, info, warn};
-use std::env::current_dir;
-use std::fs;
+use kvs::auth::Credentials;
+use kvs::bulk::{self, Format};
+use kvs::tls::ClientTls;
+use kvs::{KvClient, KvsErr, Result, ShardedKvClient, Shell};
+use std::env;
+use std::io::{self, BufReader, BufWriter};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "kvs-server")]
+#[structopt(name = "kvs-client")]
 struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
     #[structopt(
         long,
-        help = "Sets the listening address",
-        value_name = "addr",
-        default_value = DEFAULT_LISTENING_ADDRESS,
-        parse(try_from_str)
+        global = true,
+        help = "Authenticates with a token shared with the server",
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "user"
     )]
-    addr: SocketAddr,
+    token: Option<String>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Authenticates as this user, with the password in KVS_PASSWORD",
+        value_name = "USER"
+    )]
+    user: Option<String>,
     #[structopt(
         long,
-        help = "Sets the storage engine",
-        value_name = "engine",
-        possible_values = &Engine::variants()
+        global = true,
+        help = "Connects over TLS, trusting the CA certificates of this PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
     )]
-    engine: Option<Engine>,
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Presents this PEM certificate to servers requiring client certificates",
+        value_name = "FILE",
+        requires_all = &["tls-key", "tls-ca"],
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        help = "Private key of --tls-cert, in PEM",
+        value_name = "FILE",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
 }
 
-arg_enum! {
-    #[allow(non_camel_case_types)]
-    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-    enum Engine {
-        kvs,
-        sled
-    }
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(name = "VALUE", help = "The string value of the key")]
+        value: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "export",
+        about = "Write every key/value pair to stdout in key order"
+    )]
+    Export {
+        #[structopt(
+            long,
+            help = "Sets the dump format",
+            value_name = "FORMAT",
+            default_value = "jsonl",
+            possible_values = &["jsonl", "csv"]
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "import", about = "Load key/value pairs from stdin")]
+    Import {
+        #[structopt(
+            long,
+            help = "Sets the dump format",
+            value_name = "FORMAT",
+            default_value = "jsonl",
+            possible_values = &["jsonl", "csv"]
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Number of pairs written at once",
+            value_name = "N",
+            default_value = "1000"
+        )]
+        batch_size: usize,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "shell", about = "Start an interactive shell")]
+    Shell {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "admin", about = "Send an administrative request to the server")]
+    Admin {
+        #[structopt(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum AdminCommand {
+    #[structopt(
+        name = "backup",
+        about = "Back the server's data up into a directory on the server"
+    )]
+    Backup {
+        #[structopt(name = "DIR", help = "Empty or missing backup directory")]
+        dir: String,
+        #[structopt(
+            long,
+            help = "Only copy what changed since the backup in PARENT",
+            value_name = "PARENT"
+        )]
+        incremental_from: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "info", about = "Describe the server and its storage engine")]
+    Info {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Print the statistics of the storage engine as JSON"
+    )]
+    Stats {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "compact",
+        about = "Reclaim the space taken by stale records now"
+    )]
+    Compact {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "flush", about = "Sync the storage engine to disk")]
+    Flush {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "Move keys to the servers owning them after the cluster changed; stop writes while it runs"
+    )]
+    Rebalance {
+        #[structopt(
+            long,
+            help = "Adds a server of the cluster",
+            value_name = "IP:PORT",
+            required = true,
+            number_of_values = 1
+        )]
+        node: Vec<SocketAddr>,
+        #[structopt(
+            long,
+            help = "Moves every key off this server, which is leaving the cluster",
+            value_name = "IP:PORT",
+            number_of_values = 1
+        )]
+        drain: Vec<SocketAddr>,
+    },
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
+    let opt = Opt::from_args();
+    match run(opt) {
+        Ok(()) => {}
+        Err(KvsErr::KeyNotFound) => {
+            eprintln!("Key not found");
+            exit(1);
         }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
+        Err(e) => {
+            eprintln!("{}", e);
             exit(1);
         }
-        run(opt)
-    });
-    if let Err(e) = res {
-        error!("{}", e);
-        exit(1);
     }
 }
 
 fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
-    info!("kv-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
-    info!("Current dir: {:?}", current_dir()?.as_mut_os_string());
-    let data_dir = "/tmp/";
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
-    match engine {
-        Engine::kvs => run_with_engine(
-            KvStore::open(PathBuf::from(format!("{}{}", data_dir, "kvs/kvs")))?,
-            opt.addr,
-        ),
-        Engine::sled => run_with_engine(
-            SledKvsEngine::new(sled::open(PathBuf::from(format!(
-                "{}{}",
-                data_dir, "sled/seld"
-            )))?),
-            opt.addr,
-        ),
+    let credentials = credentials(&opt)?;
+    let tls = tls(&opt)?;
+    match opt.command {
+        Command::Get { key, addr } => {
+            let mut client = connect(addr, &credentials, &tls)?;
+            if let Some(value) = client.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        Command::Set { key, value, addr } => {
+            let mut client = connect(addr, &credentials, &tls)?;
+            client.set(key, value)?;
+        }
+        Command::Remove { key, addr } => {
+            let mut client = connect(addr, &credentials, &tls)?;
+            client.remove(key)?;
+        }
+        Command::Export { format, addr } => {
+            let mut client = connect(addr, &credentials, &tls)?;
+            let stdout = io::stdout();
+            let count = bulk::export(&mut client, BufWriter::new(stdout.lock()), format)?;
+            eprintln!("Exported {} pairs", count);
+        }
+        Command::Import {
+            format,
+            batch_size,
+            addr,
+        } => {
+            let mut client = connect(addr, &credentials, &tls)?;
+            let stdin = io::stdin();
+            let count = bulk::import(
+                &mut client,
+                BufReader::new(stdin.lock()),
+                format,
+                batch_size,
+                |count| eprintln!("Imported {} pairs", count),
+            )?;
+            eprintln!("Import finished, {} pairs", count);
+        }
+        Command::Shell { addr } => {
+            let client = connect(addr, &credentials, &tls)?;
+            Shell::new(client, format!("{}> ", addr)).run()?;
+        }
+        Command::Admin { command } => match command {
+            AdminCommand::Backup {
+                dir,
+                incremental_from,
+                addr,
+            } => {
+                let mut client = connect(addr, &credentials, &tls)?;
+                match incremental_from {
+                    Some(parent) => client.backup_incremental(&dir, parent)?,
+                    None => client.backup(&dir)?,
+                }
+                eprintln!("Backup written to {}", dir);
+            }
+            AdminCommand::Info { addr } => {
+                let mut client = connect(addr, &credentials, &tls)?;
+                println!("{}", client.info()?);
+            }
+            AdminCommand::Stats { addr } => {
+                let mut client = connect(addr, &credentials, &tls)?;
+                println!("{}", serde_json::to_string_pretty(&client.stats()?)?);
+            }
+            AdminCommand::Compact { addr } => {
+                let mut client = connect(addr, &credentials, &tls)?;
+                client.compact()?;
+                eprintln!("Compacted");
+            }
+            AdminCommand::Flush { addr } => {
+                let mut client = connect(addr, &credentials, &tls)?;
+                client.flush()?;
+                eprintln!("Flushed");
+            }
+            AdminCommand::Rebalance { node, drain } => {
+                let nodes: Vec<String> = node.iter().map(SocketAddr::to_string).collect();
+                let mut client = match (credentials, tls) {
+                    (Some(credentials), Some(tls)) => {
+                        ShardedKvClient::connect_tls_with_auth(&nodes, tls, credentials)?
+                    }
+                    (None, Some(tls)) => ShardedKvClient::connect_tls(&nodes, tls)?,
+                    (Some(credentials), None) => {
+                        ShardedKvClient::connect_with_auth(&nodes, credentials)?
+                    }
+                    (None, None) => ShardedKvClient::connect(&nodes)?,
+                };
+                for addr in drain {
+                    client.remove_node(&addr.to_string())?;
+                }
+                let moved = client.rebalance()?;
+                eprintln!("Moved {} keys", moved);
+            }
+        },
     }
+    Ok(())
 }
-fn run_with_engine<E: KvEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvServer::new(engine);
-    server.run(addr)
+
+fn credentials(opt: &Opt) -> Result<Option<Credentials>> {
+    if let Some(token) = &opt.token {
+        return Ok(Some(Credentials::Token(token.clone())));
+    }
+    match &opt.user {
+        Some(user) => {
+            let password = env::var("KVS_PASSWORD").map_err(|_| {
+                KvsErr::StringErr("set KVS_PASSWORD to the password of --user".to_owned())
+            })?;
+            Ok(Some(Credentials::Password {
+                user: user.clone(),
+                password,
+            }))
+        }
+        None => Ok(None),
+    }
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
-    if !engine.exists() {
-        return Ok(None);
+fn tls(opt: &Opt) -> Result<Option<ClientTls>> {
+    match (&opt.tls_ca, &opt.tls_cert, &opt.tls_key) {
+        (Some(ca), Some(cert), Some(key)) => Ok(Some(ClientTls::with_identity(ca, cert, key)?)),
+        (Some(ca), _, _) => Ok(Some(ClientTls::new(ca)?)),
+        (None, _, _) => Ok(None),
     }
+}
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+fn connect(
+    addr: SocketAddr,
+    credentials: &Option<Credentials>,
+    tls: &Option<ClientTls>,
+) -> Result<KvClient> {
+    let mut client = match (credentials, tls) {
+        (Some(credentials), Some(tls)) => {
+            KvClient::connect_tls_with_auth(&addr.to_string(), tls.clone(), credentials.clone())?
         }
-    }
+        (None, Some(tls)) => KvClient::connect_tls(&addr.to_string(), tls.clone())?,
+        (Some(credentials), None) => KvClient::connect_with_auth(addr, credentials.clone())?,
+        (None, None) => KvClient::connect(addr)?,
+    };
+    // writes sent to a replica or a Raft follower go on to the leader
+    client.set_follow_redirects(true);
+    Ok(client)
 }
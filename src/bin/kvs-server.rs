@@ -1,13 +1,23 @@
 use clap::arg_enum;
+use kvs::auth::{AclFile, Auth, Credentials, Users};
 use kvs::engines::KvEngine;
-use kvs::engines::SledKvsEngine;
+use kvs::engines::{
+    engine_dir, move_root_sled_data, Keyring, KvStoreOptions, LsmEngine, MemoryEngine,
+    SledKvsEngine, ENGINE_MARKER,
+};
+use kvs::raft::{NodeId, Peer, RaftConfig, RaftEngine};
+use kvs::tls::{ClientTls, ServerTls};
 use kvs::*;
 use log::LevelFilter;
 use log::{error, info, warn};
+use serde::Deserialize;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -18,12 +28,25 @@ const DEFAULT_ENGINE: Engine = Engine::kvs;
 struct Opt {
     #[structopt(
         long,
-        help = "Sets the listening address",
+        help = "Reads settings from a TOML file, flags given here take precedence",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the listening address [default: 127.0.0.1:4000]",
         value_name = "IP:PORT",
-        default_value = DEFAULT_LISTENING_ADDRESS,
         parse(try_from_str)
     )]
-    addr: SocketAddr,
+    addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Sets the directory holding the data and the engine marker [default: .]",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    data_dir: Option<PathBuf>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -31,6 +54,253 @@ struct Opt {
         possible_values = &Engine::variants()
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets the time allowed to read a request in milliseconds, 0 to disable",
+        value_name = "MILLIS"
+    )]
+    read_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets the time allowed to write a response in milliseconds, 0 to disable",
+        value_name = "MILLIS"
+    )]
+    write_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Closes connections idle for this many milliseconds, 0 to disable",
+        value_name = "MILLIS"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets the maximum number of concurrent connections",
+        value_name = "N"
+    )]
+    max_connections: Option<usize>,
+    #[structopt(
+        long,
+        help = "Sets the most key/value pairs returned by a scan [default: 1024]",
+        value_name = "N"
+    )]
+    max_scan_limit: Option<usize>,
+    #[structopt(
+        long,
+        help = "Sets how long in-flight requests may run on shutdown in milliseconds [default: 5000]",
+        value_name = "MILLIS"
+    )]
+    shutdown_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Replicates the server at this address and rejects writes",
+        value_name = "IP:PORT"
+    )]
+    replica_of: Option<String>,
+    #[structopt(
+        long,
+        help = "Runs as this member of the Raft cluster given with --raft-peer",
+        value_name = "ID",
+        requires = "raft-peer",
+        conflicts_with = "replica-of"
+    )]
+    raft_id: Option<NodeId>,
+    #[structopt(
+        long,
+        help = "Adds a member of the Raft cluster, this server included",
+        value_name = "ID,CLIENT_ADDR,RAFT_ADDR",
+        number_of_values = 1
+    )]
+    raft_peer: Vec<Peer>,
+    #[structopt(
+        long,
+        help = "Requires Raft members to present this token to each other",
+        value_name = "TOKEN",
+        env = "KVS_RAFT_TOKEN",
+        hide_env_values = true
+    )]
+    raft_token: Option<String>,
+    #[structopt(
+        long,
+        help = "Serves metrics in the Prometheus text format over HTTP on this address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "Requires clients to authenticate with this token, also presented to --replica-of",
+        value_name = "TOKEN",
+        env = "KVS_AUTH_TOKEN",
+        hide_env_values = true,
+        conflicts_with = "auth-users"
+    )]
+    auth_token: Option<String>,
+    #[structopt(
+        long,
+        help = "Requires clients to log in as a user of this file of salted password hashes",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    auth_users: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Logs in to --replica-of as this user instead of presenting --auth-token",
+        value_name = "USER"
+    )]
+    leader_user: Option<String>,
+    #[structopt(
+        long,
+        help = "Password of --leader-user",
+        value_name = "PASSWORD",
+        env = "KVS_LEADER_PASSWORD",
+        hide_env_values = true
+    )]
+    leader_password: Option<String>,
+    #[structopt(
+        long,
+        help = "Restricts what each user may do with this ACL file, reloaded when it changes",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    acl: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Serves clients over TLS with this PEM certificate",
+        value_name = "FILE",
+        requires = "tls-key",
+        parse(from_os_str)
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Private key of --tls-cert, in PEM",
+        value_name = "FILE",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Only accepts clients with a certificate signed by a CA of this PEM file",
+        value_name = "FILE",
+        requires = "tls-cert",
+        parse(from_os_str)
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Connects to --replica-of over TLS, trusting the CA certificates of this PEM file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    leader_tls_ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Encrypts the records of the kvs engine with the keys of this file",
+        value_name = "FILE",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(skip)]
+    kvs: KvsOptions,
+    #[structopt(skip)]
+    sled: SledOptions,
+    #[structopt(skip)]
+    lsm: LsmOptions,
+    #[structopt(skip)]
+    memory: MemoryOptions,
+}
+
+/// Settings of the `--config` file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<SocketAddr>,
+    engine: Option<String>,
+    data_dir: Option<PathBuf>,
+    metrics_addr: Option<SocketAddr>,
+    replica_of: Option<String>,
+    limits: Limits,
+    auth: AuthOptions,
+    tls: TlsOptions,
+    kvs: KvsOptions,
+    sled: SledOptions,
+    lsm: LsmOptions,
+    memory: MemoryOptions,
+}
+
+/// The `[limits]` table, in milliseconds for the timeouts. Every connection is
+/// served by its own thread, so `max_connections` also caps the server's threads.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    read_timeout: Option<u64>,
+    write_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    max_connections: Option<usize>,
+    max_scan_limit: Option<usize>,
+    shutdown_timeout: Option<u64>,
+}
+
+/// The `[auth]` table, with either a token or a users file, an ACL file, and
+/// the user a replica logs in to its leader as.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AuthOptions {
+    token: Option<String>,
+    users_file: Option<PathBuf>,
+    acl_file: Option<PathBuf>,
+    leader_user: Option<String>,
+    leader_password: Option<String>,
+}
+
+/// The `[tls]` table, with the server's certificate and key and the CA files
+/// checking clients and the leader.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TlsOptions {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    leader_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct KvsOptions {
+    /// Bytes of stale records that trigger a compaction.
+    compaction_threshold: Option<u64>,
+    /// Values of at least this many bytes are compressed.
+    compression_threshold: Option<usize>,
+    /// Values of at least this many bytes go to the value log.
+    value_log_threshold: Option<usize>,
+    /// Bytes of stale values in the value log that trigger its collection.
+    value_log_gc_threshold: Option<u64>,
+    /// Keep the index of closed generations on disk rather than in memory.
+    disk_index: bool,
+    /// Keys encrypting the records, the first one used for new records.
+    key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SledOptions {
+    /// Bytes of sled's page cache.
+    cache_capacity: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LsmOptions {
+    /// Bytes of keys and values in the memtable before it is written to a table.
+    memtable_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MemoryOptions {
+    /// Bytes of keys and values past which the least recently used are evicted.
+    budget: Option<u64>,
 }
 
 arg_enum! {
@@ -38,14 +308,16 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        memory
     }
 }
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
+    let res = options().and_then(|mut opt| {
+        let curr_engine = current_engine(&data_dir(&opt)?)?;
         if opt.engine.is_none() {
             opt.engine = curr_engine;
         }
@@ -61,24 +333,288 @@ fn main() {
     }
 }
 
+// the command line options, completed with the settings of the `--config` file
+fn options() -> Result<Opt> {
+    let mut opt = Opt::from_args();
+    if let Some(path) = &opt.config {
+        let file: FileConfig = toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            KvsErr::StringErr(format!("invalid config file {}: {}", path.display(), e))
+        })?;
+        opt.merge(file)?;
+    }
+    Ok(opt)
+}
+
+impl Opt {
+    // fill in the settings not given on the command line
+    fn merge(&mut self, file: FileConfig) -> Result<()> {
+        if self.engine.is_none() {
+            self.engine = match file.engine {
+                Some(engine) => Some(engine.parse().map_err(KvsErr::StringErr)?),
+                None => None,
+            };
+        }
+        self.addr = self.addr.or(file.addr);
+        self.data_dir = self.data_dir.take().or(file.data_dir);
+        self.metrics_addr = self.metrics_addr.or(file.metrics_addr);
+        self.replica_of = self.replica_of.take().or(file.replica_of);
+        self.read_timeout = self.read_timeout.or(file.limits.read_timeout);
+        self.write_timeout = self.write_timeout.or(file.limits.write_timeout);
+        self.idle_timeout = self.idle_timeout.or(file.limits.idle_timeout);
+        self.max_connections = self.max_connections.or(file.limits.max_connections);
+        self.max_scan_limit = self.max_scan_limit.or(file.limits.max_scan_limit);
+        self.shutdown_timeout = self.shutdown_timeout.or(file.limits.shutdown_timeout);
+        if self.auth_token.is_none() && self.auth_users.is_none() {
+            self.auth_token = file.auth.token;
+            self.auth_users = file.auth.users_file;
+        }
+        self.acl = self.acl.take().or(file.auth.acl_file);
+        if self.leader_user.is_none() {
+            self.leader_user = file.auth.leader_user;
+            self.leader_password = self.leader_password.take().or(file.auth.leader_password);
+        }
+        if self.tls_cert.is_none() {
+            self.tls_cert = file.tls.cert;
+            self.tls_key = file.tls.key;
+        }
+        self.tls_client_ca = self.tls_client_ca.take().or(file.tls.client_ca);
+        self.leader_tls_ca = self.leader_tls_ca.take().or(file.tls.leader_ca);
+        self.key_file = self.key_file.take().or(file.kvs.key_file.clone());
+        self.kvs = file.kvs;
+        self.sled = file.sled;
+        self.lsm = file.lsm;
+        self.memory = file.memory;
+        Ok(())
+    }
+}
+
+// where kvs-server kept the data of each engine before the data directory could be set
+const OLD_DATA_DIRS: [(&str, &str); 2] = [("kvs", "/tmp/kvs/kvs"), ("sled", "/tmp/sled/seld")];
+
+fn data_dir(opt: &Opt) -> Result<PathBuf> {
+    match &opt.data_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(current_dir()?),
+    }
+}
+
 fn run(opt: Opt) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let addr = match opt.addr {
+        Some(addr) => addr,
+        None => DEFAULT_LISTENING_ADDRESS.parse().unwrap(),
+    };
+    let dir = data_dir(&opt)?;
     info!("kv-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    info!("Data directory: {}", dir.display());
+    info!("Listening on {}", addr);
+    if let Some(metrics_addr) = opt.metrics_addr {
+        info!("Serving metrics on http://{}/metrics", metrics_addr);
+    }
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(ENGINE_MARKER), format!("{}", engine))?;
+    for (name, old) in OLD_DATA_DIRS {
+        if Path::new(old).exists() {
+            warn!(
+                "{} holds {} data of an older kvs-server, which is not served; move it to {}",
+                old,
+                name,
+                engine_dir(name, &dir).display()
+            );
+        }
+    }
+    let mut config = server_config(&opt);
+    config.auth = match (&opt.auth_token, &opt.auth_users) {
+        (Some(_), Some(_)) => {
+            return Err(KvsErr::StringErr(
+                "set either an auth token or a users file, not both".to_owned(),
+            ))
+        }
+        (Some(token), None) => Some(Auth::Token(token.clone())),
+        (None, Some(path)) => Some(Auth::Users(Users::load(path)?)),
+        (None, None) => None,
+    };
+    if config.auth.is_some() {
+        info!("Clients must authenticate");
+    }
+    if let Some(path) = &opt.acl {
+        info!("Access control list: {}", path.display());
+        config.acl = Some(Arc::new(AclFile::open(path)?));
+    }
+    config.tls = match (&opt.tls_cert, &opt.tls_key, &opt.tls_client_ca) {
+        (Some(cert), Some(key), Some(client_ca)) => {
+            info!("Serving TLS, clients need a certificate");
+            Some(ServerTls::with_client_auth(cert, key, client_ca)?)
+        }
+        (Some(cert), Some(key), None) => {
+            info!("Serving TLS");
+            Some(ServerTls::new(cert, key)?)
+        }
+        (None, None, None) => None,
+        _ => {
+            return Err(KvsErr::StringErr(
+                "TLS needs both a certificate and a key".to_owned(),
+            ))
+        }
+    };
+    config.leader_tls = match &opt.leader_tls_ca {
+        Some(ca) => Some(ClientTls::new(ca)?),
+        None => None,
+    };
+    config.leader_credentials = match (&opt.leader_user, &opt.leader_password) {
+        (Some(user), Some(password)) => Some(Credentials::Password {
+            user: user.clone(),
+            password: password.clone(),
+        }),
+        (None, _) => opt.auth_token.clone().map(Credentials::Token),
+        (Some(_), None) => {
+            return Err(KvsErr::StringErr(
+                "a leader user needs a leader password".to_owned(),
+            ))
+        }
+    };
+    if let Some(leader) = &opt.replica_of {
+        info!("Replica of {}", leader);
+        config.replica_of = Some(leader.clone());
+        config.replica_state = Some(dir.join("replication.json"));
+    }
+    // the memory engine starts empty, so the writes before a restart do not apply
+    if engine != Engine::memory {
+        config.backlog_file = Some(dir.join("backlog.json"));
+    }
+    let raft = match opt.raft_id {
+        Some(id) => {
+            info!("Raft node {} of {} members", id, opt.raft_peer.len());
+            if opt.raft_token.is_none() {
+                warn!("The Raft port is not authenticated, firewall it or set --raft-token");
+            }
+            Some(RaftConfig {
+                id,
+                peers: opt.raft_peer.clone(),
+                state_dir: dir.join("raft"),
+                token: opt.raft_token.clone(),
+            })
+        }
+        None => None,
+    };
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?.join("/kvs"))?, opt.addr),
-        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt.addr),
+        Engine::kvs => {
+            let keyring = match &opt.key_file {
+                Some(path) => {
+                    info!("Encrypting records with the keys of {}", path.display());
+                    Some(Keyring::load(path)?)
+                }
+                None => None,
+            };
+            let options = KvStoreOptions {
+                keyring,
+                disk_index: opt.kvs.disk_index,
+            };
+            let kvs_dir = engine_dir("kvs", &dir);
+            let mut store = KvStore::open_with(&kvs_dir, options)?;
+            if let Some(threshold) = opt.kvs.compaction_threshold {
+                store.set_compaction_threshold(threshold);
+            }
+            store.set_compression_threshold(opt.kvs.compression_threshold);
+            store.set_value_log_threshold(opt.kvs.value_log_threshold);
+            if let Some(threshold) = opt.kvs.value_log_gc_threshold {
+                store.set_value_log_gc_threshold(threshold);
+            }
+            config.data_dir = Some(kvs_dir);
+            run_with_engine(store, addr, config, raft)
+        }
+        Engine::sled => {
+            let sled_dir = engine_dir("sled", &dir);
+            if move_root_sled_data(&dir)? {
+                info!(
+                    "Moved the sled data of {} to {}",
+                    dir.display(),
+                    sled_dir.display()
+                );
+            }
+            let mut sled_config = sled::Config::new().path(&sled_dir);
+            if let Some(capacity) = opt.sled.cache_capacity {
+                sled_config = sled_config.cache_capacity(capacity);
+            }
+            config.data_dir = Some(sled_dir);
+            run_with_engine(SledKvsEngine::new(sled_config.open()?), addr, config, raft)
+        }
+        Engine::lsm => {
+            let lsm_dir = engine_dir("lsm", &dir);
+            let mut engine = LsmEngine::open(&lsm_dir)?;
+            if let Some(bytes) = opt.lsm.memtable_size {
+                engine.set_memtable_size(bytes);
+            }
+            config.data_dir = Some(lsm_dir);
+            run_with_engine(engine, addr, config, raft)
+        }
+        Engine::memory => {
+            let engine = match opt.memory.budget {
+                Some(bytes) => MemoryEngine::with_budget(bytes),
+                None => MemoryEngine::new(),
+            };
+            run_with_engine(engine, addr, config, raft)
+        }
+    }
+}
+
+fn server_config(opt: &Opt) -> ServerConfig {
+    // a timeout of 0 turns it off
+    let timeout = |millis: u64| Some(Duration::from_millis(millis)).filter(|t| !t.is_zero());
+    let mut config = ServerConfig::default();
+    if let Some(millis) = opt.read_timeout {
+        config.read_timeout = timeout(millis);
+    }
+    if let Some(millis) = opt.write_timeout {
+        config.write_timeout = timeout(millis);
+    }
+    if let Some(millis) = opt.idle_timeout {
+        config.idle_timeout = timeout(millis);
+    }
+    if let Some(max_connections) = opt.max_connections {
+        config.max_connections = max_connections;
+    }
+    if let Some(max_scan_limit) = opt.max_scan_limit {
+        config.max_scan_limit = max_scan_limit.max(1);
+    }
+    if let Some(millis) = opt.shutdown_timeout {
+        config.shutdown_timeout = Duration::from_millis(millis);
+    }
+    config.metrics_addr = opt.metrics_addr;
+    config
+}
+
+fn run_with_engine<E: KvEngine + Send + 'static>(
+    engine: E,
+    addr: SocketAddr,
+    config: ServerConfig,
+    raft: Option<RaftConfig>,
+) -> Result<()> {
+    match raft {
+        Some(raft) => serve(RaftEngine::start(engine, raft)?, addr, config),
+        None => serve(engine, addr, config),
     }
 }
-fn run_with_engine<E: KvEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvServer::new(engine);
+
+fn serve<E: KvEngine + Send + 'static>(
+    engine: E,
+    addr: SocketAddr,
+    config: ServerConfig,
+) -> Result<()> {
+    let server = KvServer::with_config(engine, config);
+    // stop accepting connections on SIGINT / SIGTERM and flush before exiting
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        info!("Received termination signal, shutting down");
+        handle.shutdown();
+    })
+    .map_err(|e| KvsErr::StringErr(format!("failed to set signal handler: {}", e)))?;
     server.run(addr)
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("engine");
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine = dir.join(ENGINE_MARKER);
     if !engine.exists() {
         return Ok(None);
     }
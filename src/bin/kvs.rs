@@ -1,7 +1,15 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{KvStore, KvsErr, Result, engines::KvEngine};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::auth;
+use kvs::backup;
+use kvs::bulk::{self, Format};
+use kvs::engines::{self, open_engine_with_keyring, Keyring, ENGINE_MARKER};
+use kvs::{engines::KvEngine, KvStore, KvsErr, Result, Shell};
 
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
 use std::{env::current_dir, process::exit};
+
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -30,6 +38,143 @@ fn main() -> Result<()> {
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(SubCommand::with_name("shell").about("Start an interactive shell"))
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every key/value pair to stdout in key order")
+                .arg(engine_arg())
+                .arg(format_arg())
+                .arg(key_file_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Load key/value pairs from stdin")
+                .arg(engine_arg())
+                .arg(format_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .value_name("N")
+                        .help("Number of pairs written at once")
+                        .default_value("1000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copy all data from one kvs-server data directory to another")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ENGINE-NAME")
+                        .help("Engine of the source directory")
+                        .possible_values(&["kvs", "sled", "lsm"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ENGINE-NAME")
+                        .help("Engine of the destination directory")
+                        .possible_values(&["kvs", "sled", "lsm"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("SRC")
+                        .help("Source directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("DST")
+                        .help("Destination directory")
+                        .required(true),
+                )
+                .arg(
+                    key_file_arg()
+                        .help("Reads and writes the kvs directories with the keys of FILE"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .value_name("N")
+                        .help("Number of pairs written at once")
+                        .default_value("1000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check the log files of a kvs directory")
+                .arg(Arg::with_name("DIR").help("Data directory").required(true))
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Salvage readable records into a fresh log file"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Print the records of a kvs directory")
+                .arg(Arg::with_name("DIR").help("Data directory").required(true))
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .value_name("N")
+                        .help("Only print records of the log generation N"),
+                )
+                .arg(
+                    Arg::with_name("key")
+                        .long("key")
+                        .value_name("KEY")
+                        .help("Only print the write history of KEY"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the data of the current directory into an empty directory")
+                .arg(engine_arg())
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("DEST")
+                        .help("Backup directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("incremental-from")
+                        .long("incremental-from")
+                        .value_name("PARENT")
+                        .help("Only copy what changed since the backup in PARENT"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Check a backup and swap it in place of a data directory")
+                .arg(
+                    Arg::with_name("BACKUP")
+                        .help("Backup directory")
+                        .required(true),
+                )
+                .arg(Arg::with_name("DIR").help("Data directory").required(true))
+                .arg(key_file_arg())
+                .arg(
+                    Arg::with_name("increment")
+                        .long("increment")
+                        .value_name("BACKUP")
+                        .help("Incremental backup to apply on top, in the order they were taken")
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("passwd")
+                .about("Print a users file line for USER, with the password read from stdin")
+                .arg(Arg::with_name("USER").help("User name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("keygen")
+                .about("Print a random encryption key, to put in a key file"),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -50,21 +195,207 @@ fn main() -> Result<()> {
                 println!("Key not found");
             }
             Ok(())
-
         }
         ("rm", Some(_matches)) => {
             let key = _matches.value_of("KEY").unwrap();
 
             let mut store = KvStore::open(current_dir()?)?;
             match store.remove(key.to_string()) {
-                Ok(()) => Ok({}),
+                Ok(()) => Ok(()),
                 Err(KvsErr::KeyNotFound) => {
                     println!("Key not found");
                     exit(1);
                 }
-                Err(e) => return Err(e),
+                Err(e) => Err(e),
+            }
+        }
+        ("shell", Some(_)) => {
+            let store = KvStore::open(current_dir()?)?;
+            Shell::new(store, "kvs> ").run()
+        }
+        ("export", Some(_matches)) => {
+            let mut engine = open_engine_with_keyring(
+                _matches.value_of("engine").unwrap(),
+                &current_dir()?,
+                keyring(_matches)?.as_ref(),
+            )?;
+            let format = _matches.value_of("format").unwrap().parse::<Format>()?;
+            let stdout = io::stdout();
+            let count = bulk::export(&mut engine, BufWriter::new(stdout.lock()), format)?;
+            eprintln!("Exported {} pairs", count);
+            Ok(())
+        }
+        ("import", Some(_matches)) => {
+            let mut engine = open_engine_with_keyring(
+                _matches.value_of("engine").unwrap(),
+                &current_dir()?,
+                keyring(_matches)?.as_ref(),
+            )?;
+            let format = _matches.value_of("format").unwrap().parse::<Format>()?;
+            let batch_size = parse_batch_size(_matches)?;
+            let stdin = io::stdin();
+            let count = bulk::import(
+                &mut engine,
+                BufReader::new(stdin.lock()),
+                format,
+                batch_size,
+                |count| eprintln!("Imported {} pairs", count),
+            )?;
+            engine.flush()?;
+            eprintln!("Import finished, {} pairs", count);
+            Ok(())
+        }
+        ("migrate", Some(_matches)) => {
+            let from = _matches.value_of("from").unwrap();
+            let to = _matches.value_of("to").unwrap();
+            let src = Path::new(_matches.value_of("SRC").unwrap());
+            let dst = Path::new(_matches.value_of("DST").unwrap());
+            if from == to {
+                eprintln!("Source and destination engines are the same");
+                exit(1);
+            }
+            if let Ok(marker) = fs::read_to_string(src.join(ENGINE_MARKER)) {
+                if marker != from {
+                    eprintln!("Source directory holds a {} engine, not {}", marker, from);
+                    exit(1);
+                }
+            }
+
+            // the engines keep their data where kvs-server looks for it
+            if from == "sled" && engines::move_root_sled_data(src)? {
+                eprintln!(
+                    "Moved the sled data of {} to its sled directory",
+                    src.display()
+                );
+            }
+            // only kvs directories are encrypted
+            let keyring = keyring(_matches)?;
+            let keyring_of = |name| keyring.as_ref().filter(|_| name == "kvs");
+            let mut src_engine =
+                open_engine_with_keyring(from, &engines::engine_dir(from, src), keyring_of(from))?;
+            let mut dst_engine =
+                open_engine_with_keyring(to, &engines::engine_dir(to, dst), keyring_of(to))?;
+            let digest = bulk::migrate(
+                &mut src_engine,
+                &mut dst_engine,
+                parse_batch_size(_matches)?,
+            )?;
+            fs::write(dst.join(ENGINE_MARKER), to)?;
+            eprintln!(
+                "Migrated {} pairs from {} to {}, checksum {:016x}",
+                digest.count, from, to, digest.checksum
+            );
+            Ok(())
+        }
+        ("fsck", Some(_matches)) => {
+            let dir = _matches.value_of("DIR").unwrap();
+            let keyring = keyring(_matches)?;
+            let report =
+                engines::fsck_with_keyring(dir, keyring.as_ref(), _matches.is_present("repair"))?;
+            println!("{}", report);
+            if !report.is_clean() && report.repaired_into.is_none() {
+                exit(1);
+            }
+            Ok(())
+        }
+        ("dump", Some(_matches)) => {
+            let dir = _matches.value_of("DIR").unwrap();
+            let version = match _matches.value_of("version") {
+                Some(version) => Some(
+                    version
+                        .parse::<u64>()
+                        .map_err(|_| KvsErr::StringErr(format!("invalid version: {}", version)))?,
+                ),
+                None => None,
+            };
+            let key = _matches.value_of("key");
+            let keyring = keyring(_matches)?;
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            engines::dump_with_keyring(dir, keyring.as_ref(), |record| {
+                if version.is_none_or(|version| version == record.version)
+                    && key.is_none_or(|key| key == record.op.key())
+                {
+                    writeln!(out, "{}", record)?;
+                }
+                Ok(())
+            })?;
+            out.flush()?;
+            Ok(())
+        }
+        ("backup", Some(_matches)) => {
+            let mut engine = open_engine_with_keyring(
+                _matches.value_of("engine").unwrap(),
+                &current_dir()?,
+                keyring(_matches)?.as_ref(),
+            )?;
+            let dest = _matches.value_of("DEST").unwrap();
+            match _matches.value_of("incremental-from") {
+                Some(parent) => engine.backup_incremental(Path::new(dest), Path::new(parent))?,
+                None => engine.backup_to(Path::new(dest))?,
             }
+            eprintln!("Backup written to {}", dest);
+            Ok(())
+        }
+        ("restore", Some(_matches)) => {
+            let dir = _matches.value_of("DIR").unwrap();
+            let mut chain = vec![_matches.value_of("BACKUP").unwrap()];
+            chain.extend(_matches.values_of("increment").into_iter().flatten());
+            let digest = backup::restore_chain(&chain, dir, keyring(_matches)?.as_ref())?;
+            eprintln!(
+                "Restored {} pairs into {}, checksum {:016x}",
+                digest.count, dir, digest.checksum
+            );
+            Ok(())
+        }
+        ("passwd", Some(_matches)) => {
+            let user = _matches.value_of("USER").unwrap();
+            let mut password = String::new();
+            io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(&['\r', '\n'][..]);
+            println!("{}", auth::hash_password(user, password)?);
+            Ok(())
+        }
+        ("keygen", Some(_)) => {
+            println!("{}", Keyring::generate_key()?);
+            Ok(())
         }
         _ => unreachable!(),
     }
 }
+
+fn engine_arg() -> Arg<'static, 'static> {
+    Arg::with_name("engine")
+        .long("engine")
+        .value_name("ENGINE-NAME")
+        .help("Sets the storage engine")
+        .possible_values(&["kvs", "sled", "lsm"])
+        .default_value("kvs")
+}
+
+fn format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("FORMAT")
+        .help("Sets the dump format")
+        .possible_values(&["jsonl", "csv"])
+        .default_value("jsonl")
+}
+
+fn key_file_arg() -> Arg<'static, 'static> {
+    Arg::with_name("key-file")
+        .long("key-file")
+        .value_name("FILE")
+        .help("Reads and writes encrypted records with the keys of FILE")
+}
+
+fn keyring(matches: &ArgMatches) -> Result<Option<Keyring>> {
+    matches.value_of("key-file").map(Keyring::load).transpose()
+}
+
+fn parse_batch_size(matches: &ArgMatches) -> Result<usize> {
+    let batch_size = matches.value_of("batch-size").unwrap();
+    batch_size
+        .parse()
+        .map_err(|_| KvsErr::StringErr(format!("invalid batch size: {}", batch_size)))
+}
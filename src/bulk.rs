@@ -0,0 +1,186 @@
+use crate::engines::{KvEngine, Pairs};
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use sha2::{Digest as _, Sha256};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+// number of pairs fetched per scan while exporting
+const SCAN_BATCH: usize = 1024;
+
+/// Format of a bulk export or import stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `{"key":..,"value":..}` object per line.
+    Jsonl,
+    /// A `key,value` header followed by one row per pair.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = KvsErr;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            other => Err(KvsErr::StringErr(format!("unknown format: {}", other))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+/// Write every key/value pair of `engine` to `writer` in key order.
+/// Return the number of pairs written.
+pub fn export<E, W>(engine: &mut E, writer: W, format: Format) -> Result<u64>
+where
+    E: KvEngine + ?Sized,
+    W: Write,
+{
+    let pairs = Pairs::new(engine, SCAN_BATCH);
+    match format {
+        Format::Jsonl => {
+            let mut writer = writer;
+            let mut count = 0;
+            for pair in pairs {
+                let (key, value) = pair?;
+                serde_json::to_writer(&mut writer, &Record { key, value })?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+            Ok(count)
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            let mut count = 0;
+            for pair in pairs {
+                let (key, value) = pair?;
+                writer.serialize(Record { key, value })?;
+                count += 1;
+            }
+            writer.flush()?;
+            Ok(count)
+        }
+    }
+}
+
+/// Load key/value pairs from `reader` into `engine`, `batch_size` pairs per write.
+/// `progress` is called with the running total after each batch.
+/// Return the number of pairs imported.
+pub fn import<E, R, F>(
+    engine: &mut E,
+    reader: R,
+    format: Format,
+    batch_size: usize,
+    mut progress: F,
+) -> Result<u64>
+where
+    E: KvEngine + ?Sized,
+    R: Read,
+    F: FnMut(u64),
+{
+    let records: Box<dyn Iterator<Item = Result<Record>>> = match format {
+        Format::Jsonl => Box::new(
+            Deserializer::from_reader(reader)
+                .into_iter::<Record>()
+                .map(|record| Ok(record?)),
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize::<Record>()
+                .map(|record| Ok(record?)),
+        ),
+    };
+
+    let batch_size = batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut count = 0;
+    for record in records {
+        let record = record?;
+        batch.push((record.key, record.value));
+        if batch.len() == batch_size {
+            count += batch.len() as u64;
+            engine.set_batch(std::mem::take(&mut batch))?;
+            progress(count);
+        }
+    }
+    if !batch.is_empty() {
+        count += batch.len() as u64;
+        engine.set_batch(batch)?;
+        progress(count);
+    }
+    Ok(count)
+}
+
+/// Number of pairs and a checksum over their contents, used to compare two engines.
+/// The checksum is the start of a SHA-256 of the pairs, the same across builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    pub count: u64,
+    pub checksum: u64,
+}
+
+/// Compute the `Digest` of every pair in `engine`.
+pub fn digest<E: KvEngine + ?Sized>(engine: &mut E) -> Result<Digest> {
+    let mut hasher = Sha256::new();
+    let mut count = 0;
+    for pair in Pairs::new(engine, SCAN_BATCH) {
+        let (key, value) = pair?;
+        // lengths first, so the boundary between key and value counts too
+        for field in &[key, value] {
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field);
+        }
+        count += 1;
+    }
+    let mut checksum = [0; 8];
+    checksum.copy_from_slice(&hasher.finalize()[..8]);
+    Ok(Digest {
+        count,
+        checksum: u64::from_be_bytes(checksum),
+    })
+}
+
+/// Copy every pair of `src` into the empty engine `dst`, then check both hold the same data.
+/// Return the digest shared by both engines.
+pub fn migrate<S, D>(src: &mut S, dst: &mut D, batch_size: usize) -> Result<Digest>
+where
+    S: KvEngine + ?Sized,
+    D: KvEngine + ?Sized,
+{
+    if !dst.scan(None, 1)?.is_empty() {
+        return Err(KvsErr::StringErr(
+            "destination engine is not empty".to_owned(),
+        ));
+    }
+
+    let batch_size = batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    for pair in Pairs::new(src, SCAN_BATCH) {
+        batch.push(pair?);
+        if batch.len() == batch_size {
+            dst.set_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        dst.set_batch(batch)?;
+    }
+    dst.flush()?;
+
+    let expected = digest(src)?;
+    let actual = digest(dst)?;
+    if expected != actual {
+        return Err(KvsErr::StringErr(format!(
+            "migration check failed: source has {} pairs (checksum {:016x}), destination has {} pairs (checksum {:016x})",
+            expected.count, expected.checksum, actual.count, actual.checksum
+        )));
+    }
+    Ok(actual)
+}
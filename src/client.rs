@@ -1,70 +1,289 @@
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::de::IoRead;
 use serde_json::Deserializer;
 
+use crate::auth::Credentials;
 use crate::common::GetResponse;
+use crate::common::InfoResponse;
 use crate::common::Request;
+use crate::common::ScanResponse;
 use crate::common::SetResponse;
+use crate::common::StatsResponse;
+use crate::common::{unix_millis, Envelope};
+use crate::engines::{EngineStats, KvEngine};
+use crate::tls::{ClientTls, Stream};
+use crate::KvsErr;
 use crate::Result;
+use crate::ServerInfo;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Write;
-use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 pub struct KvClient {
-    // reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    // writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    writer: BufWriter<Stream>,
+    deadline: Option<Duration>,
+    follow_redirects: bool,
+    credentials: Option<Credentials>,
+    tls: Option<ClientTls>,
 }
 
+// how many times a request is sent on to another server before giving up
+const MAX_REDIRECTS: usize = 3;
+
 impl KvClient {
     pub fn connect<A: ToSocketAddrs>(_addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(_addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
+        Ok(KvClient::with_stream(Stream::connect(_addr)?, None))
+    }
+
+    /// Connect over TLS to `addr`, given as `host:port`. The server's certificate
+    /// must be valid for `host`.
+    pub fn connect_tls(addr: &str, tls: ClientTls) -> Result<Self> {
+        let stream = Stream::connect_tls(addr, &tls)?;
+        Ok(KvClient::with_stream(stream, Some(tls)))
+    }
+
+    /// Connect over TLS and authenticate with `credentials`.
+    pub fn connect_tls_with_auth(
+        addr: &str,
+        tls: ClientTls,
+        credentials: Credentials,
+    ) -> Result<Self> {
+        let mut client = KvClient::connect_tls(addr, tls)?;
+        client.authenticate(credentials)?;
+        Ok(client)
+    }
+
+    fn with_stream(stream: Stream, tls: Option<ClientTls>) -> Self {
+        KvClient {
+            reader: Deserializer::from_reader(BufReader::new(stream.clone())),
+            writer: BufWriter::new(stream),
+            deadline: None,
+            follow_redirects: false,
+            credentials: None,
+            tls,
+        }
+    }
+
+    /// Connect and authenticate with `credentials`, as required by servers started with auth.
+    /// The credentials are presented again when following a redirect.
+    pub fn connect_with_auth<A: ToSocketAddrs>(addr: A, credentials: Credentials) -> Result<Self> {
+        let mut client = KvClient::connect(addr)?;
+        client.authenticate(credentials)?;
+        Ok(client)
+    }
 
-        Ok(KvClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: (BufWriter::new(tcp_writer)),
+    fn authenticate(&mut self, credentials: Credentials) -> Result<()> {
+        self.credentials = Some(credentials.clone());
+        self.call(Request::Auth { credentials }, |resp| match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
         })
     }
 
+    /// Sets how long the server may take to start each following request, counted
+    /// from when it is sent. The server compares the deadline with its own clock, so
+    /// both clocks should agree. Requests still waiting when it expires fail with
+    /// `KvsErr::DeadlineExceeded`.
+    pub fn set_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline = deadline;
+    }
+
+    /// When set, requests refused by a replica or a Raft follower are sent again
+    /// to the leader it names.
+    pub fn set_follow_redirects(&mut self, follow: bool) {
+        self.follow_redirects = follow;
+    }
+
     pub fn get(&mut self, _key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key: _key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        self.call(Request::Get { key: _key }, |resp| match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(crate::KvsErr::StringErr(msg)),
-        }
+            GetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
     }
 
     pub fn set(&mut self, _key: String, _value: String) -> Result<()> {
-        serde_json::to_writer(
-            &mut self.writer,
-            &Request::Set {
-                key: _key,
-                value: _value,
-            },
-        )?;
-        self.writer.flush()?;
-        match SetResponse::deserialize(&mut self.reader)? {
+        let request = Request::Set {
+            key: _key,
+            value: _value,
+        };
+        self.call(request, |resp| match resp {
             SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(msg) => Err(crate::KvsErr::StringErr(msg)),
-        }
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
     }
 
     pub fn remove(&mut self, _key: String) -> Result<()> {
-        serde_json::to_writer(
-            &mut self.writer,
-            &Request::Remove {
-                key: _key
-            },
-        )?;
-        self.writer.flush()?;
+        self.call(Request::Remove { key: _key }, |resp| match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Fetch up to `limit` key/value pairs in key order, starting after the key `after`.
+    pub fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.call(Request::Scan { after, limit }, |resp| match resp {
+            ScanResponse::Ok(pairs) => Ok(pairs),
+            ScanResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Set several keys with a single request.
+    pub fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.call(Request::SetBatch { pairs }, |resp| match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Ask the server to back its engine up into `dir`, a path on the server.
+    pub fn backup(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        self.request_backup(dir.as_ref(), None)
+    }
+
+    /// Ask the server for a backup of what changed since the backup in `parent`,
+    /// both paths on the server.
+    pub fn backup_incremental(
+        &mut self,
+        dir: impl AsRef<Path>,
+        parent: impl AsRef<Path>,
+    ) -> Result<()> {
+        self.request_backup(dir.as_ref(), Some(parent.as_ref()))
+    }
+
+    /// Ask the server to describe itself and its engine.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        self.call(Request::Info, |resp| match resp {
+            InfoResponse::Ok(info) => Ok(info),
+            InfoResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Fetch the statistics of the server's engine.
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        self.call(Request::Stats, |resp| match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Ask the server to compact its engine now.
+    pub fn compact(&mut self) -> Result<()> {
+        self.call(Request::Compact, |resp| match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    /// Ask the server to sync its engine to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.call(Request::Flush, |resp| match resp {
+            SetResponse::Ok(()) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
+        })
+    }
+
+    fn request_backup(&mut self, dir: &Path, parent: Option<&Path>) -> Result<()> {
+        self.send(Request::Backup {
+            dir: dir.to_string_lossy().into_owned(),
+            parent: parent.map(|parent| parent.to_string_lossy().into_owned()),
+        })?;
         match SetResponse::deserialize(&mut self.reader)? {
             SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(msg) => Err(crate::KvsErr::StringErr(msg)),
+            SetResponse::Err(msg) => Err(KvsErr::from_remote(msg)),
         }
     }
+
+    // send `request` and parse the response, following redirects if enabled
+    fn call<T, R>(&mut self, request: Request, parse: impl Fn(T) -> Result<R>) -> Result<R>
+    where
+        T: DeserializeOwned,
+    {
+        let mut request = request;
+        let mut redirects = 0;
+        loop {
+            let retry = Some(request.clone())
+                .filter(|_| self.follow_redirects && redirects < MAX_REDIRECTS);
+            self.send(request)?;
+            match (parse(T::deserialize(&mut self.reader)?), retry) {
+                (Err(KvsErr::NotLeader(addr)), Some(retry))
+                | (Err(KvsErr::ReadOnly(addr)), Some(retry)) => {
+                    // `self` is only replaced once the new client is set up
+                    let mut client = match &self.tls {
+                        Some(tls) => KvClient::connect_tls(&addr, tls.clone())?,
+                        None => KvClient::connect(addr.as_str())?,
+                    };
+                    if let Some(credentials) = &self.credentials {
+                        client.authenticate(credentials.clone())?;
+                    }
+                    client.deadline = self.deadline;
+                    client.follow_redirects = true;
+                    *self = client;
+                    request = retry;
+                    redirects += 1;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    fn send(&mut self, request: Request) -> Result<()> {
+        let envelope = Envelope {
+            request,
+            deadline_unix_ms: self.deadline.map(|d| unix_millis(SystemTime::now() + d)),
+        };
+        serde_json::to_writer(&mut self.writer, &envelope)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Lets code written against `KvEngine` run on a remote server.
+impl KvEngine for KvClient {
+    fn name(&self) -> &'static str {
+        "remote"
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvClient::remove(self, key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        KvClient::flush(self)
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        KvClient::scan(self, after, limit)
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        KvClient::set_batch(self, pairs)
+    }
+
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        KvClient::backup(self, dir)
+    }
+
+    fn backup_incremental(&mut self, dir: &Path, parent: &Path) -> Result<()> {
+        KvClient::backup_incremental(self, dir, parent)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvClient::compact(self)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        KvClient::stats(self)
+    }
 }
@@ -1,10 +1,127 @@
+use crate::auth::Credentials;
+use crate::engines::{EngineStats, LogOp};
+use crate::ServerInfo;
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize)]
+use std::time::{SystemTime, UNIX_EPOCH};
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Scan {
+        after: Option<String>,
+        limit: usize,
+    },
+    SetBatch {
+        pairs: Vec<(String, String)>,
+    },
+    /// Admin request: back the engine up into `dir` on the server's file system,
+    /// incrementally on top of the backup in `parent` when given.
+    Backup {
+        dir: String,
+        #[serde(default)]
+        parent: Option<String>,
+    },
+    /// Authenticate the connection. Servers requiring it refuse any other
+    /// request until this one succeeds.
+    Auth {
+        credentials: Credentials,
+    },
+    /// Admin request: describe the server and its engine.
+    Info,
+    /// Admin request: return the statistics of the engine.
+    Stats,
+    /// Admin request: reclaim the space taken by stale records now.
+    Compact,
+    /// Admin request: sync the engine to disk.
+    Flush,
+    /// Sent by a follower to stream the writes after `position`, or everything
+    /// when the leader can not resume from there.
+    Replicate {
+        position: Option<Position>,
+    },
+}
+
+/// Where a follower stands in the write stream of a leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    /// Changes when the leader starts without the backlog saved by a clean shutdown.
+    pub epoch: u64,
+    /// Number of the last write applied.
+    pub seq: u64,
 }
+
+/// Frames a leader sends on a replication stream.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationFrame {
+    /// Start of a full copy of the leader's data, taken at `position`.
+    Snapshot {
+        position: Position,
+    },
+    Pairs(Vec<(String, String)>),
+    SnapshotEnd,
+    Op {
+        seq: u64,
+        op: LogOp,
+    },
+    /// Sent when there was nothing to replicate for a while.
+    Heartbeat,
+    Err(String),
+}
+
+/// A request as sent over the wire, with the time the client is willing to wait for it.
+/// A bare `Request`, as sent by clients predating envelopes, is read as one without deadline.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "Incoming")]
+pub struct Envelope {
+    pub request: Request,
+    /// Milliseconds since the Unix epoch on the client's clock, after which the
+    /// server must not start the request.
+    pub deadline_unix_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Incoming {
+    Envelope {
+        request: Request,
+        #[serde(default)]
+        deadline_unix_ms: Option<u64>,
+    },
+    Bare(Request),
+}
+
+// milliseconds since the Unix epoch at `time`, 0 before it
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+impl From<Incoming> for Envelope {
+    fn from(incoming: Incoming) -> Envelope {
+        match incoming {
+            Incoming::Envelope {
+                request,
+                deadline_unix_ms,
+            } => Envelope {
+                request,
+                deadline_unix_ms,
+            },
+            Incoming::Bare(request) => Envelope {
+                request,
+                deadline_unix_ms: None,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
@@ -17,8 +134,33 @@ pub enum SetResponse {
     Err(String),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    Ok(Vec<(String, String)>),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum InfoResponse {
+    Ok(ServerInfo),
+    Err(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
     Err(String),
 }
+
+/// Sent instead of a regular response when the server refuses to serve a request.
+/// It has the same shape as the `Err` variant of every response.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse {
+    Err(String),
+}
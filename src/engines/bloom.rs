@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+/// Bloom filter over string keys, saved along with the sorted files it describes.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Bloom {
+    bits: Vec<u64>,
+    hashes: u64,
+}
+
+impl Bloom {
+    // about 1% false positives for `keys` keys
+    pub(crate) fn new(keys: u64) -> Bloom {
+        let words = (keys.max(1) * 10).div_ceil(64);
+        Bloom {
+            bits: vec![0; words as usize],
+            hashes: 7,
+        }
+    }
+
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 64;
+        let h1 = fnv1a(key.as_bytes(), 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(key.as_bytes(), 0x6c62_272e_07bb_0142) | 1;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        for bit in self.positions(key).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+// stable across builds, unlike the std hashers, as blooms are saved to disk
+fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
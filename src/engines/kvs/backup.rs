@@ -0,0 +1,232 @@
+use super::KvStore;
+use crate::engines::{prepare_backup_dir, ENGINE_MARKER};
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the manifest file written into every `KvStore` backup.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Describes what a `KvStore` backup holds and which backup it builds on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub id: String,
+    /// Id of the previous backup, `None` for a full backup.
+    pub parent: Option<String>,
+    /// Length of every log generation of the store at the time of the backup.
+    pub generations: BTreeMap<u64, u64>,
+    /// Length of every value log file, likewise.
+    #[serde(default)]
+    pub value_logs: BTreeMap<u64, u64>,
+    /// Byte ranges shipped in this backup, each stored as `<version>.log`
+    /// or `<version>.vlog`.
+    pub segments: Vec<Segment>,
+}
+
+/// The bytes `start..end` of the log generation `version`, or of the value
+/// log file `version` when `value_log` is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    pub version: u64,
+    pub start: u64,
+    pub end: u64,
+    #[serde(default)]
+    pub value_log: bool,
+}
+
+impl BackupManifest {
+    /// Read the manifest of the backup in `dir`.
+    pub fn load(dir: &Path) -> Result<BackupManifest> {
+        let file = File::open(dir.join(MANIFEST))
+            .map_err(|_| KvsErr::StringErr(format!("{} has no backup manifest", dir.display())))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    fn lens(&self, value_log: bool) -> &BTreeMap<u64, u64> {
+        if value_log {
+            &self.value_logs
+        } else {
+            &self.generations
+        }
+    }
+
+    fn save(&self, dir: &Path) -> Result<()> {
+        let mut file = File::create(dir.join(MANIFEST))?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    // Ship every generation and value log file, or with a `parent` backup only
+    // what was written since. Closed files never change, so whole ones are hard
+    // linked when possible; the active ones are copied up to the last flushed record.
+    pub(super) fn backup(&mut self, dir: &Path, parent: Option<&Path>) -> Result<BackupManifest> {
+        let parent = parent.map(BackupManifest::load).transpose()?;
+        prepare_backup_dir(dir)?;
+        self.flush_writes()?;
+
+        let mut manifest = BackupManifest {
+            id: backup_id(),
+            parent: parent.as_ref().map(|parent| parent.id.clone()),
+            generations: BTreeMap::new(),
+            value_logs: BTreeMap::new(),
+            segments: Vec::new(),
+        };
+        // (value_log, version, len, active) of every file
+        let mut files = Vec::new();
+        for &version in self.readers.keys() {
+            let active = version == self.version;
+            let len = if active {
+                self.writer.pos
+            } else {
+                fs::metadata(file_path(&self.path, false, version))?.len()
+            };
+            files.push((false, version, len, active));
+        }
+        let active_value_log = self.value_log.active();
+        for (version, len) in self.value_log.files()? {
+            files.push((true, version, len, version == active_value_log));
+        }
+
+        for (value_log, version, len, active) in files {
+            let src = file_path(&self.path, value_log, version);
+            let start = match parent
+                .as_ref()
+                .and_then(|parent| parent.lens(value_log).get(&version))
+            {
+                Some(&shipped) if shipped > len => {
+                    return Err(KvsErr::StringErr(format!(
+                        "{} shrank since the previous backup, take a full backup",
+                        file_name(value_log, version)
+                    )))
+                }
+                Some(&shipped) => shipped,
+                None => 0,
+            };
+            if value_log {
+                manifest.value_logs.insert(version, len);
+            } else {
+                manifest.generations.insert(version, len);
+            }
+            if start == len {
+                continue;
+            }
+
+            let dst = file_path(dir, value_log, version);
+            if active || start > 0 || fs::hard_link(&src, &dst).is_err() {
+                copy_range(&src, &dst, start, len)?;
+            }
+            manifest.segments.push(Segment {
+                version,
+                start,
+                end: len,
+                value_log,
+            });
+        }
+        fs::write(dir.join(ENGINE_MARKER), "kvs")?;
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+}
+
+/// Rebuild the log generations and value log files described by a chain of
+/// backups, base first, in `dir`.
+pub(crate) fn assemble(chain: &[&Path], dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut lens: BTreeMap<(bool, u64), u64> = BTreeMap::new();
+    let mut last: Option<BackupManifest> = None;
+    for &backup in chain {
+        let manifest = BackupManifest::load(backup)?;
+        if manifest.parent != last.map(|last| last.id) {
+            return Err(KvsErr::StringErr(match manifest.parent {
+                None => format!("{} is a full backup, not an increment", backup.display()),
+                Some(_) => format!("{} does not follow the backup before it", backup.display()),
+            }));
+        }
+        for segment in &manifest.segments {
+            let name = file_name(segment.value_log, segment.version);
+            let len = lens
+                .entry((segment.value_log, segment.version))
+                .or_insert(0);
+            if *len != segment.start {
+                return Err(KvsErr::StringErr(format!(
+                    "{} expects {} to hold {} bytes, found {}",
+                    backup.display(),
+                    name,
+                    segment.start,
+                    len
+                )));
+            }
+            let mut src = File::open(backup.join(&name))?;
+            let mut dst = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join(&name))?;
+            let copied = io::copy(&mut src, &mut dst)?;
+            if copied != segment.end - segment.start {
+                return Err(KvsErr::StringErr(format!(
+                    "{} in {} is {} bytes, expected {}",
+                    name,
+                    backup.display(),
+                    copied,
+                    segment.end - segment.start
+                )));
+            }
+            *len = segment.end;
+        }
+        last = Some(manifest);
+    }
+
+    let last = last.ok_or_else(|| KvsErr::StringErr("no backup to restore".to_owned()))?;
+    // files compacted or collected away before the last backup are not part of the store
+    for &(value_log, version) in lens.keys() {
+        if !last.lens(value_log).contains_key(&version) {
+            fs::remove_file(file_path(dir, value_log, version))?;
+        }
+    }
+    for value_log in [false, true] {
+        for (&version, &len) in last.lens(value_log) {
+            if lens.get(&(value_log, version)).cloned().unwrap_or(0) != len {
+                return Err(KvsErr::StringErr(format!(
+                    "backup chain is missing data of {}",
+                    file_name(value_log, version)
+                )));
+            }
+        }
+    }
+    fs::write(dir.join(ENGINE_MARKER), "kvs")?;
+    Ok(())
+}
+
+// `<version>.vlog` for value log files, `<version>.log` for generations
+fn file_name(value_log: bool, version: u64) -> String {
+    let extension = if value_log { "vlog" } else { "log" };
+    format!("{}.{}", version, extension)
+}
+
+fn file_path(dir: &Path, value_log: bool, version: u64) -> PathBuf {
+    dir.join(file_name(value_log, version))
+}
+
+fn copy_range(src: &Path, dst: &Path, start: u64, end: u64) -> Result<()> {
+    let mut src = File::open(src)?;
+    src.seek(SeekFrom::Start(start))?;
+    let mut dst = File::create(dst)?;
+    io::copy(&mut src.take(end - start), &mut dst)?;
+    dst.sync_all()?;
+    Ok(())
+}
+
+fn backup_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    format!("{:x}", nanos)
+}
@@ -0,0 +1,43 @@
+use super::OpCmd;
+use crate::{KvsErr, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// A `Set` whose value is LZ4 compressed, base64 encoded since records are JSON,
+/// which makes the compressed bytes a third larger. The record tag marks it as
+/// compressed, so it can sit next to plain records.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Compressed {
+    pub(super) key: String,
+    lz4: String,
+}
+
+impl Compressed {
+    // compress the value of a set, unless that would not make the record smaller
+    pub(super) fn new(key: &str, value: &str) -> Option<Compressed> {
+        let lz4 = STANDARD.encode(lz4_flex::compress_prepend_size(value.as_bytes()));
+        Some(Compressed {
+            key: key.to_owned(),
+            lz4,
+        })
+        .filter(|compressed| compressed.lz4.len() < value.len())
+    }
+
+    pub(super) fn value(&self) -> Result<String> {
+        let corrupt = |e: &dyn std::fmt::Display| {
+            KvsErr::StringErr(format!("corrupt compressed value of {}: {}", self.key, e))
+        };
+        let bytes = STANDARD.decode(&self.lz4).map_err(|e| corrupt(&e))?;
+        let value = lz4_flex::decompress_size_prepended(&bytes).map_err(|e| corrupt(&e))?;
+        Ok(String::from_utf8(value)?)
+    }
+
+    pub(super) fn inflate(self) -> Result<OpCmd> {
+        let value = self.value()?;
+        Ok(OpCmd::Set {
+            key: self.key,
+            value,
+        })
+    }
+}
@@ -0,0 +1,148 @@
+use super::OpCmd;
+use crate::auth::{from_hex, to_hex};
+use crate::{KvsErr, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const KEY_LEN: usize = 32;
+
+/// Keys encrypting the records of a `KvStore` with AES-256-GCM.
+///
+/// A key file holds one hex encoded 256-bit key per line, as printed by
+/// `kvs keygen`. The first key encrypts new records, the others are only used
+/// to read records written before a rotation; compaction rewrites those with
+/// the first key, after which the old keys can be dropped from the file.
+#[derive(Clone)]
+pub struct Keyring {
+    // key id, i.e. the start of the key's SHA-256, and the key
+    keys: Vec<(String, LessSafeKey)>,
+    rng: SystemRandom,
+}
+
+/// A record encrypted with the key `key`, hex encoded.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Sealed {
+    key: String,
+    nonce: String,
+    data: String,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<&str> = self.keys.iter().map(|(id, _)| id.as_str()).collect();
+        write!(f, "Keyring({:?})", ids)
+    }
+}
+
+impl Keyring {
+    /// Read a key file. Empty lines and lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Keyring> {
+        let path = path.as_ref();
+        let mut keys = Vec::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = from_hex(line)
+                .filter(|key| key.len() == KEY_LEN)
+                .ok_or_else(|| {
+                    KvsErr::StringErr(format!(
+                        "line {} of key file {} is not a 256-bit hex key",
+                        number + 1,
+                        path.display()
+                    ))
+                })?;
+            keys.push(key);
+        }
+        Keyring::new(keys)
+    }
+
+    /// Build a keyring from raw 256-bit keys, the first one encrypting new records.
+    pub fn new(keys: Vec<Vec<u8>>) -> Result<Keyring> {
+        if keys.is_empty() {
+            return Err(KvsErr::StringErr("no encryption key given".to_owned()));
+        }
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let unbound = UnboundKey::new(&AES_256_GCM, key)
+                    .map_err(|_| KvsErr::StringErr("encryption keys are 256 bits".to_owned()))?;
+                Ok((key_id(key), LessSafeKey::new(unbound)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Keyring {
+            keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// A fresh random key, hex encoded as in key files.
+    pub fn generate_key() -> Result<String> {
+        let mut key = [0u8; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| KvsErr::StringErr("no randomness available".to_owned()))?;
+        Ok(to_hex(&key))
+    }
+
+    // whether `sealed` was encrypted by one of our keys
+    pub(super) fn has_key(&self, sealed: &Sealed) -> bool {
+        self.keys.iter().any(|(id, _)| *id == sealed.key)
+    }
+
+    // whether `sealed` was encrypted by the current key
+    pub(super) fn is_current(&self, sealed: &Sealed) -> bool {
+        self.keys[0].0 == sealed.key
+    }
+
+    // encrypt `cmd` with the current key
+    pub(super) fn seal(&self, cmd: &OpCmd) -> Result<OpCmd> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| KvsErr::StringErr("no randomness available".to_owned()))?;
+        let (id, key) = &self.keys[0];
+        let mut data = serde_json::to_vec(cmd)?;
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(id.as_bytes()),
+            &mut data,
+        )
+        .map_err(|_| KvsErr::StringErr("encryption failed".to_owned()))?;
+        Ok(OpCmd::Sealed(Sealed {
+            key: id.clone(),
+            nonce: to_hex(&nonce),
+            data: to_hex(&data),
+        }))
+    }
+
+    // decrypt a sealed record, failing with `KvsErr::WrongKey` unless it was
+    // sealed by one of our keys and left untouched since
+    pub(super) fn open(&self, sealed: &Sealed) -> Result<OpCmd> {
+        let key = match self.keys.iter().find(|(id, _)| *id == sealed.key) {
+            Some((_, key)) => key,
+            None => return Err(KvsErr::WrongKey),
+        };
+        let nonce = from_hex(&sealed.nonce)
+            .and_then(|nonce| Nonce::try_assume_unique_for_key(&nonce).ok())
+            .ok_or(KvsErr::WrongKey)?;
+        let mut data = from_hex(&sealed.data).ok_or(KvsErr::WrongKey)?;
+        let plain = key
+            .open_in_place(nonce, Aad::from(sealed.key.as_bytes()), &mut data)
+            .map_err(|_| KvsErr::WrongKey)?;
+        match serde_json::from_slice(plain)? {
+            OpCmd::Sealed(_) => Err(KvsErr::UnexpectedCommandType),
+            cmd => Ok(cmd),
+        }
+    }
+}
+
+fn key_id(key: &[u8]) -> String {
+    to_hex(&Sha256::digest(key)[..4])
+}
@@ -0,0 +1,136 @@
+use super::{
+    commands, decode, load, log_path, sorted_version_list, BufReaderWithPos, Index, Keyring, OpCmd,
+    ValueLog, ValuePointer,
+};
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// A record of a `KvStore` log, as found by `dump`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Generation, i.e. the `<version>.log` file holding the record.
+    pub version: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub op: LogOp,
+    /// Whether the index built on open points at this record.
+    pub live: bool,
+    /// Set for dead records whose value is in the value log, which is not read
+    /// as garbage collection may have deleted it; `op` then has an empty value.
+    pub value_collected: bool,
+}
+
+/// The command stored in a `LogRecord`, also the unit shipped to replicas.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl LogOp {
+    pub fn key(&self) -> &str {
+        match self {
+            LogOp::Set { key, .. } | LogOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Call `visit` with every record of the store in `dir`, oldest first.
+/// The index is rebuilt the same way `KvStore::open` does to tell which records are live.
+pub fn dump<F>(dir: impl AsRef<Path>, visit: F) -> Result<()>
+where
+    F: FnMut(LogRecord) -> Result<()>,
+{
+    dump_with_keyring(dir, None, visit)
+}
+
+/// Like `dump`, for a store encrypted with `keyring`.
+pub fn dump_with_keyring<F>(
+    dir: impl AsRef<Path>,
+    keyring: Option<&Keyring>,
+    mut visit: F,
+) -> Result<()>
+where
+    F: FnMut(LogRecord) -> Result<()>,
+{
+    let dir = dir.as_ref();
+    let versions = sorted_version_list(dir)?;
+    let mut index = Index::new(dir, false);
+    for &version in &versions {
+        let mut reader = BufReaderWithPos::new(File::open(log_path(dir, version))?)?;
+        load(version, &mut reader, &mut index, keyring)?;
+    }
+    let mut value_log = ValueLog::open(dir)?;
+
+    for version in versions {
+        let mut stream = commands(BufReader::new(File::open(log_path(dir, version))?));
+        let mut pos = 0;
+        while let Some(cmd) = stream.next() {
+            let next_pos = stream.byte_offset() as u64;
+            let cmd = decode(cmd?, keyring)?;
+            let key = match &cmd {
+                OpCmd::Set { key, .. } | OpCmd::Remove { key } => key,
+                OpCmd::Pointer(ValuePointer { key, .. }) => key,
+                OpCmd::Sealed(_) | OpCmd::Compressed(_) => {
+                    return Err(KvsErr::UnexpectedCommandType)
+                }
+            };
+            let live = index
+                .get(key)?
+                .is_some_and(|cmd_pos| cmd_pos.version == version && cmd_pos.pos == pos);
+            let mut value_collected = false;
+            let op = match cmd {
+                OpCmd::Set { key, value } => LogOp::Set { key, value },
+                OpCmd::Remove { key } => LogOp::Remove { key },
+                OpCmd::Pointer(ValuePointer { key, value }) if live => LogOp::Set {
+                    value: value_log.lookup(&key, value, keyring)?,
+                    key,
+                },
+                OpCmd::Pointer(ValuePointer { key, .. }) => {
+                    value_collected = true;
+                    LogOp::Set {
+                        key,
+                        value: String::new(),
+                    }
+                }
+                OpCmd::Sealed(_) | OpCmd::Compressed(_) => {
+                    return Err(KvsErr::UnexpectedCommandType)
+                }
+            };
+            visit(LogRecord {
+                version,
+                offset: pos,
+                len: next_pos - pos,
+                op,
+                live,
+                value_collected,
+            })?;
+            pos = next_pos;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.log:{} ({} bytes) {} ",
+            self.version,
+            self.offset,
+            self.len,
+            if self.live { "live" } else { "dead" }
+        )?;
+        match &self.op {
+            LogOp::Set { key, .. } if self.value_collected => {
+                write!(f, "set {:?} (value collected)", key)
+            }
+            LogOp::Set { key, value } => write!(f, "set {:?} {:?}", key, value),
+            LogOp::Remove { key } => write!(f, "rm {:?}", key),
+        }
+    }
+}
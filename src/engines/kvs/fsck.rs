@@ -0,0 +1,341 @@
+use super::{
+    commands, log_path, remove_index, sorted_version_list, unseal, CommandPos, Compressed, Keyring,
+    OpCmd, ValueLog, ValuePointer,
+};
+use crate::{KvsErr, Result};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// records start with one of these, used to find the next record after corrupt bytes
+const RECORD_STARTS: &[&[u8]] = &[
+    b"{\"Set\":",
+    b"{\"Remove\":",
+    b"{\"Sealed\":",
+    b"{\"Compressed\":",
+    b"{\"Pointer\":",
+];
+
+/// Result of checking a `KvStore` directory with `fsck`.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub generations: Vec<GenerationReport>,
+    /// Number of keys that would be visible after opening the store.
+    pub live_keys: u64,
+    /// Set records shadowed by a later write of the same key.
+    pub superseded: u64,
+    /// Remove records for keys that were not set at that point.
+    pub dangling_removes: u64,
+    /// Bytes a compaction would free.
+    pub reclaimable: u64,
+    /// Keys whose value could not be read back from the value log.
+    pub missing_values: Vec<String>,
+    /// Files in the directory that do not belong to the store.
+    pub orphans: Vec<PathBuf>,
+    /// Generation the readable records were salvaged into, when repairing.
+    pub repaired_into: Option<u64>,
+}
+
+/// What was found in one `<version>.log` file.
+#[derive(Debug)]
+pub struct GenerationReport {
+    pub version: u64,
+    pub size: u64,
+    pub records: u64,
+    pub live_records: u64,
+    pub corrupt: Vec<Corruption>,
+    /// Offset of a record cut short by the end of the file.
+    pub truncated_at: Option<u64>,
+}
+
+/// A byte range that could not be parsed as a record.
+#[derive(Debug)]
+pub struct Corruption {
+    pub offset: u64,
+    pub len: u64,
+    pub reason: String,
+}
+
+impl FsckReport {
+    /// Whether every log generation could be read completely, and every value
+    /// log record they point at too.
+    pub fn is_clean(&self) -> bool {
+        self.missing_values.is_empty()
+            && self
+                .generations
+                .iter()
+                .all(|gen| gen.corrupt.is_empty() && gen.truncated_at.is_none())
+    }
+}
+
+/// Check every log generation in `dir` with the same parsing `KvStore::open` uses.
+/// With `repair`, the readable live records are copied into a fresh generation
+/// and the old generations are removed. Value log files are left as they are.
+pub fn fsck(dir: impl AsRef<Path>, repair: bool) -> Result<FsckReport> {
+    fsck_with_keyring(dir, None, repair)
+}
+
+/// Like `fsck`, for a store encrypted with `keyring`. Records sealed by a key
+/// missing from `keyring` fail the check with `KvsErr::WrongKey` rather than
+/// being taken for corruption.
+pub fn fsck_with_keyring(
+    dir: impl AsRef<Path>,
+    keyring: Option<&Keyring>,
+    repair: bool,
+) -> Result<FsckReport> {
+    let dir = dir.as_ref();
+    let mut report = FsckReport::default();
+    let mut index: BTreeMap<String, CommandPos> = BTreeMap::new();
+    let mut total = 0;
+
+    let versions = sorted_version_list(dir)?;
+    for &version in &versions {
+        let mut buf = Vec::new();
+        File::open(log_path(dir, version))?.read_to_end(&mut buf)?;
+        total += buf.len() as u64;
+        let gen = check_generation(version, &buf, keyring, &mut index, &mut report)?;
+        report.generations.push(gen);
+    }
+
+    report.live_keys = index.len() as u64;
+    let mut live_bytes = 0;
+    for cmd_pos in index.values() {
+        live_bytes += cmd_pos.len;
+        if let Some(gen) = report
+            .generations
+            .iter_mut()
+            .find(|gen| gen.version == cmd_pos.version)
+        {
+            gen.live_records += 1;
+        }
+    }
+    report.reclaimable = total - live_bytes;
+    report.orphans = orphans(dir)?;
+
+    // keys whose value is lost are dropped by a repair
+    let mut value_log = ValueLog::open(dir)?;
+    for (key, cmd_pos) in &index {
+        if let Some(value) = cmd_pos.value {
+            match value_log.lookup(key, value, keyring) {
+                Ok(_) => {}
+                // as in the log files, do not take what we cannot decrypt for lost
+                Err(KvsErr::WrongKey) => return Err(KvsErr::WrongKey),
+                Err(_) => report.missing_values.push(key.clone()),
+            }
+        }
+    }
+    for key in &report.missing_values {
+        index.remove(key);
+    }
+
+    if repair {
+        let version = versions.last().unwrap_or(&0) + 1;
+        salvage(dir, version, &index)?;
+        for version in versions {
+            fs::remove_file(log_path(dir, version))?;
+            remove_index(dir, version)?;
+        }
+        report.repaired_into = Some(version);
+    }
+    Ok(report)
+}
+
+// parse one generation, replaying its records into `index` like `load` does
+fn check_generation(
+    version: u64,
+    buf: &[u8],
+    keyring: Option<&Keyring>,
+    index: &mut BTreeMap<String, CommandPos>,
+    report: &mut FsckReport,
+) -> Result<GenerationReport> {
+    let mut gen = GenerationReport {
+        version,
+        size: buf.len() as u64,
+        records: 0,
+        live_records: 0,
+        corrupt: Vec::new(),
+        truncated_at: None,
+    };
+
+    let mut start = 0;
+    while start < buf.len() {
+        let mut stream = commands(&buf[start..]);
+        let mut pos = start;
+        let reason = loop {
+            match stream.next() {
+                Some(Ok(cmd)) => {
+                    if let OpCmd::Sealed(sealed) = &cmd {
+                        if !keyring.is_some_and(|keyring| keyring.has_key(sealed)) {
+                            return Err(KvsErr::WrongKey);
+                        }
+                    }
+                    // a record the key does not authenticate was tampered with
+                    let cmd = match unseal(cmd, keyring) {
+                        Ok(cmd) => cmd,
+                        Err(e) => break Some(e.to_string()),
+                    };
+                    if let OpCmd::Compressed(compressed) = &cmd {
+                        if let Err(e) = compressed.value() {
+                            break Some(e.to_string());
+                        }
+                    }
+                    let next_pos = start + stream.byte_offset();
+                    gen.records += 1;
+                    replay(
+                        cmd,
+                        (version, pos as u64..next_pos as u64).into(),
+                        index,
+                        report,
+                    );
+                    pos = next_pos;
+                }
+                Some(Err(e)) if e.is_eof() => {
+                    gen.truncated_at = Some(pos as u64);
+                    return Ok(gen);
+                }
+                Some(Err(e)) => break Some(e.to_string()),
+                None => break None,
+            }
+        };
+        let reason = match reason {
+            Some(reason) => reason,
+            None => break,
+        };
+        // skip to the next thing that looks like a record and carry on from there
+        let resume = next_record_start(buf, pos + 1).unwrap_or(buf.len());
+        gen.corrupt.push(Corruption {
+            offset: pos as u64,
+            len: (resume - pos) as u64,
+            reason,
+        });
+        start = resume;
+    }
+    Ok(gen)
+}
+
+fn replay(
+    cmd: OpCmd,
+    cmd_pos: CommandPos,
+    index: &mut BTreeMap<String, CommandPos>,
+    report: &mut FsckReport,
+) {
+    match cmd {
+        OpCmd::Set { key, .. } | OpCmd::Compressed(Compressed { key, .. }) => {
+            if index.insert(key, cmd_pos).is_some() {
+                report.superseded += 1;
+            }
+        }
+        OpCmd::Pointer(ValuePointer { key, value }) => {
+            let cmd_pos = CommandPos {
+                value: Some(value),
+                ..cmd_pos
+            };
+            if index.insert(key, cmd_pos).is_some() {
+                report.superseded += 1;
+            }
+        }
+        OpCmd::Remove { key } => {
+            if index.remove(&key).is_none() {
+                report.dangling_removes += 1;
+            }
+        }
+        OpCmd::Sealed(_) => {}
+    }
+}
+
+fn next_record_start(buf: &[u8], from: usize) -> Option<usize> {
+    (from..buf.len()).find(|&i| RECORD_STARTS.iter().any(|s| buf[i..].starts_with(s)))
+}
+
+// everything in the directory except log generations and the engine marker
+fn orphans(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut orphans = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let is_log = path.is_file()
+            && name
+                .strip_suffix(".log")
+                .or_else(|| name.strip_suffix(".vlog"))
+                .or_else(|| name.strip_suffix(".idx"))
+                .is_some_and(|version| version.parse::<u64>().is_ok());
+        if !is_log && name != "engine" {
+            orphans.push(path);
+        }
+    }
+    orphans.sort();
+    Ok(orphans)
+}
+
+// copy the live records into generation `version`
+fn salvage(dir: &Path, version: u64, index: &BTreeMap<String, CommandPos>) -> Result<()> {
+    let mut writer = io::BufWriter::new(
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(log_path(dir, version))?,
+    );
+    let mut readers = BTreeMap::new();
+    for cmd_pos in index.values() {
+        let reader = match readers.entry(cmd_pos.version) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(File::open(log_path(dir, cmd_pos.version))?),
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        io::copy(&mut reader.take(cmd_pos.len), &mut writer)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for gen in &self.generations {
+            writeln!(
+                f,
+                "{}.log: {} bytes, {} records, {} live",
+                gen.version, gen.size, gen.records, gen.live_records
+            )?;
+            for corruption in &gen.corrupt {
+                writeln!(
+                    f,
+                    "  corrupt record at offset {} ({} bytes): {}",
+                    corruption.offset, corruption.len, corruption.reason
+                )?;
+            }
+            if let Some(offset) = gen.truncated_at {
+                writeln!(
+                    f,
+                    "  truncated record at offset {} ({} bytes)",
+                    offset,
+                    gen.size - offset
+                )?;
+            }
+        }
+        writeln!(f, "live keys: {}", self.live_keys)?;
+        writeln!(f, "superseded records: {}", self.superseded)?;
+        writeln!(f, "dangling removes: {}", self.dangling_removes)?;
+        writeln!(f, "reclaimable bytes: {}", self.reclaimable)?;
+        for key in &self.missing_values {
+            writeln!(f, "missing value of {:?}", key)?;
+        }
+        for orphan in &self.orphans {
+            writeln!(f, "orphan file: {}", orphan.display())?;
+        }
+        match self.repaired_into {
+            Some(version) => write!(f, "salvaged live records into {}.log", version),
+            None if self.is_clean() => write!(f, "no errors found"),
+            None => write!(
+                f,
+                "errors found, run with --repair to salvage readable records"
+            ),
+        }
+    }
+}
@@ -0,0 +1,537 @@
+use super::{BufWriterWithPos, CommandPos};
+use crate::engines::bloom::Bloom;
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+// keys of the active generation kept in memory before it is closed
+const MEMTABLE_ENTRIES: usize = 64 * 1024;
+// index entries between two fences
+const FENCE_INTERVAL: u64 = 128;
+// entries read at once when walking the whole index
+const BATCH: usize = 1024;
+// width of the footer offset ending every index file
+const FOOTER_POS_LEN: u64 = 20;
+
+/// Where the key of every live record is.
+pub(super) enum Index {
+    /// Every key in memory.
+    Memory(BTreeMap<String, CommandPos>),
+    /// Only the keys of the active generation in memory, the others in index files.
+    Disk(DiskIndex),
+}
+
+/// An index whose closed generations each have a `<version>.idx` file, sorted by
+/// key, found through a sparse in-memory fence index and a bloom filter.
+pub(super) struct DiskIndex {
+    dir: PathBuf,
+    memtable: BTreeMap<String, Option<CommandPos>>, // `None` for removed keys
+    runs: Vec<Run>,                                 // oldest first
+    len: u64,
+}
+
+// an index file
+struct Run {
+    version: u64,
+    path: PathBuf,
+    file: File,
+    footer: Footer,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    log_len: u64,     // length of the generation when its index was written
+    entries_end: u64, // where the entries stop and the footer starts
+    fences: Vec<(String, u64)>,
+    bloom: Bloom,
+}
+
+// writes the entries of an index file, in key order
+pub(super) struct RunWriter {
+    path: PathBuf,
+    version: u64,
+    writer: BufWriterWithPos<File>,
+    count: u64,
+    fences: Vec<(String, u64)>,
+    bloom: Bloom,
+}
+
+/// The index being built by a compaction.
+pub(super) enum Rebuild {
+    Memory(BTreeMap<String, CommandPos>),
+    Disk(RunWriter),
+}
+
+type Entry = (String, Option<CommandPos>);
+type Entries<'a> = Peekable<Box<dyn Iterator<Item = Result<Entry>> + 'a>>;
+
+pub(super) fn index_path(dir: &Path, version: u64) -> PathBuf {
+    dir.join(format!("{}.idx", version))
+}
+
+// part `n` of the index of a generation being read, merged into its index file
+fn part_path(dir: &Path, version: u64, n: usize) -> PathBuf {
+    dir.join(format!("{}.{}.part", version, n))
+}
+
+// delete the index file of a generation, if it has one
+pub(super) fn remove_index(dir: &Path, version: u64) -> Result<()> {
+    match fs::remove_file(index_path(dir, version)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl Index {
+    pub(super) fn new(dir: &Path, on_disk: bool) -> Index {
+        if on_disk {
+            Index::Disk(DiskIndex {
+                dir: dir.to_owned(),
+                memtable: BTreeMap::new(),
+                runs: Vec::new(),
+                len: 0,
+            })
+        } else {
+            Index::Memory(BTreeMap::new())
+        }
+    }
+
+    pub(super) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.get(key).copied()),
+            Index::Disk(disk) => disk.get(key),
+        }
+    }
+
+    // point `key` at `cmd_pos`, returning where it pointed before
+    pub(super) fn insert(
+        &mut self,
+        key: String,
+        cmd_pos: CommandPos,
+    ) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.insert(key, cmd_pos)),
+            Index::Disk(disk) => {
+                let old = disk.get(&key)?;
+                disk.memtable.insert(key, Some(cmd_pos));
+                if old.is_none() {
+                    disk.len += 1;
+                }
+                Ok(old)
+            }
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.remove(key)),
+            Index::Disk(disk) => {
+                let old = disk.get(key)?;
+                if old.is_some() {
+                    disk.memtable.insert(key.to_owned(), None);
+                    // not exact while opening, until `set_len` counts the keys
+                    disk.len = disk.len.saturating_sub(1);
+                }
+                Ok(old)
+            }
+        }
+    }
+
+    pub(super) fn len(&self) -> u64 {
+        match self {
+            Index::Memory(map) => map.len() as u64,
+            Index::Disk(disk) => disk.len,
+        }
+    }
+
+    // set the number of live keys, once counted by a walk of the index files
+    pub(super) fn set_len(&mut self, len: u64) {
+        if let Index::Disk(disk) = self {
+            disk.len = len;
+        }
+    }
+
+    // up to `limit` live keys after `after`, in order
+    pub(super) fn range(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, CommandPos)>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        match self {
+            Index::Memory(map) => Ok(map
+                .range::<str, _>((start, Bound::Unbounded))
+                .take(limit)
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect()),
+            Index::Disk(disk) => disk.range(after, limit),
+        }
+    }
+
+    // call `visit` with every live key, in order
+    pub(super) fn for_each(&self, mut visit: impl FnMut(&str, &CommandPos)) -> Result<()> {
+        match self {
+            Index::Memory(map) => map.iter().for_each(|(key, cmd_pos)| visit(key, cmd_pos)),
+            Index::Disk(disk) => {
+                let mut after = None;
+                loop {
+                    let batch = disk.range(after.as_deref(), BATCH)?;
+                    batch.iter().for_each(|(key, cmd_pos)| visit(key, cmd_pos));
+                    match batch.into_iter().last() {
+                        Some((key, _)) => after = Some(key),
+                        None => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // use the index file of generation `version` instead of reading its log, when
+    // there is one written for the `log_len` bytes the generation has
+    pub(super) fn open_run(&mut self, version: u64, log_len: u64) -> Result<bool> {
+        match self {
+            Index::Memory(_) => Ok(false),
+            Index::Disk(disk) => match Run::open(&disk.dir, version, log_len)? {
+                Some(run) => {
+                    disk.runs.push(run);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+        }
+    }
+
+    // whether the active generation holds as many keys as should stay in memory
+    pub(super) fn is_full(&self) -> bool {
+        match self {
+            Index::Memory(_) => false,
+            Index::Disk(disk) => disk.memtable.len() >= MEMTABLE_ENTRIES,
+        }
+    }
+
+    // while the log of generation `version` is read, move the keys in memory to a
+    // part of its index, so that a long generation does not fill the memory
+    pub(super) fn seal_part(&mut self, version: u64) -> Result<()> {
+        if let Index::Disk(disk) = self {
+            let n = disk.parts(version);
+            let path = part_path(&disk.dir, version, n);
+            let mut writer = RunWriter::create_at(path, version, disk.memtable.len() as u64)?;
+            for (key, cmd_pos) in &disk.memtable {
+                writer.push(key, *cmd_pos)?;
+            }
+            disk.runs.push(writer.finish(0)?);
+            disk.memtable.clear();
+        }
+        Ok(())
+    }
+
+    // write the keys of generation `version`, now closed at `log_len` bytes, to its
+    // index file, merged with the parts written by `seal_part`
+    pub(super) fn seal(&mut self, version: u64, log_len: u64) -> Result<()> {
+        if let Index::Disk(disk) = self {
+            let first = disk.runs.len() - disk.parts(version);
+            let parts = disk.runs.split_off(first);
+            let expected = parts
+                .iter()
+                .map(|part| part.footer.fences.len() as u64 * FENCE_INTERVAL)
+                .sum::<u64>()
+                + disk.memtable.len() as u64;
+            let mut writer = RunWriter::create(&disk.dir, version, expected)?;
+            let memtable = disk
+                .memtable
+                .iter()
+                .map(|(key, cmd_pos)| Ok((key.clone(), *cmd_pos)));
+            let mut sources: Vec<Entries> =
+                vec![(Box::new(memtable) as Box<dyn Iterator<Item = _>>).peekable()];
+            for part in parts.iter().rev() {
+                sources.push(part.entries(None)?.peekable());
+            }
+            // removed keys are kept, they hide the older generations
+            while let Some((key, cmd_pos)) = next_entry(&mut sources)? {
+                writer.push(&key, cmd_pos)?;
+            }
+            drop(sources);
+            disk.runs.push(writer.finish(log_len)?);
+            disk.memtable.clear();
+            for part in parts {
+                fs::remove_file(&part.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // start the index of the compacted generation `version`
+    pub(super) fn rebuild(&self, version: u64) -> Result<Rebuild> {
+        match self {
+            Index::Memory(_) => Ok(Rebuild::Memory(BTreeMap::new())),
+            Index::Disk(disk) => Ok(Rebuild::Disk(RunWriter::create(
+                &disk.dir, version, disk.len,
+            )?)),
+        }
+    }
+
+    // switch to the index of a compacted generation `log_len` bytes long
+    pub(super) fn replace(&mut self, rebuilt: Rebuild, log_len: u64) -> Result<()> {
+        match (self, rebuilt) {
+            (Index::Memory(map), Rebuild::Memory(rebuilt)) => *map = rebuilt,
+            (Index::Disk(disk), Rebuild::Disk(writer)) => {
+                let run = writer.finish(log_len)?;
+                for old in disk.runs.drain(..) {
+                    remove_index(&disk.dir, old.version)?;
+                }
+                disk.runs.push(run);
+                disk.memtable.clear();
+            }
+            _ => return Err(KvsErr::UnexpectedCommandType),
+        }
+        Ok(())
+    }
+}
+
+impl Rebuild {
+    pub(super) fn push(&mut self, key: String, cmd_pos: CommandPos) -> Result<()> {
+        match self {
+            Rebuild::Memory(map) => {
+                map.insert(key, cmd_pos);
+            }
+            Rebuild::Disk(writer) => writer.push(&key, Some(cmd_pos))?,
+        }
+        Ok(())
+    }
+}
+
+impl DiskIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(&cmd_pos) = self.memtable.get(key) {
+            return Ok(cmd_pos);
+        }
+        for run in self.runs.iter().rev() {
+            if let Some(cmd_pos) = run.get(key)? {
+                return Ok(cmd_pos);
+            }
+        }
+        Ok(None)
+    }
+
+    // merge the memtable and the index files, the newest entry of a key winning
+    fn range(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, CommandPos)>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let memtable = self
+            .memtable
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, cmd_pos)| Ok((key.clone(), *cmd_pos)));
+        let mut sources: Vec<Entries> =
+            vec![(Box::new(memtable) as Box<dyn Iterator<Item = _>>).peekable()];
+        for run in self.runs.iter().rev() {
+            sources.push(run.entries(after)?.peekable());
+        }
+
+        let mut found = Vec::new();
+        while found.len() < limit {
+            match next_entry(&mut sources)? {
+                Some((key, Some(cmd_pos))) => found.push((key, cmd_pos)),
+                Some((_, None)) => {}
+                None => break,
+            }
+        }
+        Ok(found)
+    }
+
+    // how many parts of the index of generation `version` were written
+    fn parts(&self, version: u64) -> usize {
+        self.runs
+            .iter()
+            .rev()
+            .take_while(|run| run.version == version)
+            .count()
+    }
+}
+
+// the smallest key of the sorted `sources` with its entry in the first source
+// holding it, the newest
+fn next_entry(sources: &mut [Entries]) -> Result<Option<Entry>> {
+    let mut next: Option<String> = None;
+    for source in sources.iter_mut() {
+        if let Some(Err(_)) = source.peek() {
+            if let Some(Err(e)) = source.next() {
+                return Err(e);
+            }
+        }
+        if let Some(Ok((key, _))) = source.peek() {
+            if next.as_ref().is_none_or(|next| key < next) {
+                next = Some(key.clone());
+            }
+        }
+    }
+    let key = match next {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let mut newest = None;
+    for source in sources.iter_mut() {
+        if source
+            .peek()
+            .is_some_and(|entry| entry.as_ref().is_ok_and(|(found, _)| *found == key))
+        {
+            if let Some(Ok((_, cmd_pos))) = source.next() {
+                newest.get_or_insert(cmd_pos);
+            }
+        }
+    }
+    Ok(newest.map(|cmd_pos| (key, cmd_pos)))
+}
+
+impl Run {
+    // the index file of generation `version`, unless missing, unreadable or stale
+    fn open(dir: &Path, version: u64, log_len: u64) -> Result<Option<Run>> {
+        let path = index_path(dir, version);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match read_footer(&mut file) {
+            Some(footer) if footer.log_len == log_len => Ok(Some(Run {
+                version,
+                path,
+                file,
+                footer,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    // `Some(None)` when this file records `key` as removed
+    fn get(&self, key: &str) -> Result<Option<Option<CommandPos>>> {
+        if !self.footer.bloom.contains(key) {
+            return Ok(None);
+        }
+        let fences = &self.footer.fences;
+        let i = match fences.binary_search_by(|(fence, _)| fence.as_str().cmp(key)) {
+            Ok(i) => i,
+            Err(0) => return Ok(None),
+            Err(i) => i - 1,
+        };
+        let start = fences[i].1;
+        let end = fences
+            .get(i + 1)
+            .map_or(self.footer.entries_end, |fence| fence.1);
+        (&self.file).seek(SeekFrom::Start(start))?;
+        let block = BufReader::new((&self.file).take(end - start));
+        for entry in Deserializer::from_reader(block).into_iter::<Entry>() {
+            let (found, cmd_pos) = entry?;
+            if found == key {
+                return Ok(Some(cmd_pos));
+            }
+            if found.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // the entries with keys after `after`, read through a file handle of their own
+    fn entries(&self, after: Option<&str>) -> Result<Box<dyn Iterator<Item = Result<Entry>>>> {
+        let start = match after {
+            Some(after) => {
+                let fences = &self.footer.fences;
+                match fences.binary_search_by(|(fence, _)| fence.as_str().cmp(after)) {
+                    Ok(i) => fences[i].1,
+                    Err(0) => 0,
+                    Err(i) => fences[i - 1].1,
+                }
+            }
+            None => 0,
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file.take(self.footer.entries_end - start));
+        let after = after.map(str::to_owned);
+        Ok(Box::new(
+            Deserializer::from_reader(reader)
+                .into_iter::<Entry>()
+                .map(|entry| entry.map_err(KvsErr::from))
+                .filter(move |entry| match (entry, &after) {
+                    (Ok((key, _)), Some(after)) => key > after,
+                    _ => true,
+                }),
+        ))
+    }
+}
+
+fn read_footer(file: &mut File) -> Option<Footer> {
+    let len = file.metadata().ok()?.len();
+    let footer_end = len.checked_sub(FOOTER_POS_LEN)?;
+    file.seek(SeekFrom::Start(footer_end)).ok()?;
+    let mut footer_pos = String::new();
+    file.read_to_string(&mut footer_pos).ok()?;
+    let footer_pos: u64 = footer_pos.trim().parse().ok()?;
+    file.seek(SeekFrom::Start(footer_pos)).ok()?;
+    let footer = BufReader::new(file.take(footer_end.checked_sub(footer_pos)?));
+    serde_json::from_reader(footer).ok()
+}
+
+impl RunWriter {
+    fn create(dir: &Path, version: u64, expected: u64) -> Result<RunWriter> {
+        RunWriter::create_at(index_path(dir, version), version, expected)
+    }
+
+    // write the index file to `path`, once finished
+    fn create_at(path: PathBuf, version: u64, expected: u64) -> Result<RunWriter> {
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let file = File::create(tmp)?;
+        Ok(RunWriter {
+            path,
+            version,
+            writer: BufWriterWithPos::new(file)?,
+            count: 0,
+            fences: Vec::new(),
+            bloom: Bloom::new(expected),
+        })
+    }
+
+    fn push(&mut self, key: &str, cmd_pos: Option<CommandPos>) -> Result<()> {
+        if self.count.is_multiple_of(FENCE_INTERVAL) {
+            self.fences.push((key.to_owned(), self.writer.pos));
+        }
+        self.count += 1;
+        self.bloom.insert(key);
+        serde_json::to_writer(&mut self.writer, &(key, cmd_pos))?;
+        Ok(())
+    }
+
+    fn finish(mut self, log_len: u64) -> Result<Run> {
+        let footer = Footer {
+            log_len,
+            entries_end: self.writer.pos,
+            fences: self.fences,
+            bloom: self.bloom,
+        };
+        let footer_pos = self.writer.pos;
+        serde_json::to_writer(&mut self.writer, &footer)?;
+        write!(
+            self.writer,
+            "{:>width$}",
+            footer_pos,
+            width = FOOTER_POS_LEN as usize
+        )?;
+        self.writer.flush()?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::rename(tmp, &self.path)?;
+        Ok(Run {
+            version: self.version,
+            file: File::open(&self.path)?,
+            path: self.path,
+            footer,
+        })
+    }
+}
@@ -0,0 +1,656 @@
+use crate::KvsErr;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::de::{IoRead, StreamDeserializer};
+use serde_json::Deserializer;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::io::*;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{EngineStats, KvEngine};
+
+mod backup;
+mod compress;
+mod crypto;
+mod dump;
+mod fsck;
+mod index;
+mod vlog;
+pub(crate) use self::backup::assemble;
+pub use self::backup::{BackupManifest, Segment, MANIFEST};
+use self::compress::Compressed;
+pub use self::crypto::Keyring;
+use self::crypto::Sealed;
+pub use self::dump::{dump, dump_with_keyring, LogOp, LogRecord};
+pub use self::fsck::{fsck, fsck_with_keyring, Corruption, FsckReport, GenerationReport};
+use self::index::{remove_index, Index};
+use self::vlog::{separate, ValueLog, ValuePointer, ValuePos};
+
+pub struct KvStore {
+    path: PathBuf,
+    writer: BufWriterWithPos<File>,                 // 当前写入文件
+    version: u64,                                   // 当前版本号
+    index: Index,                                   // 索引
+    readers: BTreeMap<u64, BufReaderWithPos<File>>, // 每个版本文件接口
+    uncompacted: u64,                               // 记录需要未被压缩的内容大小
+    compactions: u64,                               // compactions run since open
+    compaction_time: Duration,                      // time spent compacting since open
+    compaction_threshold: u64,                      // stale bytes that trigger a compaction
+    keyring: Option<Keyring>,                       // encrypts records when set
+    compression_threshold: Option<usize>,           // values this long or longer are compressed
+    value_log: ValueLog,                            // large values kept out of the log files
+    value_log_threshold: Option<usize>,             // values this long or longer are separated
+    value_log_gc_threshold: u64,                    // stale value log bytes that trigger a GC
+}
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const VALUE_LOG_GC_THRESHOLD: u64 = 16 * 1024 * 1024;
+// entries read at once by a compaction
+const COMPACTION_BATCH: usize = 1024;
+
+/// How `KvStore::open_with` opens a store.
+#[derive(Default)]
+pub struct KvStoreOptions {
+    /// Encrypts the records, as with `KvStore::open_encrypted`.
+    pub keyring: Option<Keyring>,
+    /// Keeps the index of closed generations in `<version>.idx` files rather than
+    /// in memory, for more keys than fit in RAM. Not available with a keyring,
+    /// as index files hold the keys in the clear.
+    pub disk_index: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct CommandPos {
+    version: u64, // key相关的最近一次出现的版本
+    pos: u64,     //  key相关的最近一次出现的版本文件位置
+    len: u64,     // 指令长度
+    // where the value is when it went to the value log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<ValuePos>,
+}
+struct BufWriterWithPos<SeekWriter: Write + Seek> {
+    writer: BufWriter<SeekWriter>,
+    pos: u64,
+}
+struct BufReaderWithPos<SeekReader: Read + Seek> {
+    reader: BufReader<SeekReader>,
+    pos: u64,
+}
+#[derive(Serialize, Deserialize, Debug)]
+enum OpCmd {
+    Set { key: String, value: String },
+    Remove { key: String },
+    Sealed(Sealed),         // a Set or Remove encrypted by a Keyring
+    Compressed(Compressed), // a Set with its value compressed
+    Pointer(ValuePointer),  // a Set with its value in the value log
+}
+
+// decrypt `cmd` if it is sealed
+fn unseal(cmd: OpCmd, keyring: Option<&Keyring>) -> Result<OpCmd> {
+    match (cmd, keyring) {
+        (OpCmd::Sealed(sealed), Some(keyring)) => keyring.open(&sealed),
+        (OpCmd::Sealed(_), None) => Err(KvsErr::WrongKey),
+        (cmd, _) => Ok(cmd),
+    }
+}
+
+// read back a record as it was before `encode`
+fn decode(cmd: OpCmd, keyring: Option<&Keyring>) -> Result<OpCmd> {
+    match unseal(cmd, keyring)? {
+        OpCmd::Compressed(compressed) => compressed.inflate(),
+        cmd => Ok(cmd),
+    }
+}
+
+// `cmd` as written to disk, compressed and sealed as configured, or `None`
+// when it is written as is
+fn encode(
+    cmd: &OpCmd,
+    keyring: Option<&Keyring>,
+    compression_threshold: Option<usize>,
+) -> Result<Option<OpCmd>> {
+    let compressed = match (cmd, compression_threshold) {
+        (OpCmd::Set { key, value }, Some(threshold)) if value.len() >= threshold => {
+            Compressed::new(key, value).map(OpCmd::Compressed)
+        }
+        _ => None,
+    };
+    match keyring {
+        Some(keyring) => Ok(Some(keyring.seal(compressed.as_ref().unwrap_or(cmd))?)),
+        None => Ok(compressed),
+    }
+}
+
+fn sorted_version_list(path: &Path) -> Result<Vec<u64>> {
+    sorted_file_numbers(path, "log")
+}
+
+// the numbers of the `<n>.<extension>` files in `path`
+fn sorted_file_numbers(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut version_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    version_list.sort_unstable();
+    Ok(version_list)
+}
+
+// stream the commands stored in a log file
+fn commands<R: Read>(reader: R) -> StreamDeserializer<'static, IoRead<R>, OpCmd> {
+    Deserializer::from_reader(reader).into_iter::<OpCmd>()
+}
+
+// load load version file
+fn load(
+    version: u64,
+    reader: &mut BufReaderWithPos<File>,
+    index: &mut Index,
+    keyring: Option<&Keyring>,
+) -> Result<()> {
+    let mut pos: u64 = reader.seek(SeekFrom::Start(0))?;
+    let mut stream = commands(reader);
+    while let Some(cmd) = stream.next() {
+        let next_pos: u64 = stream.byte_offset() as u64;
+        match unseal(cmd?, keyring)? {
+            OpCmd::Set { key, .. } | OpCmd::Compressed(Compressed { key, .. }) => {
+                index.insert(key, (version, pos..next_pos).into())?;
+            }
+            OpCmd::Pointer(ValuePointer { key, value }) => {
+                let cmd_pos = CommandPos {
+                    value: Some(value),
+                    ..(version, pos..next_pos).into()
+                };
+                index.insert(key, cmd_pos)?;
+            }
+            OpCmd::Remove { key } => {
+                index.remove(&key)?;
+            }
+            OpCmd::Sealed(_) => return Err(KvsErr::UnexpectedCommandType),
+        }
+        if index.is_full() {
+            index.seal_part(version)?;
+        }
+        pos = next_pos;
+    }
+    Ok(())
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open a store whose records are encrypted with `keyring`. Plaintext records
+    /// left from before are still read, and encrypted by the next compaction.
+    /// Fails with `KvsErr::WrongKey` when some records were sealed by other keys.
+    pub fn open_encrypted(path: impl Into<PathBuf>, keyring: Keyring) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            keyring: Some(keyring),
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with(path, options)
+    }
+
+    /// Open a store with the given options.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let KvStoreOptions {
+            keyring,
+            disk_index,
+        } = options;
+        if disk_index && keyring.is_some() {
+            return Err(KvsErr::StringErr(
+                "the disk index can not be used with encryption".to_owned(),
+            ));
+        }
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let mut index = Index::new(&path, disk_index);
+        let mut readers = BTreeMap::new();
+        // load persistent data, from the index files of generations that have one
+        let version_list = sorted_version_list(&path)?;
+        let mut log_bytes = 0;
+        for &version in &version_list {
+            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, version))?)?;
+            let log_len = reader.reader.get_ref().metadata()?.len();
+            if !index.open_run(version, log_len)? {
+                load(version, &mut reader, &mut index, keyring.as_ref())?;
+                index.seal(version, log_len)?;
+            }
+            log_bytes += log_len;
+            readers.insert(version, reader);
+        }
+        // update version
+        let version = version_list.last().unwrap_or(&0) + 1;
+        // create new version file
+        let writer = new_log_file(&path, version, &mut readers)?;
+        let mut value_log = ValueLog::open(&path)?;
+        let (mut keys, mut live_bytes) = (0, 0);
+        index.for_each(|_, cmd_pos| {
+            keys += 1;
+            live_bytes += cmd_pos.len;
+            if let Some(value) = cmd_pos.value {
+                value_log.live(value);
+            }
+        })?;
+        index.set_len(keys);
+        let uncompacted = log_bytes - live_bytes;
+        let store = KvStore {
+            path,
+            writer,
+            version,
+            index,
+            readers,
+            uncompacted,
+            compactions: 0,
+            compaction_time: Duration::default(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            keyring,
+            compression_threshold: None,
+            value_log,
+            value_log_threshold: None,
+            value_log_gc_threshold: VALUE_LOG_GC_THRESHOLD,
+        };
+        Ok(store)
+    }
+
+    /// Sets how many bytes of stale records trigger a compaction, 1 MiB by default.
+    pub fn set_compaction_threshold(&mut self, bytes: u64) {
+        self.compaction_threshold = bytes;
+    }
+
+    /// Compresses values of at least `bytes` bytes with LZ4, off by default.
+    /// Records written before keep their encoding until the next compaction.
+    pub fn set_compression_threshold(&mut self, bytes: Option<usize>) {
+        self.compression_threshold = bytes;
+    }
+
+    /// Writes values of at least `bytes` bytes to the value log, off by default.
+    /// Compactions then only move a small pointer for each of them.
+    pub fn set_value_log_threshold(&mut self, bytes: Option<usize>) {
+        self.value_log_threshold = bytes;
+    }
+
+    /// Sets how many bytes of stale values trigger `collect_value_log`, 16 MiB by default.
+    pub fn set_value_log_gc_threshold(&mut self, bytes: u64) {
+        self.value_log_gc_threshold = bytes;
+    }
+
+    fn add_uncompacted(&mut self, new_uncompacted: u64) -> Result<()> {
+        self.uncompacted += new_uncompacted;
+        if self.uncompacted > self.compaction_threshold {
+            self.compact()?
+        }
+
+        Ok(())
+    }
+    // append a set command without flushing, return the length of the record it replaces
+    fn write_set(&mut self, key: String, value: String) -> Result<u64> {
+        let (cmd, value) = separate(
+            OpCmd::Set { key, value },
+            &mut self.value_log,
+            self.value_log_threshold,
+            self.keyring.as_ref(),
+            self.compression_threshold,
+        )?;
+        let pos = self.writer.pos;
+        self.append(&cmd)?;
+        // create index for get
+        if let OpCmd::Set { key, .. } | OpCmd::Pointer(ValuePointer { key, .. }) = cmd {
+            let cmd_pos = CommandPos {
+                value,
+                ..(self.version, pos..self.writer.pos).into()
+            };
+            if let Some(old_cmd) = self.index.insert(key, cmd_pos)? {
+                return Ok(self.forget(old_cmd));
+            }
+        }
+        Ok(0)
+    }
+
+    // account for a record no key points at any more, returning its length
+    fn forget(&mut self, old_cmd: CommandPos) -> u64 {
+        if let Some(value) = old_cmd.value {
+            self.value_log.forget(value);
+        }
+        old_cmd.len
+    }
+
+    // flush the value log before the records pointing into it
+    fn flush_writes(&mut self) -> Result<()> {
+        self.value_log.flush()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // start a new generation once the index holds as many of the active one's keys
+    // as it keeps in memory, writing them to the index file of the generation
+    fn maybe_seal_generation(&mut self) -> Result<()> {
+        if self.index.is_full() {
+            self.flush_writes()?;
+            self.index.seal(self.version, self.writer.pos)?;
+            self.version += 1;
+            self.writer = self.new_log_file(self.version)?;
+        }
+        Ok(())
+    }
+
+    // write `cmd` to the active log file, compressed and sealed as configured
+    fn append(&mut self, cmd: &OpCmd) -> Result<()> {
+        let encoded = encode(cmd, self.keyring.as_ref(), self.compression_threshold)?;
+        serde_json::to_writer(&mut self.writer, encoded.as_ref().unwrap_or(cmd))?;
+        Ok(())
+    }
+
+    // read the value of the set command stored at `cmd_pos`
+    fn read_value(&mut self, cmd_pos: CommandPos) -> Result<String> {
+        let reader = self
+            .readers
+            .get_mut(&cmd_pos.version)
+            .ok_or_else(|| KvsErr::StringErr(format!("{} reader not found", cmd_pos.version)))?;
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let cmd = serde_json::from_reader(reader.take(cmd_pos.len))?;
+        match decode(cmd, self.keyring.as_ref())? {
+            OpCmd::Set { value, .. } => Ok(value),
+            OpCmd::Pointer(ValuePointer { key, value }) => {
+                self.value_log.lookup(&key, value, self.keyring.as_ref())
+            }
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    /// Remove a given key.
+    fn new_log_file(&mut self, version: u64) -> Result<BufWriterWithPos<File>> {
+        new_log_file(&self.path, version, &mut self.readers)
+    }
+    fn compact(&mut self) -> Result<()> {
+        // compact step
+        // 1. iterate all keys, copy the latest record to a new file
+        // create new log for later write;
+        let started = Instant::now();
+        let compaction_version = self.version + 1;
+        self.version += 2;
+        self.writer = self.new_log_file(self.version)?;
+
+        let mut writer = self.new_log_file(compaction_version)?;
+        let mut rebuilt = self.index.rebuild(compaction_version)?;
+        let mut pos: u64 = 0;
+        let mut after = None;
+        loop {
+            let batch = self.index.range(after.as_deref(), COMPACTION_BATCH)?;
+            after = match batch.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+            for (key, cmd_pos) in batch {
+                let reader = self
+                    .readers
+                    .get_mut(&cmd_pos.version)
+                    .unwrap_or_else(|| panic!("{} reader not found", cmd_pos.version));
+                if reader.pos != cmd_pos.pos {
+                    reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+                }
+                let mut entry_reader = reader.take(cmd_pos.len);
+                let mut value = cmd_pos.value;
+                if self.keyring.is_none()
+                    && self.compression_threshold.is_none()
+                    && self.value_log_threshold.is_none()
+                {
+                    io::copy(&mut entry_reader, &mut writer)?;
+                } else {
+                    // write again with the current key, compression and value log,
+                    // rotating keys and recompressing or separating old records
+                    let keyring = self.keyring.as_ref();
+                    let mut cmd = decode(serde_json::from_reader(entry_reader)?, keyring)?;
+                    // values in the value log sealed by an older key are sealed again
+                    if let (OpCmd::Pointer(pointer), Some(keyring)) = (&cmd, keyring) {
+                        if !self.value_log.sealed_by_current(pointer.value, keyring)? {
+                            let old = pointer.value;
+                            let plain = self.value_log.lookup(&key, old, Some(keyring))?;
+                            self.value_log.forget(old);
+                            value = None;
+                            cmd = OpCmd::Set {
+                                key: key.clone(),
+                                value: plain,
+                            };
+                        }
+                    }
+                    let (cmd, separated) = separate(
+                        cmd,
+                        &mut self.value_log,
+                        self.value_log_threshold,
+                        keyring,
+                        self.compression_threshold,
+                    )?;
+                    value = separated.or(value);
+                    let encoded = encode(&cmd, keyring, self.compression_threshold)?;
+                    serde_json::to_writer(&mut writer, encoded.as_ref().unwrap_or(&cmd))?;
+                }
+                let cmd_pos = CommandPos {
+                    value,
+                    ..(compaction_version, (pos..writer.pos)).into()
+                };
+                rebuilt.push(key, cmd_pos)?;
+                pos = writer.pos;
+            }
+        }
+        self.value_log.flush()?;
+        writer.flush()?;
+        self.index.replace(rebuilt, writer.pos)?;
+        let removed_versions: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&version| version < compaction_version)
+            .cloned()
+            .collect();
+        for version in removed_versions {
+            self.readers.remove(&version);
+            fs::remove_file(log_path(&self.path, version))?;
+            remove_index(&self.path, version)?;
+        }
+        self.uncompacted = 0;
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+// new
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+// read
+impl<R> Read for BufReaderWithPos<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+// seek
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+fn log_path(dir: &Path, version: u64) -> PathBuf {
+    dir.join(format!("{}.log", version))
+}
+fn new_log_file(
+    path: &Path,
+    version: u64,
+    readers: &mut BTreeMap<u64, BufReaderWithPos<File>>,
+) -> Result<BufWriterWithPos<File>> {
+    let path: PathBuf = log_path(path, version);
+
+    // writer file should have rw auth
+    let writer = BufWriterWithPos::new(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?,
+    )?;
+    readers.insert(version, BufReaderWithPos::new(File::open(&path)?)?);
+
+    Ok(writer)
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((version, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            version,
+            pos: range.start,
+            len: range.end - range.start,
+            value: None,
+        }
+    }
+}
+
+impl KvEngine for KvStore {
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let old_len = self.write_set(key, value)?;
+        self.flush_writes()?;
+        self.maybe_seal_generation()?;
+        // update uncompacted data size
+        self.add_uncompacted(old_len)?;
+        self.maybe_collect_value_log()
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        // find last record path from index
+        match self.index.get(&key)? {
+            Some(cmd_pos) => Ok(Some(self.read_value(cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        // Check key existence
+        if self.index.get(&key)?.is_some() {
+            let cmd = OpCmd::Remove { key };
+            // serialize record
+            self.append(&cmd)?;
+            self.writer.flush()?;
+
+            if let OpCmd::Remove { key } = cmd {
+                if let Some(old_cmd) = self.index.remove(&key)? {
+                    let old_len = self.forget(old_cmd);
+                    self.maybe_seal_generation()?;
+                    self.add_uncompacted(old_len)?
+                }
+            }
+            self.maybe_collect_value_log()
+        } else {
+            Err(KvsErr::KeyNotFound)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.value_log.sync()?;
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        self.backup(dir, None).map(|_| ())
+    }
+
+    fn backup_incremental(&mut self, dir: &Path, parent: &Path) -> Result<()> {
+        self.backup(dir, Some(parent)).map(|_| ())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut disk_bytes = 0;
+        for &version in self.readers.keys() {
+            disk_bytes += fs::metadata(log_path(&self.path, version))?.len();
+        }
+        for (_, size) in self.value_log.files()? {
+            disk_bytes += size;
+        }
+        Ok(EngineStats {
+            keys: self.index.len(),
+            log_files: self.readers.len() as u64,
+            uncompacted_bytes: self.uncompacted,
+            compactions: self.compactions,
+            compaction_seconds: self.compaction_time.as_secs_f64(),
+            disk_bytes,
+        })
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let positions = self.index.range(after.as_deref(), limit)?;
+        positions
+            .into_iter()
+            .map(|(key, cmd_pos)| Ok((key, self.read_value(cmd_pos)?)))
+            .collect()
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        // write every record first so the batch costs a single flush
+        let mut old_len = 0;
+        for (key, value) in pairs {
+            old_len += self.write_set(key, value)?;
+        }
+        self.flush_writes()?;
+        self.maybe_seal_generation()?;
+        self.add_uncompacted(old_len)?;
+        self.maybe_collect_value_log()
+    }
+}
@@ -0,0 +1,278 @@
+use super::{
+    decode, encode, sorted_file_numbers, BufReaderWithPos, BufWriterWithPos, CommandPos, Keyring,
+    KvStore, OpCmd, COMPACTION_BATCH,
+};
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// value log files are rolled over past this size
+const VALUE_LOG_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where a value moved to the value log is: `len` bytes at `pos` of `<file>.vlog`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct ValuePos {
+    pub(super) file: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+}
+
+/// What the main log holds for a `Set` whose value went to the value log.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ValuePointer {
+    pub(super) key: String,
+    pub(super) value: ValuePos,
+}
+
+/// Append-only `<n>.vlog` files holding large values as `Set` records, so that
+/// compacting the main log only moves the pointers to them.
+/// Files are deleted once their live values were moved by `collect_value_log`.
+pub(super) struct ValueLog {
+    dir: PathBuf,
+    active: u64,                            // file new values are appended to
+    writer: Option<BufWriterWithPos<File>>, // opened by the first append
+    readers: BTreeMap<u64, BufReaderWithPos<File>>,
+    stale: BTreeMap<u64, u64>, // bytes of each file no pointer refers to
+}
+
+pub(super) fn value_log_path(dir: &Path, file: u64) -> PathBuf {
+    dir.join(format!("{}.vlog", file))
+}
+
+impl ValueLog {
+    // open the value log in `dir`, every value stale until marked `live`
+    pub(super) fn open(dir: &Path) -> Result<ValueLog> {
+        let files = sorted_file_numbers(dir, "vlog")?;
+        let mut readers = BTreeMap::new();
+        let mut stale = BTreeMap::new();
+        for &file in &files {
+            let path = value_log_path(dir, file);
+            stale.insert(file, fs::metadata(&path)?.len());
+            readers.insert(file, BufReaderWithPos::new(File::open(path)?)?);
+        }
+        Ok(ValueLog {
+            dir: dir.to_owned(),
+            active: files.last().copied().unwrap_or(1),
+            writer: None,
+            readers,
+            stale,
+        })
+    }
+
+    // write an encoded `Set` record, returning where it went
+    pub(super) fn append(&mut self, record: &OpCmd) -> Result<ValuePos> {
+        if self
+            .writer
+            .as_ref()
+            .is_some_and(|writer| writer.pos >= VALUE_LOG_FILE_SIZE)
+        {
+            self.roll()?;
+        }
+        let writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => self.open_active()?,
+        };
+        let writer = self.writer.insert(writer);
+        let pos = writer.pos;
+        serde_json::to_writer(&mut *writer, record)?;
+        Ok(ValuePos {
+            file: self.active,
+            pos,
+            len: writer.pos - pos,
+        })
+    }
+
+    fn open_active(&mut self) -> Result<BufWriterWithPos<File>> {
+        let path = value_log_path(&self.dir, self.active);
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.seek(SeekFrom::End(0))?;
+        if !self.readers.contains_key(&self.active) {
+            let reader = BufReaderWithPos::new(File::open(&path)?)?;
+            self.readers.insert(self.active, reader);
+            self.stale.insert(self.active, 0);
+        }
+        BufWriterWithPos::new(file)
+    }
+
+    // leave the active file for a fresh one
+    fn roll(&mut self) -> Result<()> {
+        self.flush()?;
+        self.writer = None;
+        self.active += 1;
+        Ok(())
+    }
+
+    // read back the value of `key` stored at `value`
+    pub(super) fn lookup(
+        &mut self,
+        key: &str,
+        value: ValuePos,
+        keyring: Option<&Keyring>,
+    ) -> Result<String> {
+        match decode(self.read(value)?, keyring)? {
+            OpCmd::Set { key: found, value } if found == key => Ok(value),
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    // whether the record at `value` was sealed by the current key of `keyring`
+    pub(super) fn sealed_by_current(&mut self, value: ValuePos, keyring: &Keyring) -> Result<bool> {
+        Ok(matches!(self.read(value)?, OpCmd::Sealed(sealed) if keyring.is_current(&sealed)))
+    }
+
+    // the record at `value`, as written
+    fn read(&mut self, value: ValuePos) -> Result<OpCmd> {
+        let reader = self
+            .readers
+            .get_mut(&value.file)
+            .ok_or_else(|| KvsErr::StringErr(format!("{}.vlog not found", value.file)))?;
+        reader.seek(SeekFrom::Start(value.pos))?;
+        Ok(serde_json::from_reader(reader.take(value.len))?)
+    }
+
+    // note that a pointer refers to `value`
+    pub(super) fn live(&mut self, value: ValuePos) {
+        if let Some(stale) = self.stale.get_mut(&value.file) {
+            *stale = stale.saturating_sub(value.len);
+        }
+    }
+
+    // note that no pointer refers to `value` any more
+    pub(super) fn forget(&mut self, value: ValuePos) {
+        if let Some(stale) = self.stale.get_mut(&value.file) {
+            *stale += value.len;
+        }
+    }
+
+    pub(super) fn stale_bytes(&self) -> u64 {
+        self.stale.values().sum()
+    }
+
+    pub(super) fn active(&self) -> u64 {
+        self.active
+    }
+
+    // every file with its size, after flushing
+    pub(super) fn files(&mut self) -> Result<Vec<(u64, u64)>> {
+        self.flush()?;
+        self.readers
+            .keys()
+            .map(|&file| Ok((file, fs::metadata(value_log_path(&self.dir, file))?.len())))
+            .collect()
+    }
+
+    pub(super) fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+            writer.writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+
+    // the files at least half stale, or else the stalest one
+    fn collectable(&mut self) -> Result<Vec<u64>> {
+        let mut collectable: Vec<u64> = self
+            .files()?
+            .into_iter()
+            .filter(|&(file, size)| self.stale[&file] * 2 >= size)
+            .map(|(file, _)| file)
+            .collect();
+        if collectable.is_empty() {
+            collectable.extend(
+                self.stale
+                    .iter()
+                    .max_by_key(|&(_, &stale)| stale)
+                    .map(|(&file, _)| file),
+            );
+        }
+        Ok(collectable)
+    }
+
+    fn remove(&mut self, file: u64) -> Result<()> {
+        self.readers.remove(&file);
+        self.stale.remove(&file);
+        fs::remove_file(value_log_path(&self.dir, file))?;
+        Ok(())
+    }
+}
+
+// move the value of a large enough set to the value log, returning the record
+// to write in the main log instead and where the value went
+pub(super) fn separate(
+    cmd: OpCmd,
+    value_log: &mut ValueLog,
+    threshold: Option<usize>,
+    keyring: Option<&Keyring>,
+    compression_threshold: Option<usize>,
+) -> Result<(OpCmd, Option<ValuePos>)> {
+    match cmd {
+        OpCmd::Set { ref key, ref value }
+            if threshold.is_some_and(|threshold| value.len() >= threshold) =>
+        {
+            let encoded = encode(&cmd, keyring, compression_threshold)?;
+            let value = value_log.append(encoded.as_ref().unwrap_or(&cmd))?;
+            let pointer = ValuePointer {
+                key: key.clone(),
+                value,
+            };
+            Ok((OpCmd::Pointer(pointer), Some(value)))
+        }
+        cmd => Ok((cmd, None)),
+    }
+}
+
+impl KvStore {
+    /// Move the live values out of the value log files that are mostly stale,
+    /// then delete those files. Runs by itself once the stale bytes of the value
+    /// log pass the threshold set by `set_value_log_gc_threshold`.
+    pub fn collect_value_log(&mut self) -> Result<()> {
+        let files = self.value_log.collectable()?;
+        if files.is_empty() {
+            return Ok(());
+        }
+        if files.contains(&self.value_log.active()) {
+            self.value_log.roll()?;
+        }
+        let mut moved: Vec<(String, CommandPos)> = Vec::new();
+        let mut after: Option<String> = None;
+        loop {
+            let batch = self.index.range(after.as_deref(), COMPACTION_BATCH)?;
+            after = match batch.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+            moved.extend(batch.into_iter().filter(|(_, cmd_pos)| {
+                cmd_pos
+                    .value
+                    .is_some_and(|value| files.contains(&value.file))
+            }));
+        }
+        let mut old_len = 0;
+        for (key, cmd_pos) in moved {
+            let value = self.read_value(cmd_pos)?;
+            old_len += self.write_set(key, value)?;
+        }
+        self.flush_writes()?;
+        for file in files {
+            self.value_log.remove(file)?;
+        }
+        self.add_uncompacted(old_len)
+    }
+
+    pub(super) fn maybe_collect_value_log(&mut self) -> Result<()> {
+        if self.value_log.stale_bytes() > self.value_log_gc_threshold {
+            self.collect_value_log()?;
+        }
+        Ok(())
+    }
+}
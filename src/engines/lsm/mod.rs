@@ -0,0 +1,535 @@
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io;
+use std::iter::{self, Peekable};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{prepare_backup_dir, EngineStats, KvEngine, ENGINE_MARKER};
+
+mod table;
+mod wal;
+use self::table::{table_path, Table, TableWriter};
+use self::wal::Wal;
+
+// bytes of keys and values in the memtable before it is written to a table
+const MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+// level 0 tables that trigger their compaction into level 1
+const L0_TABLES: usize = 4;
+// how much larger each level is than the one above it
+const LEVEL_MULTIPLIER: u64 = 10;
+// the tables of every level, and the write-ahead log in use
+const LEVELS: &str = "levels.json";
+
+// a key with its value, `None` once removed
+type Entry = (String, Option<String>);
+type Entries<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+/// A log-structured merge tree: writes go to a write-ahead log and an in-memory
+/// memtable, which is written to an immutable sorted table once full. Level 0
+/// holds those tables, and is merged into level 1 once it has a few of them;
+/// each deeper level holds tables of distinct keys, ten times more bytes of
+/// them than the level above, merged one table at a time into the next level.
+pub struct LsmEngine {
+    dir: PathBuf,
+    memtable: Memtable,
+    wal: Wal,
+    levels: Vec<Vec<Table>>,   // level 0 newest first, the others by key
+    cursors: Vec<String>,      // last key compacted out of each level
+    next_id: u64,              // of the next table or write-ahead log
+    memtable_size: usize,      // bytes flushed at, and size of the tables
+    compactions: u64,          // compactions run since open
+    compaction_time: Duration, // time spent compacting since open
+}
+
+#[derive(Default)]
+struct Memtable {
+    entries: BTreeMap<String, Option<String>>,
+    bytes: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Levels {
+    next_id: u64,
+    wal: u64, // write-ahead logs before this one are in tables
+    levels: Vec<Vec<u64>>,
+}
+
+impl Memtable {
+    fn insert(&mut self, key: String, value: Option<String>) {
+        let key_len = key.len();
+        let value_len = value.as_ref().map_or(0, String::len);
+        match self.entries.insert(key, value) {
+            Some(old) => self.bytes = self.bytes - old.map_or(0, |old| old.len()) + value_len,
+            None => self.bytes += key_len + value_len,
+        }
+    }
+}
+
+// the newest entry of every key of sorted sources, the first sources newest
+struct Merge<'a> {
+    sources: Vec<Peekable<Entries<'a>>>,
+}
+
+impl<'a> Iterator for Merge<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        let mut next: Option<String> = None;
+        for source in &mut self.sources {
+            match source.peek() {
+                Some(Err(_)) => return source.next(),
+                Some(Ok((key, _))) if next.as_ref().is_none_or(|next| key < next) => {
+                    next = Some(key.clone())
+                }
+                _ => {}
+            }
+        }
+        let key = next?;
+        let mut newest = None;
+        for source in &mut self.sources {
+            if let Some(Ok((found, _))) = source.peek() {
+                if *found == key {
+                    if let Some(Ok(entry)) = source.next() {
+                        newest.get_or_insert(entry);
+                    }
+                }
+            }
+        }
+        newest.map(Ok)
+    }
+}
+
+// the entries of a level of tables of distinct keys after `after`, opening
+// each table only once the previous ones were read
+fn level_entries<'a>(tables: &'a [Table], after: Option<&'a str>) -> Entries<'a> {
+    let start = after.map_or(0, |after| {
+        tables.partition_point(|table| table.last_key() <= after)
+    });
+    Box::new(
+        tables[start..]
+            .iter()
+            .flat_map(move |table| match table.entries(after) {
+                Ok(entries) => entries,
+                Err(e) => Box::new(iter::once(Err(e))),
+            }),
+    )
+}
+
+// the write-ahead log or table number of a file name, when it has `extension`
+fn file_id(name: &str, extension: &str) -> Option<u64> {
+    name.strip_suffix(extension)?
+        .strip_suffix('.')?
+        .parse()
+        .ok()
+}
+
+// delete the write-ahead logs before `wal`, whose writes are all in tables
+fn remove_stale_wals(dir: &Path, wal: u64) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if file_id(&name.to_string_lossy(), "wal").is_some_and(|id| id < wal) {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+impl LsmEngine {
+    /// Open the LSM tree in `dir`, replaying the writes not yet in tables.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<LsmEngine> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let saved = match fs::read(dir.join(LEVELS)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Levels {
+                next_id: 1,
+                wal: 0,
+                levels: Vec::new(),
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let levels = saved
+            .levels
+            .iter()
+            .map(|ids| ids.iter().map(|&id| Table::open(&dir, id)).collect())
+            .collect::<Result<Vec<Vec<Table>>>>()?;
+
+        // drop what a crash left behind, and replay the write-ahead logs in order
+        remove_stale_wals(&dir, saved.wal)?;
+        let live: BTreeSet<u64> = saved.levels.iter().flatten().copied().collect();
+        let mut wals = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(id) = file_id(&name, "wal") {
+                wals.push(id);
+            } else if file_id(&name, "sst").is_some_and(|id| !live.contains(&id))
+                || name.ends_with(".tmp")
+            {
+                fs::remove_file(dir.join(name))?;
+            }
+        }
+        wals.sort_unstable();
+        let mut next_id = saved.next_id;
+        let mut memtable = Memtable::default();
+        let mut wal = None;
+        for id in wals {
+            wal = Some(Wal::replay(&dir, id, &mut memtable)?);
+            next_id = next_id.max(id + 1);
+        }
+        let wal = match wal {
+            Some(wal) => wal,
+            None => {
+                next_id += 1;
+                Wal::create(&dir, next_id - 1)?
+            }
+        };
+        Ok(LsmEngine {
+            cursors: vec![String::new(); levels.len()],
+            dir,
+            memtable,
+            wal,
+            levels,
+            next_id,
+            memtable_size: MEMTABLE_SIZE,
+            compactions: 0,
+            compaction_time: Duration::default(),
+        })
+    }
+
+    /// Set how many bytes of keys and values the memtable holds before they are
+    /// written to a table, which is also the size of the tables compactions write.
+    pub fn set_memtable_size(&mut self, bytes: usize) {
+        self.memtable_size = bytes.max(1);
+    }
+
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn save_levels(&self) -> Result<()> {
+        let levels = Levels {
+            next_id: self.next_id,
+            wal: self.wal.id,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id).collect())
+                .collect(),
+        };
+        let path = self.dir.join(LEVELS);
+        let tmp = path.with_extension("json.tmp");
+        let file = File::create(&tmp)?;
+        serde_json::to_writer(&file, &levels)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn write(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.wal.append(&key, value.as_deref())?;
+        self.memtable.insert(key, value);
+        Ok(())
+    }
+
+    // write the memtable to a level 0 table once full, then compact what has to be
+    fn maybe_flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.bytes >= self.memtable_size {
+            self.flush_memtable()?;
+            self.maybe_compact()?;
+        }
+        Ok(())
+    }
+
+    // write the memtable to a level 0 table, and start a new write-ahead log
+    fn flush_memtable(&mut self) -> Result<()> {
+        if self.memtable.entries.is_empty() {
+            return Ok(());
+        }
+        let id = self.new_id();
+        let mut writer = TableWriter::create(&self.dir, id, self.memtable.entries.len() as u64)?;
+        for (key, value) in &self.memtable.entries {
+            writer.push(key, value.as_deref())?;
+        }
+        let table = writer.finish()?;
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+            self.cursors.push(String::new());
+        }
+        self.levels[0].insert(0, table);
+        let id = self.new_id();
+        self.wal = Wal::create(&self.dir, id)?;
+        self.save_levels()?;
+        remove_stale_wals(&self.dir, self.wal.id)?;
+        self.memtable = Memtable::default();
+        Ok(())
+    }
+
+    // the most bytes of tables level `level` holds before it is compacted
+    fn max_bytes(&self, level: usize) -> u64 {
+        let level_1 = self.memtable_size as u64 * L0_TABLES as u64;
+        level_1 * LEVEL_MULTIPLIER.pow(level as u32 - 1)
+    }
+
+    // merge level 0 into level 1 once it has enough tables, then every level too
+    // large one table at a time into the next one
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.levels[0].len() >= L0_TABLES {
+            let level_0 = &self.levels[0];
+            let first = level_0.iter().map(Table::first_key).min().unwrap_or("");
+            let last = level_0.iter().map(Table::last_key).max().unwrap_or("");
+            let mut ids: Vec<u64> = level_0.iter().map(|table| table.id).collect();
+            if let Some(level_1) = self.levels.get(1) {
+                ids.extend(
+                    level_1
+                        .iter()
+                        .filter(|table| table.overlaps(first, last))
+                        .map(|table| table.id),
+                );
+            }
+            self.merge_tables(ids, 1)?;
+        }
+        let mut level = 1;
+        while level < self.levels.len() {
+            let bytes: u64 = self.levels[level].iter().map(Table::size).sum();
+            if bytes <= self.max_bytes(level) {
+                level += 1;
+                continue;
+            }
+            // the table after the last one compacted out of the level
+            let tables = &self.levels[level];
+            let next =
+                tables.partition_point(|table| table.first_key() <= self.cursors[level].as_str());
+            let table = &tables[if next == tables.len() { 0 } else { next }];
+            self.cursors[level] = table.last_key().to_owned();
+            let (first, last) = (table.first_key().to_owned(), table.last_key().to_owned());
+            let mut ids = vec![table.id];
+            if let Some(below) = self.levels.get(level + 1) {
+                ids.extend(
+                    below
+                        .iter()
+                        .filter(|table| table.overlaps(&first, &last))
+                        .map(|table| table.id),
+                );
+            }
+            self.merge_tables(ids, level + 1)?;
+        }
+        Ok(())
+    }
+
+    // replace the tables `ids`, newest first, with tables of their merged entries
+    // in level `target`, which none of them is above
+    fn merge_tables(&mut self, ids: Vec<u64>, target: usize) -> Result<()> {
+        let started = Instant::now();
+        while self.levels.len() <= target {
+            self.levels.push(Vec::new());
+            self.cursors.push(String::new());
+        }
+        let inputs: Vec<&Table> = ids
+            .iter()
+            .filter_map(|&id| self.levels.iter().flatten().find(|table| table.id == id))
+            .collect();
+        // no older entry of the removed keys is left below the target level
+        let drop_removed = self.levels[target + 1..].iter().all(Vec::is_empty);
+        let (count, bytes) = inputs.iter().fold((0, 0), |(count, bytes), table| {
+            (count + table.len(), bytes + table.size())
+        });
+        // entries per table, to size the bloom filters
+        let per_table = count.min(count * self.memtable_size as u64 / bytes.max(1) + 1);
+
+        let sources = inputs
+            .iter()
+            .map(|table| Ok(table.entries(None)?.peekable()))
+            .collect::<Result<_>>()?;
+        let mut next_id = self.next_id;
+        let mut outputs = Vec::new();
+        let mut writer: Option<TableWriter> = None;
+        for entry in (Merge { sources }) {
+            let (key, value) = entry?;
+            if value.is_none() && drop_removed {
+                continue;
+            }
+            let current = match writer.take() {
+                Some(current) => current,
+                None => {
+                    next_id += 1;
+                    TableWriter::create(&self.dir, next_id - 1, per_table)?
+                }
+            };
+            let current = writer.insert(current);
+            current.push(&key, value.as_deref())?;
+            if current.size() >= self.memtable_size as u64 {
+                outputs.extend(writer.take().map(TableWriter::finish).transpose()?);
+            }
+        }
+        outputs.extend(writer.map(TableWriter::finish).transpose()?);
+        self.next_id = next_id;
+
+        for level in &mut self.levels {
+            level.retain(|table| !ids.contains(&table.id));
+        }
+        self.levels[target].extend(outputs);
+        self.levels[target].sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.save_levels()?;
+        for id in ids {
+            fs::remove_file(table_path(&self.dir, id))?;
+        }
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+
+    // the newest entry of every key after `after`, from the memtable and every table
+    fn entries<'a>(&'a self, after: Option<&'a str>) -> Result<Merge<'a>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let memtable = self
+            .memtable
+            .entries
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let mut sources: Vec<Peekable<Entries>> = vec![(Box::new(memtable) as Entries).peekable()];
+        if let Some((level_0, levels)) = self.levels.split_first() {
+            for table in level_0 {
+                sources.push(table.entries(after)?.peekable());
+            }
+            for level in levels {
+                sources.push(level_entries(level, after).peekable());
+            }
+        }
+        Ok(Merge { sources })
+    }
+
+    // `Some(None)` when `key` was removed
+    fn lookup(&self, key: &str) -> Result<Option<Option<String>>> {
+        if let Some(value) = self.memtable.entries.get(key) {
+            return Ok(Some(value.clone()));
+        }
+        if let Some((level_0, levels)) = self.levels.split_first() {
+            for table in level_0 {
+                if let Some(value) = table.get(key)? {
+                    return Ok(Some(value));
+                }
+            }
+            for level in levels {
+                // the only table of the level that may have the key
+                let i = level.partition_point(|table| table.last_key() < key);
+                if let Some(table) = level.get(i) {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(Some(value));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl KvEngine for LsmEngine {
+    fn name(&self) -> &'static str {
+        "lsm"
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(key, Some(value))?;
+        self.wal.flush()?;
+        self.maybe_flush_memtable()
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.lookup(&key)?.flatten())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.lookup(&key)?.flatten().is_none() {
+            return Err(KvsErr::KeyNotFound);
+        }
+        self.write(key, None)?;
+        self.wal.flush()?;
+        self.maybe_flush_memtable()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.wal.sync()
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        self.entries(after.as_deref())?
+            .filter_map(|entry| match entry {
+                Ok((key, Some(value))) => Some(Ok((key, value))),
+                Ok((_, None)) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .take(limit)
+            .collect()
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.write(key, Some(value))?;
+        }
+        self.wal.flush()?;
+        self.maybe_flush_memtable()
+    }
+
+    /// The tables are immutable and the write-ahead logs only appended to, so
+    /// copying them with the list of tables is a consistent copy.
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        prepare_backup_dir(dir)?;
+        self.wal.flush()?;
+        self.save_levels()?;
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            if file_id(&name.to_string_lossy(), "wal").is_some() {
+                fs::copy(self.dir.join(&name), dir.join(&name))?;
+            }
+        }
+        for table in self.levels.iter().flatten() {
+            fs::copy(table_path(&self.dir, table.id), table_path(dir, table.id))?;
+        }
+        fs::copy(self.dir.join(LEVELS), dir.join(LEVELS))?;
+        fs::write(dir.join(ENGINE_MARKER), "lsm")?;
+        Ok(())
+    }
+
+    /// Write the memtable to a table, then merge every table into the last level,
+    /// dropping removed keys.
+    fn compact(&mut self) -> Result<()> {
+        self.flush_memtable()?;
+        let ids: Vec<u64> = self.levels.iter().flatten().map(|table| table.id).collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let target = (self.levels.len() - 1).max(1);
+        self.merge_tables(ids, target)
+    }
+
+    /// Keys are estimated as the values written less the removals, from the memtable
+    /// and the table footers, so overwritten and removed keys make the estimate drift
+    /// until a full compaction. The tables are reported as log files.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let (mut written, mut removed) = (0, 0);
+        for value in self.memtable.entries.values() {
+            match value {
+                Some(_) => written += 1,
+                None => removed += 1,
+            }
+        }
+        for table in self.levels.iter().flatten() {
+            written += table.len() - table.removed();
+            removed += table.removed();
+        }
+        let tables: u64 = self.levels.iter().flatten().map(Table::size).sum();
+        Ok(EngineStats {
+            keys: written.saturating_sub(removed),
+            log_files: self.levels.iter().flatten().count() as u64,
+            compactions: self.compactions,
+            compaction_seconds: self.compaction_time.as_secs_f64(),
+            disk_bytes: tables + self.wal.size(),
+            ..EngineStats::default()
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use super::Entry;
+use crate::engines::bloom::Bloom;
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// bytes of entries after which a new block starts
+const BLOCK_SIZE: u64 = 4096;
+// width of the footer offset ending every table
+const FOOTER_POS_LEN: u64 = 20;
+
+/// An immutable `<id>.sst` file of entries sorted by key, `None` values marking
+/// removed keys. The entries are split in blocks, found through the block index
+/// and the bloom filter of the footer.
+pub(super) struct Table {
+    pub(super) id: u64,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    footer: Footer,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    count: u64,
+    #[serde(default)]
+    removed: u64, // entries removing their key
+    entries_end: u64,           // where the entries stop and the footer starts
+    blocks: Vec<(String, u64)>, // first key and offset of every block
+    last_key: String,
+    bloom: Bloom,
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+impl Table {
+    pub(super) fn open(dir: &Path, id: u64) -> Result<Table> {
+        let path = table_path(dir, id);
+        let mut file = File::open(&path)?;
+        let footer = read_footer(&mut file)?;
+        Ok(Table {
+            id,
+            path,
+            size: file.metadata()?.len(),
+            file,
+            footer,
+        })
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    // number of entries, removed keys included
+    pub(super) fn len(&self) -> u64 {
+        self.footer.count
+    }
+
+    // number of entries removing their key
+    pub(super) fn removed(&self) -> u64 {
+        self.footer.removed
+    }
+
+    pub(super) fn first_key(&self) -> &str {
+        self.footer.blocks.first().map_or("", |(key, _)| key)
+    }
+
+    pub(super) fn last_key(&self) -> &str {
+        &self.footer.last_key
+    }
+
+    // whether some key of the table is in `first..=last`
+    pub(super) fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key() <= last && self.last_key() >= first
+    }
+
+    // `Some(None)` when the table records `key` as removed
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key > self.last_key() || !self.footer.bloom.contains(key) {
+            return Ok(None);
+        }
+        let blocks = &self.footer.blocks;
+        let i = match blocks.binary_search_by(|(first, _)| first.as_str().cmp(key)) {
+            Ok(i) => i,
+            Err(0) => return Ok(None),
+            Err(i) => i - 1,
+        };
+        let start = blocks[i].1;
+        let end = blocks
+            .get(i + 1)
+            .map_or(self.footer.entries_end, |block| block.1);
+        (&self.file).seek(SeekFrom::Start(start))?;
+        let block = BufReader::new((&self.file).take(end - start));
+        for entry in Deserializer::from_reader(block).into_iter::<Entry>() {
+            let (found, value) = entry?;
+            if found == key {
+                return Ok(Some(value));
+            }
+            if found.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // the entries with keys after `after`, read through a file handle of their own
+    pub(super) fn entries(
+        &self,
+        after: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Result<Entry>>>> {
+        let start = match after {
+            Some(after) => {
+                let blocks = &self.footer.blocks;
+                match blocks.binary_search_by(|(first, _)| first.as_str().cmp(after)) {
+                    Ok(i) => blocks[i].1,
+                    Err(0) => 0,
+                    Err(i) => blocks[i - 1].1,
+                }
+            }
+            None => 0,
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let reader = BufReader::new(file.take(self.footer.entries_end - start));
+        let after = after.map(str::to_owned);
+        Ok(Box::new(
+            Deserializer::from_reader(reader)
+                .into_iter::<Entry>()
+                .map(|entry| entry.map_err(KvsErr::from))
+                .filter(move |entry| match (entry, &after) {
+                    (Ok((key, _)), Some(after)) => key > after,
+                    _ => true,
+                }),
+        ))
+    }
+}
+
+fn read_footer(file: &mut File) -> Result<Footer> {
+    let invalid = || KvsErr::StringErr("invalid table footer".to_owned());
+    let len = file.metadata()?.len();
+    let footer_end = len.checked_sub(FOOTER_POS_LEN).ok_or_else(invalid)?;
+    file.seek(SeekFrom::Start(footer_end))?;
+    let mut footer_pos = String::new();
+    file.read_to_string(&mut footer_pos)?;
+    let footer_pos: u64 = footer_pos.trim().parse().map_err(|_| invalid())?;
+    file.seek(SeekFrom::Start(footer_pos))?;
+    let footer_len = footer_end.checked_sub(footer_pos).ok_or_else(invalid)?;
+    Ok(serde_json::from_reader(BufReader::new(
+        file.take(footer_len),
+    ))?)
+}
+
+/// Writes the entries of a table, in key order, to `<id>.sst.tmp` until finished.
+pub(super) struct TableWriter {
+    dir: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    pos: u64,
+    count: u64,
+    removed: u64,
+    block_start: u64,
+    blocks: Vec<(String, u64)>,
+    last_key: String,
+    bloom: Bloom,
+}
+
+impl TableWriter {
+    // a table for about `expected` entries
+    pub(super) fn create(dir: &Path, id: u64, expected: u64) -> Result<TableWriter> {
+        let file = File::create(table_path(dir, id).with_extension("sst.tmp"))?;
+        Ok(TableWriter {
+            dir: dir.to_owned(),
+            id,
+            writer: BufWriter::new(file),
+            pos: 0,
+            count: 0,
+            removed: 0,
+            block_start: 0,
+            blocks: Vec::new(),
+            last_key: String::new(),
+            bloom: Bloom::new(expected),
+        })
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.pos
+    }
+
+    pub(super) fn push(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.blocks.is_empty() || self.pos - self.block_start >= BLOCK_SIZE {
+            self.blocks.push((key.to_owned(), self.pos));
+            self.block_start = self.pos;
+        }
+        self.count += 1;
+        self.removed += value.is_none() as u64;
+        self.bloom.insert(key);
+        self.last_key = key.to_owned();
+        let entry = serde_json::to_vec(&(key, value))?;
+        self.writer.write_all(&entry)?;
+        self.pos += entry.len() as u64;
+        Ok(())
+    }
+
+    // write the footer, sync and move the table in place
+    pub(super) fn finish(mut self) -> Result<Table> {
+        let footer_pos = self.pos;
+        let footer = Footer {
+            count: self.count,
+            removed: self.removed,
+            entries_end: footer_pos,
+            blocks: self.blocks,
+            last_key: self.last_key,
+            bloom: self.bloom,
+        };
+        serde_json::to_writer(&mut self.writer, &footer)?;
+        write!(
+            self.writer,
+            "{:>width$}",
+            footer_pos,
+            width = FOOTER_POS_LEN as usize
+        )?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        let path = table_path(&self.dir, self.id);
+        fs::rename(path.with_extension("sst.tmp"), &path)?;
+        let file = File::open(&path)?;
+        Ok(Table {
+            id: self.id,
+            size: file.metadata()?.len(),
+            file,
+            path,
+            footer,
+        })
+    }
+}
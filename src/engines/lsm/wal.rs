@@ -0,0 +1,78 @@
+use super::{Entry, Memtable};
+use crate::Result;
+use serde_json::Deserializer;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The write-ahead log `<id>.wal`, every write going there before the memtable.
+/// It is deleted once the memtable was written to a table.
+pub(super) struct Wal {
+    pub(super) id: u64,
+    writer: BufWriter<File>,
+    size: u64,
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+impl Wal {
+    pub(super) fn create(dir: &Path, id: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+            size: 0,
+        })
+    }
+
+    // replay `<id>.wal` into `memtable` and keep appending to it, cutting off a
+    // last record torn by a crash
+    pub(super) fn replay(dir: &Path, id: u64, memtable: &mut Memtable) -> Result<Wal> {
+        let path = wal_path(dir, id);
+        let mut stream =
+            Deserializer::from_reader(BufReader::new(File::open(&path)?)).into_iter::<Entry>();
+        let mut size = 0;
+        while let Some(entry) = stream.next() {
+            match entry {
+                Ok((key, value)) => memtable.insert(key, value),
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+            size = stream.byte_offset() as u64;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        file.set_len(size)?;
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+            size,
+        })
+    }
+
+    pub(super) fn append(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        let entry = serde_json::to_vec(&(key, value))?;
+        self.writer.write_all(&entry)?;
+        self.size += entry.len() as u64;
+        Ok(())
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
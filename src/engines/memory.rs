@@ -0,0 +1,192 @@
+use super::{EngineStats, KvEngine};
+use crate::{KvsErr, Result};
+use crossbeam_skiplist::SkipMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A `KvEngine` keeping its data in a concurrent ordered map, and nowhere else.
+/// Clones share the same map. With a memory budget, the least recently used
+/// keys are evicted once the keys and values take more bytes than the budget.
+#[derive(Clone, Default)]
+pub struct MemoryEngine(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    map: SkipMap<String, Slot>,
+    budget: Option<u64>,
+    lru: Mutex<Lru>, // held by every write, and by reads with a budget
+}
+
+struct Slot {
+    value: String,
+    used: AtomicU64, // tick of the last read or write
+}
+
+#[derive(Default)]
+struct Lru {
+    by_use: BTreeMap<u64, String>, // keys by tick, with a budget only
+    clock: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+fn size(key: &str, slot: &Slot) -> u64 {
+    (key.len() + slot.value.len()) as u64
+}
+
+impl MemoryEngine {
+    /// Creates an empty engine without a memory budget.
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::default()
+    }
+
+    /// Creates an empty engine evicting the least recently used keys once the
+    /// keys and values take more than `bytes`. A single larger value is evicted
+    /// right away.
+    pub fn with_budget(bytes: u64) -> MemoryEngine {
+        MemoryEngine(Arc::new(Shared {
+            budget: Some(bytes),
+            ..Shared::default()
+        }))
+    }
+
+    /// Bytes taken by the keys and values.
+    pub fn bytes(&self) -> u64 {
+        self.lru().bytes
+    }
+
+    fn lru(&self) -> MutexGuard<'_, Lru> {
+        self.0.lru.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // with the write lock held
+    fn insert(&self, lru: &mut Lru, key: String, value: String) {
+        let slot = Slot {
+            value,
+            used: AtomicU64::new(lru.tick()),
+        };
+        lru.bytes += size(&key, &slot);
+        if self.0.budget.is_some() {
+            lru.by_use
+                .insert(slot.used.load(Ordering::Relaxed), key.clone());
+        }
+        if let Some(old) = self.0.map.get(&key) {
+            lru.bytes -= size(&key, old.value());
+            lru.by_use.remove(&old.value().used.load(Ordering::Relaxed));
+        }
+        self.0.map.insert(key, slot);
+    }
+
+    // with the write lock held, drop least recently used keys until within budget
+    fn evict(&self, lru: &mut Lru) {
+        let budget = match self.0.budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        while lru.bytes > budget {
+            let key = match lru.by_use.pop_first() {
+                Some((_, key)) => key,
+                None => break,
+            };
+            if let Some(evicted) = self.0.map.remove(&key) {
+                lru.bytes -= size(&key, evicted.value());
+            }
+        }
+    }
+}
+
+impl KvEngine for MemoryEngine {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut lru = self.lru();
+        self.insert(&mut lru, key, value);
+        self.evict(&mut lru);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let entry = match self.0.map.get(&key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let slot = entry.value();
+        if self.0.budget.is_some() {
+            let mut lru = self.lru();
+            // unless the slot was replaced or evicted meanwhile
+            if lru
+                .by_use
+                .remove(&slot.used.load(Ordering::Relaxed))
+                .is_some()
+            {
+                let tick = lru.tick();
+                slot.used.store(tick, Ordering::Relaxed);
+                lru.by_use.insert(tick, key);
+            }
+        }
+        Ok(Some(slot.value.clone()))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let mut lru = self.lru();
+        let removed = self.0.map.remove(&key).ok_or(KvsErr::KeyNotFound)?;
+        lru.bytes -= size(&key, removed.value());
+        lru.by_use
+            .remove(&removed.value().used.load(Ordering::Relaxed));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(self
+            .0
+            .map
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|entry| (entry.key().clone(), entry.value().value.clone()))
+            .collect())
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut lru = self.lru();
+        for (key, value) in pairs {
+            self.insert(&mut lru, key, value);
+        }
+        self.evict(&mut lru);
+        Ok(())
+    }
+
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        let _ = dir;
+        Err(KvsErr::StringErr(
+            "the memory engine keeps nothing on disk to back up".to_owned(),
+        ))
+    }
+
+    /// Nothing is ever stale, so there is nothing to reclaim.
+    fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.0.map.len() as u64,
+            ..EngineStats::default()
+        })
+    }
+}
@@ -1,5 +1,66 @@
-use crate::Result;
+use crate::{KvsErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the file recording which engine wrote a data directory.
+pub const ENGINE_MARKER: &str = "engine";
+
+/// Where the engine `name` keeps its data inside the kvs-server data directory `dir`.
+pub fn engine_dir(name: &str, dir: &Path) -> PathBuf {
+    match name {
+        "kvs" | "lsm" | "sled" => dir.join(name),
+        _ => dir.to_owned(),
+    }
+}
+
+/// Move the sled data that older versions of kvs-server kept in the root of the
+/// data directory `dir` to its own directory. Returns whether there was any.
+pub fn move_root_sled_data(dir: &Path) -> Result<bool> {
+    let sled_dir = engine_dir("sled", dir);
+    if sled_dir.exists() || !dir.join("conf").is_file() || !dir.join("db").is_file() {
+        return Ok(false);
+    }
+    let names: Vec<_> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<std::io::Result<_>>()?;
+    fs::create_dir(&sled_dir)?;
+    for name in names {
+        let file = name.to_string_lossy();
+        if file == "conf" || file == "db" || file == "blobs" || file.starts_with("snap.") {
+            fs::rename(dir.join(&name), sled_dir.join(&name))?;
+        }
+    }
+    Ok(true)
+}
+
+/// Numbers describing the state of an engine, as exported by the server's metrics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys, estimated by the LSM engine.
+    pub keys: u64,
+    /// Number of log files, or of tables for the LSM engine, 0 for engines without any.
+    pub log_files: u64,
+    /// Bytes of stale records a compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Compactions run since the engine was opened.
+    pub compactions: u64,
+    /// Time spent compacting since the engine was opened, in seconds.
+    pub compaction_seconds: f64,
+    /// Bytes taken on disk by the data, 0 when unknown.
+    pub disk_bytes: u64,
+}
+
 pub trait KvEngine {
+    /**
+     * Return the name of the engine, such as "kvs" or "sled".
+     * Engines not naming themselves are reported as "unknown".
+     */
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
     /**
      * Set the value of a string key to a string.
      * Return an error if the value is not written successfully.
@@ -10,7 +71,6 @@ pub trait KvEngine {
      * Set the value of a string key to a string.
      * Return an error if the value is not written successfully.
      */
-
     fn get(&mut self, key: String) -> Result<Option<String>>;
 
     /**
@@ -18,8 +78,257 @@ pub trait KvEngine {
      * Return an error if the key does not exit or value is not read successfully.
      */
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /**
+     * Flush buffered writes and sync them to disk.
+     * Return an error if the data can not be persisted.
+     */
+    fn flush(&mut self) -> Result<()>;
+
+    /**
+     * Return up to `limit` key/value pairs in key order, starting after the key `after`.
+     * An empty result means there is nothing left to scan.
+     */
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>>;
+
+    /**
+     * Set the values of several keys.
+     * Engines may persist the whole batch at once instead of one key at a time.
+     */
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Write a consistent copy of the data into the empty or missing directory `dir`,
+     * while the engine stays usable.
+     */
+    fn backup_to(&mut self, dir: &Path) -> Result<()>;
+
+    /**
+     * Like `backup_to`, but only write what changed since the backup in `parent`.
+     * Restoring needs the whole chain of backups, starting with a full one.
+     */
+    fn backup_incremental(&mut self, dir: &Path, parent: &Path) -> Result<()> {
+        let _ = (dir, parent);
+        Err(KvsErr::StringErr(
+            "incremental backups are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /**
+     * Reclaim the space taken by stale records.
+     * Return an error if the engine can not be compacted on request.
+     */
+    fn compact(&mut self) -> Result<()> {
+        Err(KvsErr::StringErr(
+            "compaction is not supported by this engine".to_owned(),
+        ))
+    }
+
+    /**
+     * Return statistics about the engine.
+     * By default only the keys are counted, by scanning all of them.
+     */
+    fn stats(&mut self) -> Result<EngineStats> {
+        let mut keys = 0;
+        for pair in Pairs::new(self, 1024) {
+            pair?;
+            keys += 1;
+        }
+        Ok(EngineStats {
+            keys,
+            ..EngineStats::default()
+        })
+    }
+}
+
+impl<E: KvEngine + ?Sized> KvEngine for Box<E> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        (**self).scan(after, limit)
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        (**self).set_batch(pairs)
+    }
+
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        (**self).backup_to(dir)
+    }
+
+    fn backup_incremental(&mut self, dir: &Path, parent: &Path) -> Result<()> {
+        (**self).backup_incremental(dir, parent)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        (**self).stats()
+    }
+}
+
+/// Open the engine called `name` ("kvs", "sled" or "lsm") stored in `dir`.
+pub fn open_engine(name: &str, dir: &Path) -> Result<Box<dyn KvEngine>> {
+    open_engine_with_keyring(name, dir, None)
+}
+
+/// Like `open_engine`, for a kvs store encrypted with `keyring`. Only the kvs
+/// engine encrypts its records, so a keyring is refused for the others.
+pub fn open_engine_with_keyring(
+    name: &str,
+    dir: &Path,
+    keyring: Option<&Keyring>,
+) -> Result<Box<dyn KvEngine>> {
+    match (name, keyring) {
+        ("kvs", Some(keyring)) => Ok(Box::new(KvStore::open_encrypted(dir, keyring.clone())?)),
+        (_, Some(_)) => Err(KvsErr::StringErr(format!(
+            "the {} engine does not encrypt its data",
+            name
+        ))),
+        ("kvs", None) => Ok(Box::new(KvStore::open(dir)?)),
+        ("lsm", None) => Ok(Box::new(LsmEngine::open(dir)?)),
+        ("sled", None) => Ok(Box::new(SledKvsEngine::new(::sled::open(dir)?))),
+        (other, None) => Err(KvsErr::StringErr(format!("unknown engine: {}", other))),
+    }
+}
+
+// make the keys of `engine` after `after` and up to the last of the sorted `pairs`,
+// or up to the end with `to_end`, exactly `pairs`; a snapshot of another node is
+// loaded a batch at a time this way
+pub(crate) fn replace_range<E: KvEngine + ?Sized>(
+    engine: &mut E,
+    after: Option<&str>,
+    pairs: Vec<(String, String)>,
+    to_end: bool,
+) -> Result<()> {
+    const BATCH: usize = 1024;
+    let end = match pairs.last() {
+        _ if to_end => None,
+        Some((key, _)) => Some(key.clone()),
+        None => return Ok(()),
+    };
+    let keys: BTreeSet<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    let mut stale = Vec::new();
+    let mut cursor = after.map(str::to_owned);
+    'scan: loop {
+        let batch = engine.scan(cursor.take(), BATCH)?;
+        cursor = batch.last().map(|(key, _)| key.clone());
+        if cursor.is_none() {
+            break;
+        }
+        for (key, _) in batch {
+            if end.as_ref().is_some_and(|end| key > *end) {
+                break 'scan;
+            }
+            if !keys.contains(key.as_str()) {
+                stale.push(key);
+            }
+        }
+    }
+    for key in stale {
+        engine.remove(key)?;
+    }
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    engine.set_batch(pairs)
+}
+
+// make sure a backup is written to an empty directory
+fn prepare_backup_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsErr::StringErr(format!(
+            "backup directory {} is not empty",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Iterator over every key/value pair of an engine, fetched `batch` pairs at a time.
+pub struct Pairs<'a, E: KvEngine + ?Sized> {
+    engine: &'a mut E,
+    batch: usize,
+    buffer: std::vec::IntoIter<(String, String)>,
+    last_key: Option<String>,
+    done: bool,
+}
+
+impl<'a, E: KvEngine + ?Sized> Pairs<'a, E> {
+    pub fn new(engine: &'a mut E, batch: usize) -> Self {
+        Pairs {
+            engine,
+            batch: batch.max(1),
+            buffer: Vec::new().into_iter(),
+            last_key: None,
+            done: false,
+        }
+    }
+}
+
+impl<'a, E: KvEngine + ?Sized> Iterator for Pairs<'a, E> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(pair) = self.buffer.next() {
+            self.last_key = Some(pair.0.clone());
+            return Some(Ok(pair));
+        }
+        if self.done {
+            return None;
+        }
+        match self.engine.scan(self.last_key.clone(), self.batch) {
+            Ok(pairs) => {
+                // a remote engine may return fewer pairs than asked before the end
+                self.done = pairs.is_empty();
+                self.buffer = pairs.into_iter();
+                let pair = self.buffer.next()?;
+                self.last_key = Some(pair.0.clone());
+                Some(Ok(pair))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
+mod bloom;
 mod kvs;
+mod lsm;
+mod memory;
 mod sled;
-pub use self::kvs::KvStore;
+pub(crate) use self::kvs::assemble;
+pub use self::kvs::{
+    dump, dump_with_keyring, fsck, fsck_with_keyring, BackupManifest, Corruption, FsckReport,
+    GenerationReport, Keyring, KvStore, KvStoreOptions, LogOp, LogRecord, Segment, MANIFEST,
+};
+pub use self::lsm::LsmEngine;
+pub use self::memory::MemoryEngine;
 pub use self::sled::SledKvsEngine;
@@ -1,7 +1,10 @@
 
-use super::KvEngine;
+use super::{prepare_backup_dir, EngineStats, KvEngine, ENGINE_MARKER};
 use crate::{KvsErr, Result};
-use sled::{Db, Tree};
+use sled::{Batch, Db, Tree};
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
@@ -15,6 +18,10 @@ impl SledKvsEngine {
 }
 
 impl KvEngine for SledKvsEngine {
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
     fn set(&mut self, key: String, value: String) -> Result<()> {
         let tree: &Tree = &self.0;
         tree.insert(key, value.into_bytes()).map(|_| ())?;
@@ -37,4 +44,52 @@ impl KvEngine for SledKvsEngine {
         tree.flush()?;
         Ok(())
     }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        let start = after.map_or(Bound::Unbounded, |key| Bound::Excluded(key.into_bytes()));
+        tree.range::<Vec<u8>, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(key.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        self.0.apply_batch(batch)?;
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        prepare_backup_dir(dir)?;
+        self.0.flush()?;
+        let backup = sled::open(dir)?;
+        backup.import(self.0.export());
+        backup.flush()?;
+        fs::write(dir.join(ENGINE_MARKER), "sled")?;
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            keys: self.0.len() as u64,
+            disk_bytes: self.0.size_on_disk()?,
+            ..EngineStats::default()
+        })
+    }
 }
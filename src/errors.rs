@@ -1,30 +1,128 @@
-use failure::Fail;
-use std::{io, string::FromUtf8Error};
-#[derive(Fail, Debug)]
+use std::{error, fmt, io, string::FromUtf8Error};
+#[derive(Debug)]
 pub enum KvsErr {
-    #[fail(display = "Key not found")]
     KeyNotFound,
-    #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// The server gave up on a request because its deadline passed.
+    DeadlineExceeded,
+    /// The server refused the connection because it is serving too many clients.
+    TooManyConnections,
+    /// The server is a replica and only serves reads; writes go to the leader given.
+    ReadOnly(String),
+    /// The Raft node is not the leader; requests go to the leader given.
+    NotLeader(String),
+    /// The server requires the connection to authenticate first.
+    Unauthenticated,
+    /// The credentials presented were rejected.
+    AuthFailed,
+    /// The ACL does not let the user send the request.
+    PermissionDenied(String),
+    /// Encrypted records can not be read with the keys given, or without keys.
+    WrongKey,
     /// IO error.
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    Io(io::Error),
 
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    Serde(serde_json::Error),
     /// Key or value is invalid UTF-8 sequence
-    #[fail(display = "UTF-8 error: {}", _0)]
-    Utf8(#[cause] FromUtf8Error),
+    Utf8(FromUtf8Error),
     /// Sled error
-    #[fail(display = "sled error: {}", _0)]
-    Sled(#[cause] sled::Error),
+    Sled(sled::Error),
+    /// CSV error
+    Csv(csv::Error),
+    /// TLS error
+    Tls(rustls::Error),
 
-    #[fail(display = "{}", _0)]
     StringErr(String),
 }
 
+impl fmt::Display for KvsErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsErr::KeyNotFound => write!(f, "Key not found"),
+            KvsErr::UnexpectedCommandType => write!(f, "Unexpected command type"),
+            KvsErr::DeadlineExceeded => write!(f, "Deadline exceeded"),
+            KvsErr::TooManyConnections => write!(f, "Too many connections"),
+            KvsErr::ReadOnly(leader) => write!(f, "Read-only replica, writes go to {}", leader),
+            KvsErr::NotLeader(leader) => write!(f, "Not the leader, the leader is {}", leader),
+            KvsErr::Unauthenticated => write!(f, "Authentication required"),
+            KvsErr::AuthFailed => write!(f, "Authentication failed"),
+            KvsErr::PermissionDenied(reason) => write!(f, "Permission denied: {}", reason),
+            KvsErr::WrongKey => write!(f, "Wrong or missing encryption key"),
+            KvsErr::Io(err) => write!(f, "{}", err),
+            KvsErr::Serde(err) => write!(f, "{}", err),
+            KvsErr::Utf8(err) => write!(f, "UTF-8 error: {}", err),
+            KvsErr::Sled(err) => write!(f, "sled error: {}", err),
+            KvsErr::Csv(err) => write!(f, "csv error: {}", err),
+            KvsErr::Tls(err) => write!(f, "TLS error: {}", err),
+            KvsErr::StringErr(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl error::Error for KvsErr {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KvsErr::Io(err) => Some(err),
+            KvsErr::Serde(err) => Some(err),
+            KvsErr::Utf8(err) => Some(err),
+            KvsErr::Sled(err) => Some(err),
+            KvsErr::Csv(err) => Some(err),
+            KvsErr::Tls(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, KvsErr>;
 
+impl KvsErr {
+    /// Short name of the kind of error, used to label metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KvsErr::KeyNotFound => "key_not_found",
+            KvsErr::UnexpectedCommandType => "unexpected_command_type",
+            KvsErr::DeadlineExceeded => "deadline_exceeded",
+            KvsErr::TooManyConnections => "too_many_connections",
+            KvsErr::ReadOnly(_) => "read_only",
+            KvsErr::NotLeader(_) => "not_leader",
+            KvsErr::Unauthenticated => "unauthenticated",
+            KvsErr::AuthFailed => "auth_failed",
+            KvsErr::PermissionDenied(_) => "permission_denied",
+            KvsErr::WrongKey => "wrong_key",
+            KvsErr::Io(_) => "io",
+            KvsErr::Serde(_) => "serde",
+            KvsErr::Utf8(_) => "utf8",
+            KvsErr::Sled(_) => "sled",
+            KvsErr::Csv(_) => "csv",
+            KvsErr::Tls(_) => "tls",
+            KvsErr::StringErr(_) => "other",
+        }
+    }
+
+    /// Rebuild an error from the message sent back by a server.
+    pub(crate) fn from_remote(msg: String) -> KvsErr {
+        if let Some(leader) = msg.strip_prefix("Read-only replica, writes go to ") {
+            return KvsErr::ReadOnly(leader.to_owned());
+        }
+        if let Some(leader) = msg.strip_prefix("Not the leader, the leader is ") {
+            return KvsErr::NotLeader(leader.to_owned());
+        }
+        if let Some(reason) = msg.strip_prefix("Permission denied: ") {
+            return KvsErr::PermissionDenied(reason.to_owned());
+        }
+        vec![
+            KvsErr::KeyNotFound,
+            KvsErr::DeadlineExceeded,
+            KvsErr::TooManyConnections,
+            KvsErr::Unauthenticated,
+            KvsErr::AuthFailed,
+        ]
+        .into_iter()
+        .find(|err| err.to_string() == msg)
+        .unwrap_or(KvsErr::StringErr(msg))
+    }
+}
+
 impl From<std::io::Error> for KvsErr {
     fn from(err: std::io::Error) -> Self {
         KvsErr::Io(err)
@@ -40,6 +138,16 @@ impl From<sled::Error> for KvsErr {
         KvsErr::Sled(err)
     }
 }
+impl From<csv::Error> for KvsErr {
+    fn from(err: csv::Error) -> Self {
+        KvsErr::Csv(err)
+    }
+}
+impl From<rustls::Error> for KvsErr {
+    fn from(err: rustls::Error) -> Self {
+        KvsErr::Tls(err)
+    }
+}
 impl From<FromUtf8Error> for KvsErr {
     fn from(err: FromUtf8Error) -> Self {
         KvsErr::Utf8(err)
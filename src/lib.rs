@@ -1,3 +1,8 @@
+pub mod auth;
+pub mod backup;
+pub mod bulk;
+pub mod raft;
+pub mod tls;
 mod common;
 pub mod engines;
 mod errors;
@@ -5,6 +10,10 @@ pub use crate::engines::KvEngine;
 pub use crate::engines::KvStore;
 pub use errors::{KvsErr, Result};
 mod server;
-pub use server::KvServer;
+pub use server::{KvServer, ServerConfig, ServerInfo, ShutdownHandle};
 pub use client::KvClient;
 mod client;
+mod shell;
+pub use shell::Shell;
+mod sharded;
+pub use sharded::{HashRing, ShardedKvClient, DEFAULT_VNODES};
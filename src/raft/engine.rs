@@ -0,0 +1,601 @@
+use super::node::{Command, Entry, HardState, Message, NodeId, RaftMessage, RaftNode, Snapshot};
+use crate::auth::constant_time_eq;
+use crate::engines::{replace_range, EngineStats, KvEngine, LogOp};
+use crate::{KvsErr, Result};
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// one tick of the Raft clock
+const TICK: Duration = Duration::from_millis(50);
+// how long a request may wait to be committed and applied
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const IO_TIMEOUT: Duration = Duration::from_secs(1);
+// applied entries kept in the log before it is replaced by a snapshot
+const COMPACT_ENTRIES: u64 = 1000;
+// pairs sent in one snapshot chunk
+const SNAPSHOT_BATCH: usize = 1024;
+// messages waiting for a peer; more are dropped until it catches up
+const OUTBOX_MESSAGES: usize = 64;
+
+const HARD_STATE_FILE: &str = "hard_state.json";
+const LOG_FILE: &str = "log.json";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// A member of a Raft cluster, as `ID,CLIENT_ADDR,RAFT_ADDR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub id: NodeId,
+    /// Where the member's `kvs-server` answers clients.
+    pub client_addr: String,
+    /// Where the member talks to the other members.
+    pub raft_addr: String,
+}
+
+impl FromStr for Peer {
+    type Err = KvsErr;
+
+    fn from_str(s: &str) -> Result<Peer> {
+        let invalid = || {
+            KvsErr::StringErr(format!(
+                "invalid peer, expected ID,CLIENT_ADDR,RAFT_ADDR: {}",
+                s
+            ))
+        };
+        let parts: Vec<&str> = s.split(',').collect();
+        match parts[..] {
+            [id, client_addr, raft_addr] => Ok(Peer {
+                id: id.parse().map_err(|_| invalid())?,
+                client_addr: client_addr.to_owned(),
+                raft_addr: raft_addr.to_owned(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How a `RaftEngine` finds the rest of its cluster.
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub id: NodeId,
+    /// Every member of the cluster, this node included.
+    pub peers: Vec<Peer>,
+    /// Where the Raft log, vote and snapshot are kept.
+    pub state_dir: PathBuf,
+    /// Shared by the members, which present it when connecting to each other.
+    /// Without it anyone reaching the Raft port can rewrite the data, so the port
+    /// must be firewalled from everything but the other members.
+    pub token: Option<String>,
+}
+
+// the first message on a connection between members
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    token: Option<String>,
+}
+
+/// A `KvEngine` replicated with Raft: requests are committed to a majority of the
+/// cluster, then applied to the local engine of every member in the same order.
+/// Only the leader accepts requests, the others fail with `KvsErr::NotLeader`.
+pub struct RaftEngine<E: KvEngine> {
+    name: &'static str,
+    shared: Arc<Shared<E>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Shared<E> {
+    state: Mutex<State<E>>,
+    config: RaftConfig,
+    stop: AtomicBool,
+}
+
+struct State<E> {
+    node: RaftNode,
+    log: LogFile,
+    engine: E,
+    // requests waiting for the entry at an index to be applied, with the term it was proposed in
+    waiters: BTreeMap<u64, (u64, mpsc::Sender<Result<Output>>)>,
+    outboxes: BTreeMap<NodeId, mpsc::SyncSender<RaftMessage>>,
+}
+
+// result of applying a command
+enum Output {
+    Done,
+    Value(Option<String>),
+    Pairs(Vec<(String, String)>),
+}
+
+impl<E: KvEngine + Send + 'static> RaftEngine<E> {
+    /// Join the cluster described by `config`, applying committed requests to `engine`.
+    pub fn start(engine: E, config: RaftConfig) -> Result<RaftEngine<E>> {
+        let me = config
+            .peers
+            .iter()
+            .find(|peer| peer.id == config.id)
+            .ok_or_else(|| {
+                KvsErr::StringErr(format!("node {} is not in the peer list", config.id))
+            })?;
+        fs::create_dir_all(&config.state_dir)?;
+        let hard_state: HardState =
+            load(&config.state_dir.join(HARD_STATE_FILE))?.unwrap_or_default();
+        let snapshot: Snapshot = load(&config.state_dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+        let (log, entries) = LogFile::open(config.state_dir.join(LOG_FILE))?;
+        let listener = TcpListener::bind(&me.raft_addr)?;
+        listener.set_nonblocking(true)?;
+        info!(
+            "Raft node {} at term {}, snapshot at {}, {} log entries",
+            config.id,
+            hard_state.term,
+            snapshot.index,
+            entries.len()
+        );
+
+        let ids = config.peers.iter().map(|peer| peer.id).collect();
+        let node = RaftNode::restart(
+            config.id,
+            ids,
+            hard_state,
+            entries,
+            snapshot,
+            seed(config.id),
+        );
+        let mut outboxes = BTreeMap::new();
+        let mut senders = Vec::new();
+        for peer in config.peers.iter().filter(|peer| peer.id != config.id) {
+            let (tx, rx) = mpsc::sync_channel(OUTBOX_MESSAGES);
+            outboxes.insert(peer.id, tx);
+            senders.push((peer.raft_addr.clone(), rx));
+        }
+        let name = engine.name();
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                node,
+                log,
+                engine,
+                waiters: BTreeMap::new(),
+                outboxes,
+            }),
+            config,
+            stop: AtomicBool::new(false),
+        });
+
+        let mut threads = Vec::new();
+        for (addr, rx) in senders {
+            let shared = Arc::clone(&shared);
+            threads.push(thread::spawn(move || shared.send_loop(&addr, rx)));
+        }
+        let ticker = Arc::clone(&shared);
+        threads.push(thread::spawn(move || ticker.tick_loop()));
+        let receiver = Arc::clone(&shared);
+        threads.push(thread::spawn(move || receiver.accept_loop(listener)));
+        Ok(RaftEngine {
+            name,
+            shared,
+            threads,
+        })
+    }
+
+    // propose `command` and wait until it is applied
+    fn call(&mut self, command: Command) -> Result<Output> {
+        let rx = {
+            let mut state = self.shared.lock()?;
+            let (index, term) = match state.node.propose(command) {
+                Ok(proposed) => proposed,
+                Err(leader) => return Err(self.shared.not_leader(leader)),
+            };
+            let (tx, rx) = mpsc::channel();
+            state.waiters.insert(index, (term, tx));
+            state.process(&self.shared.config)?;
+            rx
+        };
+        rx.recv_timeout(REQUEST_TIMEOUT).unwrap_or_else(|_| {
+            Err(KvsErr::StringErr(
+                "request was not committed in time".to_owned(),
+            ))
+        })
+    }
+}
+
+impl<E: KvEngine + Send + 'static> Shared<E> {
+    fn lock(&self) -> Result<MutexGuard<'_, State<E>>> {
+        self.state
+            .lock()
+            .map_err(|_| KvsErr::StringErr("raft state lock poisoned".to_owned()))
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    fn not_leader(&self, leader: Option<NodeId>) -> KvsErr {
+        match self
+            .config
+            .peers
+            .iter()
+            .find(|peer| Some(peer.id) == leader)
+        {
+            Some(peer) => KvsErr::NotLeader(peer.client_addr.clone()),
+            None => KvsErr::StringErr("No leader elected".to_owned()),
+        }
+    }
+
+    fn tick_loop(&self) {
+        while !self.stopped() {
+            thread::sleep(TICK);
+            let result = self.lock().and_then(|mut state| {
+                state.node.tick();
+                state.process(&self.config)
+            });
+            if let Err(e) = result {
+                error!("Raft tick failed: {}", e);
+            }
+        }
+    }
+
+    fn accept_loop(self: Arc<Self>, listener: TcpListener) {
+        while !self.stopped() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let shared = Arc::clone(&self);
+                    thread::spawn(move || {
+                        if let Err(e) = shared.receive(stream) {
+                            debug!("Raft connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(TICK),
+                Err(e) => warn!("Raft connection failed: {}", e),
+            }
+        }
+    }
+
+    // step every message sent by a peer on `stream`, once it presented the token
+    fn receive(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let hello = Hello::deserialize(&mut Deserializer::from_reader(&mut reader))?;
+        if let Some(token) = &self.config.token {
+            let presented = hello.token.unwrap_or_default();
+            if !constant_time_eq(token.as_bytes(), presented.as_bytes()) {
+                warn!(
+                    "Raft connection from {} refused: wrong token",
+                    stream.peer_addr()?
+                );
+                return Err(KvsErr::AuthFailed);
+            }
+        }
+        while !self.stopped() {
+            // wait for the next message, waking up to check for shutdown
+            stream.set_read_timeout(Some(TICK))?;
+            match reader.fill_buf() {
+                Ok([]) => break,
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            }
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+            let msg = RaftMessage::deserialize(&mut Deserializer::from_reader(&mut reader))?;
+            let mut state = self.lock()?;
+            state.node.step(msg);
+            state.process(&self.config)?;
+        }
+        Ok(())
+    }
+
+    fn introduce(&self, addr: &str) -> Result<BufWriter<TcpStream>> {
+        let mut writer = BufWriter::new(connect(addr)?);
+        let token = self.config.token.clone();
+        serde_json::to_writer(&mut writer, &Hello { token })?;
+        Ok(writer)
+    }
+
+    // deliver messages to one peer; Raft copes with the ones lost while it is down
+    fn send_loop(&self, addr: &str, rx: mpsc::Receiver<RaftMessage>) {
+        let mut stream: Option<BufWriter<TcpStream>> = None;
+        while !self.stopped() {
+            let msg = match rx.recv_timeout(TICK) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if stream.is_none() {
+                stream = self.introduce(addr).ok();
+                if stream.is_none() {
+                    // the peer is down: the messages queued meanwhile are stale
+                    // by the time it is back, and Raft sends what it still needs
+                    while rx.try_recv().is_ok() {}
+                    continue;
+                }
+            }
+            if let Some(writer) = stream.as_mut() {
+                if serde_json::to_writer(&mut *writer, &msg).is_err() || writer.flush().is_err() {
+                    stream = None;
+                }
+            }
+        }
+    }
+}
+
+impl<E: KvEngine> State<E> {
+    // persist, apply and send whatever the node produced
+    fn process(&mut self, config: &RaftConfig) -> Result<()> {
+        let ready = self.node.ready();
+        if let Some(hard_state) = &ready.hard_state {
+            save(&config.state_dir.join(HARD_STATE_FILE), hard_state)?;
+        }
+        // entries conflicting with an installed snapshot are dropped before it is saved
+        if let Some(from) = ready.log_from {
+            self.log.write(from, &ready.entries)?;
+        }
+        for chunk in ready.snapshot_chunks {
+            replace_range(
+                &mut self.engine,
+                chunk.after.as_deref(),
+                chunk.pairs,
+                chunk.done,
+            )?;
+        }
+        if let Some(snapshot) = ready.snapshot {
+            info!("Installed snapshot at {}", snapshot.index);
+            // the data must be on disk before the snapshot replaces the log
+            self.engine.flush()?;
+            save(&config.state_dir.join(SNAPSHOT_FILE), &snapshot)?;
+            let rest = self.waiters.split_off(&(snapshot.index + 1));
+            for (_, (_, tx)) in std::mem::replace(&mut self.waiters, rest) {
+                let _ = tx.send(Err(superseded()));
+            }
+        }
+        for entry in ready.committed {
+            let output = apply(&mut self.engine, entry.command);
+            if let Some((term, tx)) = self.waiters.remove(&entry.index) {
+                let _ = tx.send(if term == entry.term {
+                    output
+                } else {
+                    Err(superseded())
+                });
+            }
+        }
+        for mut msg in ready.messages {
+            // the leader asks for a chunk of its snapshot, read from the engine now
+            if let Message::InstallSnapshot { chunk, .. } = &mut msg.body {
+                chunk.pairs = self.engine.scan(chunk.after.clone(), SNAPSHOT_BATCH)?;
+                chunk.done = chunk.pairs.len() < SNAPSHOT_BATCH;
+            }
+            if let Some(outbox) = self.outboxes.get(&msg.to) {
+                if let Err(TrySendError::Full(msg)) = outbox.try_send(msg) {
+                    debug!("Dropped a message to node {}, its outbox is full", msg.to);
+                }
+            }
+        }
+
+        let applied = self.node.commit_index();
+        if applied - self.node.snapshot().index > COMPACT_ENTRIES {
+            // the engine holds the entries dropped from the log from now on
+            self.engine.flush()?;
+            self.node.compact(applied);
+            // a crash in between leaves a log overlapping the snapshot, which is trimmed on restart
+            save(&config.state_dir.join(SNAPSHOT_FILE), self.node.snapshot())?;
+            self.log.rewrite(self.node.entries())?;
+        }
+        Ok(())
+    }
+}
+
+// the log entries after the snapshot, stored one after another: new entries are
+// appended, and the ones the leader replaces are cut off
+struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+    // index and offset of every entry in the file
+    offsets: Vec<(u64, u64)>,
+    len: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> Result<(LogFile, Vec<Entry>)> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let len = {
+            let mut stream = Deserializer::from_reader(BufReader::new(&file)).into_iter::<Entry>();
+            loop {
+                let offset = stream.byte_offset() as u64;
+                match stream.next() {
+                    Some(Ok(entry)) => {
+                        offsets.push((entry.index, offset));
+                        entries.push(entry);
+                    }
+                    // the end of the file, or an entry cut short by a crash
+                    None => break offset,
+                    Some(Err(e)) if e.is_eof() => break offset,
+                    Some(Err(e)) => return Err(e.into()),
+                }
+            }
+        };
+        file.set_len(len)?;
+        let log = LogFile {
+            path,
+            file,
+            offsets,
+            len,
+        };
+        Ok((log, entries))
+    }
+
+    // replace the entries from index `from` on with `entries`
+    fn write(&mut self, from: u64, entries: &[Entry]) -> Result<()> {
+        if let Some(pos) = self.offsets.iter().position(|&(index, _)| index >= from) {
+            self.len = self.offsets[pos].1;
+            self.offsets.truncate(pos);
+            self.file.set_len(self.len)?;
+        }
+        let mut buf = Vec::new();
+        for entry in entries {
+            self.offsets
+                .push((entry.index, self.len + buf.len() as u64));
+            serde_json::to_writer(&mut buf, entry)?;
+        }
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    // start over with only `entries`, written aside and renamed
+    fn rewrite(&mut self, entries: &[Entry]) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)?;
+        *self = LogFile::open(self.path.clone())?.0;
+        Ok(())
+    }
+}
+
+fn apply<E: KvEngine>(engine: &mut E, command: Command) -> Result<Output> {
+    match command {
+        Command::Noop => Ok(Output::Done),
+        Command::Op(LogOp::Set { key, value }) => engine.set(key, value).map(|()| Output::Done),
+        Command::Op(LogOp::Remove { key }) => engine.remove(key).map(|()| Output::Done),
+        Command::Get { key } => engine.get(key).map(Output::Value),
+        Command::Scan { after, limit } => engine.scan(after, limit).map(Output::Pairs),
+    }
+}
+
+impl<E: KvEngine + Send + 'static> KvEngine for RaftEngine<E> {
+    /// Name of the engine holding the local copy of the data.
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.call(Command::Op(LogOp::Set { key, value }))? {
+            Output::Done => Ok(()),
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.call(Command::Get { key })? {
+            Output::Value(value) => Ok(value),
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.call(Command::Op(LogOp::Remove { key }))? {
+            Output::Done => Ok(()),
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.shared.lock()?.engine.flush()
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        match self.call(Command::Scan { after, limit })? {
+            Output::Pairs(pairs) => Ok(pairs),
+            _ => Err(KvsErr::UnexpectedCommandType),
+        }
+    }
+
+    /// Backs up the local copy of the data.
+    fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        self.shared.lock()?.engine.backup_to(dir)
+    }
+
+    fn backup_incremental(&mut self, dir: &Path, parent: &Path) -> Result<()> {
+        self.shared.lock()?.engine.backup_incremental(dir, parent)
+    }
+
+    /// Compacts the local copy of the data.
+    fn compact(&mut self) -> Result<()> {
+        self.shared.lock()?.engine.compact()
+    }
+
+    /// Statistics of the local copy of the data.
+    fn stats(&mut self) -> Result<EngineStats> {
+        self.shared.lock()?.engine.stats()
+    }
+}
+
+impl<E: KvEngine> Drop for RaftEngine<E> {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let addr: SocketAddr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| KvsErr::StringErr(format!("invalid address: {}", addr)))?;
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn superseded() -> KvsErr {
+    KvsErr::StringErr("leadership changed before the request was committed".to_owned())
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// written aside and renamed, so a crash leaves either the old or the new state
+fn save<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// election timeouts differ between nodes and runs
+fn seed(id: NodeId) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos ^ id
+}
@@ -0,0 +1,8 @@
+mod engine;
+mod node;
+
+pub use self::engine::{Peer, RaftConfig, RaftEngine};
+pub use self::node::{
+    Command, Entry, HardState, Message, NodeId, RaftMessage, RaftNode, Ready, Role, Snapshot,
+    SnapshotChunk,
+};
@@ -0,0 +1,767 @@
+use crate::engines::LogOp;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Identifies a member of a Raft cluster.
+pub type NodeId = u64;
+
+// ticks between heartbeats of a leader
+const HEARTBEAT_TICKS: u32 = 2;
+// followers start an election after this many ticks, plus up to as many again at random
+const ELECTION_TICKS: u32 = 10;
+// entries sent in one append message
+const MAX_APPEND: usize = 64;
+
+/// What a log entry asks the state machine to do.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    /// Appended by every new leader to commit the entries of earlier terms.
+    Noop,
+    Op(LogOp),
+    /// Reads go through the log too, which makes them linearizable.
+    Get {
+        key: String,
+    },
+    Scan {
+        after: Option<String>,
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+/// Where the log was cut: the entries up to `index` are only kept in the state machine.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+}
+
+/// One part of the state machine sent with a snapshot: `pairs` replace the data
+/// after the key `after`, up to their last key or, once `done`, to the end.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub after: Option<String>,
+    pub pairs: Vec<(String, String)>,
+    pub done: bool,
+}
+
+/// The vote of a node, which must survive a crash next to its log and snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RaftMessage {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub term: u64,
+    pub body: Message,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    RequestVote {
+        last_index: u64,
+        last_term: u64,
+    },
+    Vote {
+        granted: bool,
+    },
+    AppendEntries {
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// Answers both `AppendEntries` and `InstallSnapshot`. On failure `match_index`
+    /// is a hint of where the logs may agree.
+    AppendResponse {
+        success: bool,
+        match_index: u64,
+    },
+    /// The leader only asks for the chunk after `chunk.after`; its pairs are read
+    /// from the state machine when the message is sent. The state machine keeps
+    /// changing meanwhile, which the entries after the snapshot make up for.
+    InstallSnapshot {
+        snapshot: Snapshot,
+        chunk: SnapshotChunk,
+    },
+    /// Answers a chunk that did not finish the snapshot, asking for the one after `after`.
+    SnapshotAck {
+        index: u64,
+        after: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Work produced by a node since the last call to `RaftNode::ready`.
+#[derive(Debug, Default)]
+pub struct Ready {
+    /// Set when the hard state changed; it must be persisted before `messages` are sent.
+    pub hard_state: Option<HardState>,
+    /// Set when the log changed from this index on: the stored entries at and after it
+    /// are replaced by `entries`, before `messages` are sent.
+    pub log_from: Option<u64>,
+    pub entries: Vec<Entry>,
+    /// Chunks of a snapshot received from the leader, to load into the state machine
+    /// in order.
+    pub snapshot_chunks: Vec<SnapshotChunk>,
+    /// Set when the last chunk of a snapshot arrived; it must be persisted before
+    /// `committed` is applied.
+    pub snapshot: Option<Snapshot>,
+    pub committed: Vec<Entry>,
+    pub messages: Vec<RaftMessage>,
+}
+
+/// One member of a Raft cluster, without any I/O: time advances through `tick`,
+/// messages come in through `step`, and everything to do is collected by `ready`.
+/// Given the same seed and inputs a node always behaves the same way.
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    role: Role,
+    leader: Option<NodeId>,
+    term: u64,
+    voted_for: Option<NodeId>,
+    entries: Vec<Entry>,
+    snapshot: Snapshot,
+    commit: u64,
+    applied: u64,
+    votes: BTreeSet<NodeId>,
+    next_index: BTreeMap<NodeId, u64>,
+    match_index: BTreeMap<NodeId, u64>,
+    elapsed: u32,
+    election_timeout: u32,
+    rng: u64,
+    dirty: bool,
+    // lowest index of the log changed since the last `ready`
+    unstable: Option<u64>,
+    installed: Option<Snapshot>,
+    // the snapshot being received and the last key received so far
+    receiving: Option<(Snapshot, Option<String>)>,
+    chunks: Vec<SnapshotChunk>,
+    // the snapshot being sent to each peer that fell behind it, one at a time
+    transfers: BTreeMap<NodeId, Transfer>,
+    outbox: Vec<RaftMessage>,
+}
+
+struct Transfer {
+    snapshot: Snapshot,
+    // the last key the peer received
+    after: Option<String>,
+    // whether the peer acknowledged a chunk since the last heartbeat
+    progressed: bool,
+}
+
+impl RaftNode {
+    /// Create node `id` of a new cluster made of itself and `peers`.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, seed: u64) -> RaftNode {
+        RaftNode::restart(
+            id,
+            peers,
+            HardState::default(),
+            Vec::new(),
+            Snapshot::default(),
+            seed,
+        )
+    }
+
+    /// Bring a node back from the state it persisted before stopping.
+    pub fn restart(
+        id: NodeId,
+        peers: Vec<NodeId>,
+        hard_state: HardState,
+        mut entries: Vec<Entry>,
+        snapshot: Snapshot,
+        seed: u64,
+    ) -> RaftNode {
+        // the snapshot may have been saved without trimming the log, and a crash while
+        // installing one may leave a gap after it
+        entries.retain(|entry| entry.index > snapshot.index);
+        let contiguous = entries
+            .iter()
+            .zip(snapshot.index + 1..)
+            .take_while(|(entry, index)| entry.index == *index)
+            .count();
+        entries.truncate(contiguous);
+        let mut node = RaftNode {
+            id,
+            peers: peers.into_iter().filter(|&peer| peer != id).collect(),
+            role: Role::Follower,
+            leader: None,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            entries,
+            commit: snapshot.index,
+            applied: snapshot.index,
+            snapshot,
+            votes: BTreeSet::new(),
+            next_index: BTreeMap::new(),
+            match_index: BTreeMap::new(),
+            elapsed: 0,
+            election_timeout: 0,
+            // xorshift needs a non-zero state
+            rng: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+            dirty: false,
+            unstable: None,
+            installed: None,
+            receiving: None,
+            chunks: Vec::new(),
+            transfers: BTreeMap::new(),
+            outbox: Vec::new(),
+        };
+        node.reset_election_timeout();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// The leader of the current term, if known.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// The log entries after the snapshot.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Advance time by one tick.
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader => {
+                if self.elapsed >= HEARTBEAT_TICKS {
+                    self.elapsed = 0;
+                    self.retry_transfers();
+                    self.broadcast_append();
+                }
+            }
+            _ => {
+                if self.elapsed >= self.election_timeout {
+                    self.campaign();
+                }
+            }
+        }
+    }
+
+    /// Append `command` to the log if this node is the leader, returning its index
+    /// and term. Otherwise return the leader this node knows of, if any.
+    pub fn propose(&mut self, command: Command) -> Result<(u64, u64), Option<NodeId>> {
+        if self.role != Role::Leader {
+            return Err(self.leader);
+        }
+        let index = self.append(command);
+        self.broadcast_append();
+        self.advance_commit();
+        Ok((index, self.term))
+    }
+
+    /// Handle a message from another node.
+    pub fn step(&mut self, msg: RaftMessage) {
+        if msg.to != self.id {
+            return;
+        }
+        if msg.term > self.term {
+            let leader = match msg.body {
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => Some(msg.from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader);
+        }
+        if msg.term < self.term {
+            // tell a stale node about the newer term
+            match msg.body {
+                Message::RequestVote { .. } => {
+                    self.send(msg.from, Message::Vote { granted: false })
+                }
+                Message::AppendEntries { .. } | Message::InstallSnapshot { .. } => self.send(
+                    msg.from,
+                    Message::AppendResponse {
+                        success: false,
+                        match_index: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return;
+        }
+
+        match msg.body {
+            Message::RequestVote {
+                last_index,
+                last_term,
+            } => {
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = up_to_date && self.voted_for.is_none_or(|id| id == msg.from);
+                if granted {
+                    self.voted_for = Some(msg.from);
+                    self.dirty = true;
+                    self.elapsed = 0;
+                }
+                self.send(msg.from, Message::Vote { granted });
+            }
+            Message::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(msg.from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            Message::AppendEntries {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => {
+                self.follow(msg.from);
+                self.handle_append(msg.from, prev_index, prev_term, entries, commit);
+            }
+            Message::InstallSnapshot { snapshot, chunk } => {
+                self.follow(msg.from);
+                self.receive_chunk(msg.from, snapshot, chunk);
+            }
+            Message::SnapshotAck { index, after } => {
+                if self.role == Role::Leader {
+                    self.handle_snapshot_ack(msg.from, index, after);
+                }
+            }
+            Message::AppendResponse {
+                success,
+                match_index,
+            } => {
+                if self.role == Role::Leader {
+                    self.handle_append_response(msg.from, success, match_index);
+                }
+            }
+        }
+    }
+
+    /// Collect what the node produced: state to persist, entries to apply and
+    /// messages to send.
+    pub fn ready(&mut self) -> Ready {
+        let hard_state = if self.dirty {
+            self.dirty = false;
+            Some(HardState {
+                term: self.term,
+                voted_for: self.voted_for,
+            })
+        } else {
+            None
+        };
+        let log_from = self.unstable.take();
+        let entries = match log_from {
+            Some(from) => self
+                .entries
+                .iter()
+                .filter(|entry| entry.index >= from)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        let committed = self
+            .entries
+            .iter()
+            .filter(|entry| entry.index > self.applied && entry.index <= self.commit)
+            .cloned()
+            .collect();
+        self.applied = self.commit;
+        Ready {
+            hard_state,
+            log_from,
+            entries,
+            snapshot_chunks: std::mem::take(&mut self.chunks),
+            snapshot: self.installed.take(),
+            committed,
+            messages: std::mem::take(&mut self.outbox),
+        }
+    }
+
+    /// Drop the log up to the applied entry `index`, which the state machine holds
+    /// from now on. The caller persists the state machine and the new snapshot,
+    /// then the shorter log.
+    pub fn compact(&mut self, index: u64) {
+        if index <= self.snapshot.index || index > self.applied {
+            return;
+        }
+        let term = self.term_at(index).unwrap_or(self.term);
+        self.entries.retain(|entry| entry.index > index);
+        self.snapshot = Snapshot { index, term };
+    }
+
+    fn campaign(&mut self) {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.dirty = true;
+        self.votes = vec![self.id].into_iter().collect();
+        self.reset_election_timeout();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for peer in self.peers.clone() {
+            self.send(
+                peer,
+                Message::RequestVote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        self.role = Role::Follower;
+        self.term = term;
+        self.voted_for = None;
+        self.leader = leader;
+        self.dirty = true;
+        self.reset_election_timeout();
+    }
+
+    // an append from the leader of the current term
+    fn follow(&mut self, leader: NodeId) {
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+        }
+        self.leader = Some(leader);
+        self.elapsed = 0;
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|&peer| (peer, next)).collect();
+        self.match_index = self.peers.iter().map(|&peer| (peer, 0)).collect();
+        self.transfers.clear();
+        self.append(Command::Noop);
+        self.broadcast_append();
+        self.advance_commit();
+    }
+
+    fn append(&mut self, command: Command) -> u64 {
+        let index = self.last_index() + 1;
+        self.entries.push(Entry {
+            term: self.term,
+            index,
+            command,
+        });
+        self.changed(index);
+        index
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: NodeId) {
+        let next = self.next_index.get(&peer).cloned().unwrap_or(1);
+        if next <= self.snapshot.index {
+            // a transfer already under way goes on at its own pace
+            if !self.transfers.contains_key(&peer) {
+                let transfer = Transfer {
+                    snapshot: self.snapshot.clone(),
+                    after: None,
+                    progressed: true,
+                };
+                self.transfers.insert(peer, transfer);
+                self.send_chunk(peer);
+            }
+            return;
+        }
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(0);
+        let entries = self
+            .entries
+            .iter()
+            .filter(|entry| entry.index >= next)
+            .take(MAX_APPEND)
+            .cloned()
+            .collect();
+        let commit = self.commit;
+        self.send(
+            peer,
+            Message::AppendEntries {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            },
+        );
+    }
+
+    fn handle_append(
+        &mut self,
+        leader: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    ) {
+        // entries covered by the snapshot are known to match
+        let matches = prev_index <= self.snapshot.index
+            || self
+                .term_at(prev_index)
+                .is_some_and(|term| term == prev_term);
+        if !matches {
+            let hint = self.last_index().min(prev_index.saturating_sub(1));
+            self.send(
+                leader,
+                Message::AppendResponse {
+                    success: false,
+                    match_index: hint,
+                },
+            );
+            return;
+        }
+
+        let last_new = prev_index + entries.len() as u64;
+        for entry in entries {
+            if entry.index <= self.snapshot.index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                // a conflicting entry and everything after it are replaced
+                Some(_) => self.entries.retain(|existing| existing.index < entry.index),
+                None => {}
+            }
+            self.changed(entry.index);
+            self.entries.push(entry);
+        }
+        let match_index = last_new.max(self.snapshot.index);
+        if commit > self.commit {
+            self.commit = commit.min(match_index);
+        }
+        self.send(
+            leader,
+            Message::AppendResponse {
+                success: true,
+                match_index,
+            },
+        );
+    }
+
+    // ask for the chunk after the last key `peer` received
+    fn send_chunk(&mut self, peer: NodeId) {
+        if let Some(transfer) = self.transfers.get(&peer) {
+            let snapshot = transfer.snapshot.clone();
+            let chunk = SnapshotChunk {
+                after: transfer.after.clone(),
+                ..SnapshotChunk::default()
+            };
+            self.send(peer, Message::InstallSnapshot { snapshot, chunk });
+        }
+    }
+
+    // resend the chunks that were not acknowledged since the last heartbeat; a
+    // stalled transfer of an older snapshot starts over with the current one
+    fn retry_transfers(&mut self) {
+        let mut stalled = Vec::new();
+        for (&peer, transfer) in self.transfers.iter_mut() {
+            if !std::mem::replace(&mut transfer.progressed, false) {
+                if transfer.snapshot != self.snapshot {
+                    transfer.snapshot = self.snapshot.clone();
+                    transfer.after = None;
+                }
+                stalled.push(peer);
+            }
+        }
+        for peer in stalled {
+            self.send_chunk(peer);
+        }
+    }
+
+    fn handle_snapshot_ack(&mut self, peer: NodeId, index: u64, after: Option<String>) {
+        // duplicate acks of a chunk sent twice are dropped
+        match self.transfers.get_mut(&peer) {
+            Some(transfer) if transfer.snapshot.index == index && transfer.after != after => {
+                transfer.after = after;
+                transfer.progressed = true;
+            }
+            _ => return,
+        }
+        self.send_chunk(peer);
+    }
+
+    // chunks are taken in order; anything else is answered with the chunk expected
+    fn receive_chunk(&mut self, leader: NodeId, snapshot: Snapshot, chunk: SnapshotChunk) {
+        if snapshot.index <= self.commit {
+            self.install(leader, snapshot);
+            return;
+        }
+        let expected = match &self.receiving {
+            Some((receiving, after)) if *receiving == snapshot => after.clone(),
+            _ => None,
+        };
+        if chunk.after != expected {
+            let index = snapshot.index;
+            let after = expected;
+            self.send(leader, Message::SnapshotAck { index, after });
+            return;
+        }
+        let last = chunk.pairs.last().map(|(key, _)| key.clone());
+        let after = last.or(expected);
+        let done = chunk.done;
+        self.chunks.push(chunk);
+        if done {
+            self.receiving = None;
+            self.install(leader, snapshot);
+        } else {
+            let index = snapshot.index;
+            self.receiving = Some((snapshot, after.clone()));
+            self.send(leader, Message::SnapshotAck { index, after });
+        }
+    }
+
+    fn install(&mut self, leader: NodeId, snapshot: Snapshot) {
+        let index = snapshot.index;
+        if index > self.commit {
+            if self.term_at(index) == Some(snapshot.term) {
+                self.entries.retain(|entry| entry.index > index);
+            } else {
+                self.entries.clear();
+                self.changed(index + 1);
+            }
+            self.commit = index;
+            self.applied = index;
+            self.snapshot = snapshot.clone();
+            self.installed = Some(snapshot);
+        }
+        self.send(
+            leader,
+            Message::AppendResponse {
+                success: true,
+                match_index: index.max(self.commit.min(self.last_index())),
+            },
+        );
+    }
+
+    fn handle_append_response(&mut self, peer: NodeId, success: bool, match_index: u64) {
+        if success {
+            let matched = self.match_index.entry(peer).or_insert(0);
+            *matched = (*matched).max(match_index);
+            let next = *matched + 1;
+            self.next_index.insert(peer, next);
+            if self
+                .transfers
+                .get(&peer)
+                .is_some_and(|transfer| transfer.snapshot.index < next)
+            {
+                self.transfers.remove(&peer);
+            }
+            self.advance_commit();
+            if next <= self.last_index() {
+                self.send_append(peer);
+            }
+        } else {
+            let next = self.next_index.get(&peer).cloned().unwrap_or(1);
+            let next = next.saturating_sub(1).min(match_index + 1).max(1);
+            self.next_index.insert(peer, next);
+            self.send_append(peer);
+        }
+    }
+
+    // commit the highest entry of this term stored on a majority
+    fn advance_commit(&mut self) {
+        let mut matched: Vec<u64> = self.match_index.values().cloned().collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.term_at(index) == Some(self.term) {
+            self.commit = index;
+        }
+    }
+
+    fn changed(&mut self, index: u64) {
+        self.unstable = Some(self.unstable.map_or(index, |from| from.min(index)));
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        let first = self.snapshot.index + 1;
+        if index < first {
+            return None;
+        }
+        self.entries
+            .get((index - first) as usize)
+            .map(|entry| entry.term)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn send(&mut self, to: NodeId, body: Message) {
+        self.outbox.push(RaftMessage {
+            from: self.id,
+            to,
+            term: self.term,
+            body,
+        });
+    }
+
+    fn reset_election_timeout(&mut self) {
+        self.elapsed = 0;
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = ELECTION_TICKS + (self.rng % ELECTION_TICKS as u64) as u32;
+    }
+}
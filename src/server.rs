@@ -1,68 +1,610 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::engines::KvEngine;
+use crate::auth::{Acl, AclFile, Auth, Credentials, Permission};
+use crate::common::{
+    unix_millis, Envelope, ErrorResponse, GetResponse, InfoResponse, RemoveResponse, Request,
+    ScanResponse, SetResponse, StatsResponse,
+};
+use crate::engines::{KvEngine, LogOp};
+use crate::tls::{ClientTls, ServerTls, Stream};
 
-use crate::Result;
-use log::{debug, error};
+use crate::{KvsErr, Result};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use std::io::{self, BufReader, BufWriter};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+mod metrics;
+mod replication;
+use self::metrics::{Counted, Metrics};
+use self::replication::ReplicationLog;
+
+// how often the accept loop and idle connections check for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Limits and timeouts applied by a `KvServer`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Maximum time to wait for the rest of a request once it started arriving.
+    pub read_timeout: Option<Duration>,
+    /// Maximum time to wait for a response to be written.
+    pub write_timeout: Option<Duration>,
+    /// Connections that send nothing for this long are closed.
+    pub idle_timeout: Option<Duration>,
+    /// Connections beyond this number are rejected with an error frame.
+    pub max_connections: usize,
+    /// Scans asking for more pairs get only this many.
+    pub max_scan_limit: usize,
+    /// How long in-flight requests may keep running once shutdown starts.
+    pub shutdown_timeout: Duration,
+    /// Leader to replicate from. A replica rejects writes from clients.
+    pub replica_of: Option<String>,
+    /// File where a replica keeps its position in the leader's write stream,
+    /// so it can catch up after a restart instead of copying everything again.
+    pub replica_state: Option<PathBuf>,
+    /// File where the recent writes are saved on shutdown, so followers can
+    /// resume after a restart of this server instead of copying everything again.
+    pub backlog_file: Option<PathBuf>,
+    /// Address of the HTTP endpoint serving metrics in the Prometheus text format.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Directory holding the engine's data, reported by INFO requests.
+    pub data_dir: Option<PathBuf>,
+    /// When set, connections must authenticate before sending any other request.
+    pub auth: Option<Auth>,
+    /// Presented to the leader when replicating from one that requires authentication.
+    pub leader_credentials: Option<Credentials>,
+    /// Which users may send which requests. Reloaded when the file changes.
+    pub acl: Option<Arc<AclFile>>,
+    /// When set, clients must connect over TLS.
+    pub tls: Option<ServerTls>,
+    /// Used to connect to a leader serving TLS.
+    pub leader_tls: Option<ClientTls>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            max_connections: 128,
+            max_scan_limit: 1024,
+            shutdown_timeout: Duration::from_secs(5),
+            replica_of: None,
+            replica_state: None,
+            backlog_file: None,
+            metrics_addr: None,
+            data_dir: None,
+            auth: None,
+            leader_credentials: None,
+            acl: None,
+            tls: None,
+            leader_tls: None,
+        }
+    }
+}
+
 pub struct KvServer<E: KvEngine> {
-    engine: E,
+    engine: Arc<Mutex<E>>,
+    config: Arc<ServerConfig>,
+    shutdown: ShutdownHandle,
+    connections: Arc<AtomicUsize>,
+    replication: Arc<ReplicationLog>,
+    metrics: Arc<Metrics>,
+    started: Instant,
+}
+
+/// What a server reports about itself in answer to an INFO request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Name of the storage engine.
+    pub engine: String,
+    /// Version of the server.
+    pub version: String,
+    /// Directory holding the engine's data, when set in `ServerConfig`.
+    pub data_dir: Option<String>,
+    /// Number of live keys.
+    pub keys: u64,
+    /// Bytes taken on disk by the data, 0 when unknown.
+    pub disk_bytes: u64,
+    /// Seconds since the server started.
+    pub uptime_secs: u64,
+}
+
+impl fmt::Display for ServerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "version: {}", self.version)?;
+        if let Some(data_dir) = &self.data_dir {
+            writeln!(f, "data_dir: {}", data_dir)?;
+        }
+        writeln!(f, "keys: {}", self.keys)?;
+        writeln!(f, "disk_bytes: {}", self.disk_bytes)?;
+        write!(f, "uptime_secs: {}", self.uptime_secs)
+    }
 }
 
-impl<E: KvEngine> KvServer<E> {
+/// Handle used to stop a running `KvServer` from another thread.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop accepting connections and exit.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+// decrements the open connection count when a connection thread ends
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<E: KvEngine> Clone for KvServer<E> {
+    fn clone(&self) -> Self {
+        KvServer {
+            engine: Arc::clone(&self.engine),
+            config: Arc::clone(&self.config),
+            shutdown: self.shutdown.clone(),
+            connections: Arc::clone(&self.connections),
+            replication: Arc::clone(&self.replication),
+            metrics: Arc::clone(&self.metrics),
+            started: self.started,
+        }
+    }
+}
+
+impl<E: KvEngine + Send + 'static> KvServer<E> {
     pub fn new(engine: E) -> Self {
-        KvServer { engine }
+        KvServer::with_config(engine, ServerConfig::default())
+    }
+
+    pub fn with_config(engine: E, config: ServerConfig) -> Self {
+        let replication = match &config.backlog_file {
+            Some(path) => ReplicationLog::load(path),
+            None => ReplicationLog::new(),
+        };
+        KvServer {
+            engine: Arc::new(Mutex::new(engine)),
+            config: Arc::new(config),
+            shutdown: ShutdownHandle::default(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            replication: Arc::new(replication),
+            metrics: Arc::new(Metrics::default()),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
+
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    if let Err(e) = self.serve(stream) {
-                        error!("Error on serving client: {}", e);
-                    }
-                }
+        // accept without blocking so the shutdown flag is noticed
+        listener.set_nonblocking(true)?;
+        let exporter = match self.config.metrics_addr {
+            Some(addr) => {
+                let listener = metrics::bind(addr)?;
+                let server = self.clone();
+                Some(thread::spawn(move || server.serve_metrics(listener)))
+            }
+            None => None,
+        };
+        let follower = self.config.replica_of.clone().map(|leader| {
+            let server = self.clone();
+            thread::spawn(move || server.follow(&leader))
+        });
+        while !self.shutdown.is_shutdown() {
+            match listener.accept() {
+                Ok((stream, _)) => self.accept(stream),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
                 Err(e) => error!("connection failed: {}", e),
             }
         }
+
+        // connections stop by themselves once the shutdown deadline passes
+        let deadline = Instant::now() + self.config.shutdown_timeout + POLL_INTERVAL;
+        while self.connections.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        if let Some(follower) = follower {
+            follower
+                .join()
+                .map_err(|_| KvsErr::StringErr("replication thread panicked".to_owned()))?;
+        }
+        if let Some(exporter) = exporter {
+            exporter
+                .join()
+                .map_err(|_| KvsErr::StringErr("metrics thread panicked".to_owned()))?;
+        }
+        info!("Shutting down, flushing storage engine");
+        let mut engine = self.engine()?;
+        engine.flush()?;
+        if let Some(path) = &self.config.backlog_file {
+            self.replication.save(path)?;
+        }
         Ok(())
     }
-    fn serve(&mut self, tcp: TcpStream) -> Result<()> {
+
+    fn accept(&self, stream: TcpStream) {
+        if self.connections.fetch_add(1, Ordering::SeqCst) >= self.config.max_connections {
+            let guard = ConnectionGuard(Arc::clone(&self.connections));
+            warn!("Too many connections, rejecting {:?}", stream.peer_addr());
+            self.metrics.error(&KvsErr::TooManyConnections);
+            let rejected = Stream::accept(stream, self.config.tls.as_ref())
+                .and_then(|stream| reject(stream, KvsErr::TooManyConnections));
+            if let Err(e) = rejected {
+                debug!("Error on rejecting client: {}", e);
+            }
+            drop(guard);
+            return;
+        }
+
+        let guard = ConnectionGuard(Arc::clone(&self.connections));
+        let server = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.serve(stream) {
+                error!("Error on serving client: {}", e);
+            }
+            drop(guard);
+        });
+    }
+
+    fn serve(&self, tcp: TcpStream) -> Result<()> {
+        let stream = Stream::accept(tcp, self.config.tls.as_ref())?;
+        let tcp = stream.tcp();
         let peer = tcp.peer_addr()?;
-        let reader = BufReader::new(&tcp);
-        let mut writer = BufWriter::new(&tcp);
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-
-        for req in req_reader {
-            let req = req?;
-            debug!("Receive request from {} : {:?}", peer, req);
-            match req {
+        tcp.set_nonblocking(false)?;
+        tcp.set_write_timeout(self.config.write_timeout)?;
+        let mut reader = BufReader::new(Counted::new(stream.clone(), &self.metrics.bytes_read));
+        let mut writer = BufWriter::new(Counted::new(stream.clone(), &self.metrics.bytes_written));
+        let mut deadline = None;
+        let mut last_active = Instant::now();
+        let mut authenticated = self.config.auth.is_none();
+        let mut user = None;
+
+        loop {
+            if self.shutdown.is_shutdown() {
+                let timeout = self.config.shutdown_timeout;
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + timeout);
+                if Instant::now() >= deadline {
+                    warn!(
+                        "Shutdown deadline reached, dropping connection from {}",
+                        peer
+                    );
+                    break;
+                }
+            }
+            if let Some(idle_timeout) = self.config.idle_timeout {
+                if last_active.elapsed() >= idle_timeout {
+                    debug!("Closing idle connection from {}", peer);
+                    break;
+                }
+            }
+            // wait for the next request, waking up regularly to check for shutdown
+            tcp.set_read_timeout(Some(POLL_INTERVAL))?;
+            match reader.fill_buf() {
+                Ok([]) => break,
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => {
+                    if self.shutdown.is_shutdown() {
+                        break;
+                    }
+                    continue;
+                }
+                // a TLS client hanging up without saying so
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            tcp.set_read_timeout(self.config.read_timeout)?;
+
+            let envelope = Envelope::deserialize(&mut Deserializer::from_reader(&mut reader))?;
+            let received = Instant::now();
+            let command = metrics::command(&envelope.request);
+            debug!("Receive request from {} : {:?}", peer, envelope);
+            if let Request::Auth { credentials } = &envelope.request {
+                let result = match &self.config.auth {
+                    Some(auth) => self.metrics.track(auth.check(credentials)),
+                    None => Ok(None),
+                };
+                self.metrics.request(command, received.elapsed());
+                match result {
+                    Ok(name) => {
+                        authenticated = true;
+                        user = name;
+                        send_resp(&mut writer, SetResponse::Ok(()))?;
+                        last_active = Instant::now();
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Authentication of {} failed", peer);
+                        send_resp(&mut writer, ErrorResponse::Err(e.to_string()))?;
+                        break;
+                    }
+                }
+            }
+            if !authenticated {
+                warn!("Rejecting unauthenticated request from {}", peer);
+                self.metrics.error(&KvsErr::Unauthenticated);
+                send_resp(
+                    &mut writer,
+                    ErrorResponse::Err(KvsErr::Unauthenticated.to_string()),
+                )?;
+                break;
+            }
+            let acl = match &self.config.acl {
+                Some(acl) => Some(acl.current()?),
+                None => None,
+            };
+            if let Some(acl) = &acl {
+                if let Err(e) = acl.check(user.as_deref(), &envelope.request) {
+                    warn!("Refusing {} request from {}: {}", command, peer, e);
+                    self.metrics.error(&e);
+                    send_resp(&mut writer, ErrorResponse::Err(e.to_string()))?;
+                    self.metrics.request(command, received.elapsed());
+                    last_active = Instant::now();
+                    continue;
+                }
+            }
+            if let Request::Replicate { position } = envelope.request {
+                info!("Replicating to {}", peer);
+                return self.replicate(&mut writer, position);
+            }
+            if let (Some(leader), true) = (&self.config.replica_of, is_write(&envelope.request)) {
+                let err = KvsErr::ReadOnly(leader.clone());
+                self.metrics.error(&err);
+                send_resp(&mut writer, ErrorResponse::Err(err.to_string()))?;
+                self.metrics.request(command, received.elapsed());
+                last_active = Instant::now();
+                continue;
+            }
+            let deadline_exceeded = || {
+                envelope
+                    .deadline_unix_ms
+                    .is_some_and(|deadline| unix_millis(SystemTime::now()) >= deadline)
+            };
+
+            // checked again once the engine is free, waiting for it takes time too
+            let engine = if deadline_exceeded() {
+                None
+            } else {
+                Some(self.engine()?).filter(|_| !deadline_exceeded())
+            };
+            let mut engine = match engine {
+                Some(engine) => engine,
+                None => {
+                    self.metrics.error(&KvsErr::DeadlineExceeded);
+                    send_resp(
+                        &mut writer,
+                        ErrorResponse::Err(KvsErr::DeadlineExceeded.to_string()),
+                    )?;
+                    self.metrics.request(command, received.elapsed());
+                    last_active = Instant::now();
+                    continue;
+                }
+            };
+            match envelope.request {
                 Request::Get { key } => send_resp(
                     &mut writer,
-                    match self.engine.get(key) {
+                    match self.metrics.track(engine.get(key)) {
                         Ok(value) => GetResponse::Ok(value),
                         Err(e) => GetResponse::Err(format!("{}", e)),
                     },
                 ),
                 Request::Set { key, value } => send_resp(
                     &mut writer,
-                    match self.engine.set(key, value) {
+                    match self.metrics.track(
+                        engine
+                            .set(key.clone(), value.clone())
+                            .and_then(|()| self.replication.append(LogOp::Set { key, value })),
+                    ) {
                         Ok(value) => SetResponse::Ok(value),
                         Err(e) => SetResponse::Err(format!("{}", e)),
                     },
                 ),
                 Request::Remove { key } => send_resp(
                     &mut writer,
-                    match self.engine.remove(key) {
+                    match self.metrics.track(
+                        engine
+                            .remove(key.clone())
+                            .and_then(|()| self.replication.append(LogOp::Remove { key })),
+                    ) {
                         Ok(value) => RemoveResponse::Ok(value),
                         Err(e) => RemoveResponse::Err(format!("{}", e)),
                     },
                 ),
+                Request::Scan { after, limit } => {
+                    let limit = limit.min(self.config.max_scan_limit);
+                    send_resp(
+                        &mut writer,
+                        match self.metrics.track(match &acl {
+                            Some(acl) => {
+                                scan_permitted(&mut *engine, acl, user.as_deref(), after, limit)
+                            }
+                            None => engine.scan(after, limit),
+                        }) {
+                            Ok(pairs) => ScanResponse::Ok(pairs),
+                            Err(e) => ScanResponse::Err(format!("{}", e)),
+                        },
+                    )
+                }
+                Request::SetBatch { pairs } => send_resp(
+                    &mut writer,
+                    match self
+                        .metrics
+                        .track(engine.set_batch(pairs.clone()).and_then(|()| {
+                            pairs.into_iter().try_for_each(|(key, value)| {
+                                self.replication.append(LogOp::Set { key, value })
+                            })
+                        })) {
+                        Ok(value) => SetResponse::Ok(value),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    },
+                ),
+                Request::Backup { dir, parent } => send_resp(
+                    &mut writer,
+                    match self.metrics.track(backup(
+                        &mut *engine,
+                        Path::new(&dir),
+                        parent.as_deref().map(Path::new),
+                    )) {
+                        Ok(value) => SetResponse::Ok(value),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    },
+                ),
+                Request::Info => send_resp(
+                    &mut writer,
+                    match self.metrics.track(self.info(&mut *engine)) {
+                        Ok(info) => InfoResponse::Ok(info),
+                        Err(e) => InfoResponse::Err(format!("{}", e)),
+                    },
+                ),
+                Request::Stats => send_resp(
+                    &mut writer,
+                    match self.metrics.track(engine.stats()) {
+                        Ok(stats) => StatsResponse::Ok(stats),
+                        Err(e) => StatsResponse::Err(format!("{}", e)),
+                    },
+                ),
+                Request::Compact => send_resp(
+                    &mut writer,
+                    match self.metrics.track(engine.compact()) {
+                        Ok(value) => SetResponse::Ok(value),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    },
+                ),
+                Request::Flush => send_resp(
+                    &mut writer,
+                    match self.metrics.track(engine.flush()) {
+                        Ok(value) => SetResponse::Ok(value),
+                        Err(e) => SetResponse::Err(format!("{}", e)),
+                    },
+                ),
+                // answered above, before the engine is locked
+                Request::Auth { .. } | Request::Replicate { .. } => send_resp(
+                    &mut writer,
+                    ErrorResponse::Err(KvsErr::UnexpectedCommandType.to_string()),
+                ),
             }?;
+            self.metrics.request(command, received.elapsed());
+            last_active = Instant::now();
         }
         Ok(())
     }
+
+    fn info(&self, engine: &mut E) -> Result<ServerInfo> {
+        let stats = engine.stats()?;
+        Ok(ServerInfo {
+            engine: engine.name().to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            data_dir: self
+                .config
+                .data_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+            keys: stats.keys,
+            disk_bytes: stats.disk_bytes,
+            uptime_secs: self.started.elapsed().as_secs(),
+        })
+    }
+
+    fn engine(&self) -> Result<MutexGuard<'_, E>> {
+        self.engine
+            .lock()
+            .map_err(|_| KvsErr::StringErr("storage engine lock poisoned".to_owned()))
+    }
+}
+
+fn backup<E: KvEngine>(engine: &mut E, dir: &Path, parent: Option<&Path>) -> Result<()> {
+    match parent {
+        Some(parent) => engine.backup_incremental(dir, parent),
+        None => engine.backup_to(dir),
+    }
+}
+
+// answer a connection with a single error frame and close it
+fn reject(mut stream: Stream, err: KvsErr) -> Result<()> {
+    let tcp = stream.tcp();
+    tcp.set_nonblocking(false)?;
+    tcp.set_read_timeout(Some(POLL_INTERVAL))?;
+    tcp.set_write_timeout(Some(POLL_INTERVAL))?;
+    send_resp(&mut stream, ErrorResponse::Err(err.to_string()))?;
+    stream.shutdown()?;
+    Ok(())
+}
+
+fn is_write(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::Set { .. } | Request::Remove { .. } | Request::SetBatch { .. }
+    )
+}
+
+// scan like `engine.scan`, only visiting the prefixes `user` may scan
+fn scan_permitted<E: KvEngine + ?Sized>(
+    engine: &mut E,
+    acl: &Acl,
+    user: Option<&str>,
+    after: Option<String>,
+    limit: usize,
+) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for prefix in acl.prefixes(user, Permission::Scan) {
+        let mut cursor = match &after {
+            // the keys of the prefix are all before `after`
+            Some(after) if *after > prefix && !after.starts_with(&prefix) => continue,
+            Some(after) if *after >= prefix => Some(after.clone()),
+            _ if prefix.is_empty() => None,
+            // keys after the prefix, which is a key of its own too
+            _ => {
+                if let Some(value) = engine.get(prefix.clone())? {
+                    pairs.push((prefix.clone(), value));
+                }
+                Some(prefix.clone())
+            }
+        };
+        while pairs.len() < limit {
+            let batch = engine.scan(cursor.take(), limit - pairs.len())?;
+            cursor = batch.last().map(|(key, _)| key.clone());
+            pairs.extend(
+                batch
+                    .into_iter()
+                    .take_while(|(key, _)| key.starts_with(&prefix)),
+            );
+            // stop at the end of the data or of the prefix
+            if cursor.is_none() || pairs.last().map(|(key, _)| key) != cursor.as_ref() {
+                break;
+            }
+        }
+        if pairs.len() >= limit {
+            break;
+        }
+    }
+    pairs.truncate(limit);
+    Ok(pairs)
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
 }
 
 fn send_resp<W: io::Write, T: serde::Serialize>(mut writer: W, resp: T) -> Result<()> {
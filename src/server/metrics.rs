@@ -0,0 +1,314 @@
+use super::{is_timeout, KvServer, POLL_INTERVAL};
+use crate::common::Request;
+use crate::engines::KvEngine;
+use crate::{KvsErr, Result};
+use log::{debug, error};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+// upper bounds of the request latency histogram, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Counters of a running server, rendered in the Prometheus text format.
+#[derive(Default)]
+pub(super) struct Metrics {
+    pub(super) bytes_read: AtomicU64,
+    pub(super) bytes_written: AtomicU64,
+    requests: Mutex<BTreeMap<&'static str, Histogram>>,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// Record a request answered after `latency`.
+    pub(super) fn request(&self, command: &'static str, latency: Duration) {
+        let seconds = latency.as_secs_f64();
+        if let Ok(mut requests) = self.requests.lock() {
+            let histogram = requests.entry(command).or_default();
+            for (bucket, &bound) in histogram.buckets.iter_mut().zip(&LATENCY_BUCKETS) {
+                if seconds <= bound {
+                    *bucket += 1;
+                }
+            }
+            histogram.sum += seconds;
+            histogram.count += 1;
+        }
+    }
+
+    pub(super) fn error(&self, err: &KvsErr) {
+        if let Ok(mut errors) = self.errors.lock() {
+            *errors.entry(err.kind()).or_default() += 1;
+        }
+    }
+
+    /// Pass `result` through, counting it if it is an error.
+    pub(super) fn track<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.error(e);
+        }
+        result
+    }
+
+    fn render<E: KvEngine>(&self, engine: &mut E, connections: usize) -> Result<String> {
+        let stats = engine.stats()?;
+        let mut out = String::new();
+        let requests = lock(&self.requests)?;
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests served by command.",
+        );
+        for (command, histogram) in requests.iter() {
+            sample(
+                &mut out,
+                "kvs_requests_total",
+                &[("command", command)],
+                histogram.count,
+            );
+        }
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "histogram",
+            "Time taken to answer requests by command.",
+        );
+        for (command, histogram) in requests.iter() {
+            for (&bound, &count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                let le = bound.to_string();
+                let labels = [("command", *command), ("le", le.as_str())];
+                sample(
+                    &mut out,
+                    "kvs_request_duration_seconds_bucket",
+                    &labels,
+                    count,
+                );
+            }
+            let labels = [("command", *command), ("le", "+Inf")];
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_bucket",
+                &labels,
+                histogram.count,
+            );
+            let labels = [("command", *command)];
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_sum",
+                &labels,
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "kvs_request_duration_seconds_count",
+                &labels,
+                histogram.count,
+            );
+        }
+        drop(requests);
+        header(&mut out, "kvs_errors_total", "counter", "Errors by kind.");
+        for (kind, count) in lock(&self.errors)?.iter() {
+            sample(&mut out, "kvs_errors_total", &[("kind", kind)], count);
+        }
+        let gauges: [(&str, &str, &str, f64); 8] = [
+            (
+                "kvs_open_connections",
+                "gauge",
+                "Connections being served.",
+                connections as f64,
+            ),
+            (
+                "kvs_read_bytes_total",
+                "counter",
+                "Bytes read from clients.",
+                self.bytes_read.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "kvs_written_bytes_total",
+                "counter",
+                "Bytes written to clients.",
+                self.bytes_written.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "kvs_keys",
+                "gauge",
+                "Live keys in the storage engine.",
+                stats.keys as f64,
+            ),
+            (
+                "kvs_log_files",
+                "gauge",
+                "Log files of the storage engine.",
+                stats.log_files as f64,
+            ),
+            (
+                "kvs_uncompacted_bytes",
+                "gauge",
+                "Bytes a compaction would reclaim.",
+                stats.uncompacted_bytes as f64,
+            ),
+            (
+                "kvs_compactions_total",
+                "counter",
+                "Compactions run.",
+                stats.compactions as f64,
+            ),
+            (
+                "kvs_compaction_duration_seconds_total",
+                "counter",
+                "Time spent compacting.",
+                stats.compaction_seconds,
+            ),
+        ];
+        for (name, kind, help, value) in gauges.iter() {
+            header(&mut out, name, kind, help);
+            sample(&mut out, name, &[], value);
+        }
+        Ok(out)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample<V: std::fmt::Display>(out: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, value))
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|_| KvsErr::StringErr("metrics lock poisoned".to_owned()))
+}
+
+/// Name of a request as used in the `command` label.
+pub(super) fn command(request: &Request) -> &'static str {
+    match request {
+        Request::Get { .. } => "get",
+        Request::Set { .. } => "set",
+        Request::Remove { .. } => "remove",
+        Request::Scan { .. } => "scan",
+        Request::SetBatch { .. } => "set_batch",
+        Request::Backup { .. } => "backup",
+        Request::Auth { .. } => "auth",
+        Request::Info => "info",
+        Request::Stats => "stats",
+        Request::Compact => "compact",
+        Request::Flush => "flush",
+        Request::Replicate { .. } => "replicate",
+    }
+}
+
+/// A reader or writer adding the bytes going through it to a counter.
+pub(super) struct Counted<'a, T> {
+    inner: T,
+    bytes: &'a AtomicU64,
+}
+
+impl<'a, T> Counted<'a, T> {
+    pub(super) fn new(inner: T, bytes: &'a AtomicU64) -> Self {
+        Counted { inner, bytes }
+    }
+}
+
+impl<'a, T: Read> Read for Counted<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+impl<'a, T: Write> Write for Counted<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<E: KvEngine + Send + 'static> KvServer<E> {
+    /// Answer HTTP requests for `/metrics` until shutdown.
+    pub(super) fn serve_metrics(&self, listener: TcpListener) {
+        while !self.shutdown.is_shutdown() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = self.answer_scrape(stream) {
+                        debug!("Error on serving metrics: {}", e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => error!("metrics connection failed: {}", e),
+            }
+        }
+    }
+
+    fn answer_scrape(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // skip the headers, the request has no body
+        loop {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) if line.trim().is_empty() => break,
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let path = request_line.split_whitespace().nth(1).unwrap_or("");
+        let (status, body) = if path == "/metrics" {
+            let connections = self.connections.load(Ordering::SeqCst);
+            let body = self.metrics.render(&mut *self.engine()?, connections)?;
+            ("200 OK", body)
+        } else {
+            ("404 Not Found", "Not found\n".to_owned())
+        };
+        write!(
+            &stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+}
+
+/// Bind the metrics endpoint, accepting without blocking so shutdown is noticed.
+pub(super) fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
@@ -0,0 +1,400 @@
+use super::{is_timeout, send_resp, KvServer, POLL_INTERVAL};
+use crate::common::{Envelope, Position, ReplicationFrame, Request, SetResponse};
+use crate::engines::{replace_range, KvEngine, LogOp};
+use crate::tls::Stream;
+use crate::{KvsErr, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// writes kept in memory for followers that fall behind
+const BACKLOG: usize = 10_000;
+// how long a leader stays silent before telling a follower it is still there
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+// a follower reconnects when the leader is silent for this long
+const LEADER_TIMEOUT: Duration = Duration::from_secs(3);
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+// how often a follower syncs what it applied and saves its position
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+// pairs per snapshot frame
+const SNAPSHOT_BATCH: usize = 1024;
+
+/// The recent writes of a server, numbered so followers can resume where they stopped.
+/// Kept in memory, and saved on shutdown so followers resume after a restart too.
+pub(super) struct ReplicationLog {
+    state: Mutex<Backlog>,
+    appended: Condvar,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Backlog {
+    epoch: u64,
+    seq: u64,
+    ops: VecDeque<(u64, LogOp)>,
+}
+
+impl ReplicationLog {
+    pub(super) fn new() -> ReplicationLog {
+        ReplicationLog::with_backlog(Backlog {
+            epoch: new_epoch(),
+            seq: 0,
+            ops: VecDeque::new(),
+        })
+    }
+
+    /// Pick up the backlog saved in `path` by the last clean shutdown. The file is
+    /// removed, so after a crash, when the data may not match it, a new epoch starts.
+    pub(super) fn load(path: &Path) -> ReplicationLog {
+        let backlog = fs::read(path)
+            .map_err(KvsErr::from)
+            .and_then(|buf| Ok(serde_json::from_slice(&buf)?))
+            .and_then(|backlog| {
+                fs::remove_file(path)?;
+                Ok(backlog)
+            });
+        match backlog {
+            Ok(backlog) => ReplicationLog::with_backlog(backlog),
+            Err(KvsErr::Io(e)) if e.kind() == io::ErrorKind::NotFound => ReplicationLog::new(),
+            Err(e) => {
+                warn!("Ignoring saved replication backlog: {}", e);
+                ReplicationLog::new()
+            }
+        }
+    }
+
+    fn with_backlog(backlog: Backlog) -> ReplicationLog {
+        ReplicationLog {
+            state: Mutex::new(backlog),
+            appended: Condvar::new(),
+        }
+    }
+
+    /// Save the backlog to `path`, once the engine was flushed for the last time.
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let backlog = self.backlog()?;
+        write_synced(path, &serde_json::to_vec(&*backlog)?)
+    }
+
+    /// Record a write applied to the engine; called with the engine locked so the
+    /// order matches the engine's.
+    pub(super) fn append(&self, op: LogOp) -> Result<()> {
+        let mut backlog = self.backlog()?;
+        backlog.seq += 1;
+        let seq = backlog.seq;
+        backlog.ops.push_back((seq, op));
+        if backlog.ops.len() > BACKLOG {
+            backlog.ops.pop_front();
+        }
+        self.appended.notify_all();
+        Ok(())
+    }
+
+    fn position(&self) -> Result<Position> {
+        let backlog = self.backlog()?;
+        Ok(Position {
+            epoch: backlog.epoch,
+            seq: backlog.seq,
+        })
+    }
+
+    // forget every write, after the data was replaced by a snapshot
+    fn reset(&self, position: Position) -> Result<()> {
+        let mut backlog = self.backlog()?;
+        backlog.epoch = position.epoch;
+        backlog.seq = position.seq;
+        backlog.ops.clear();
+        Ok(())
+    }
+
+    // writes after `position`, waiting up to `timeout` for one to arrive;
+    // `None` when they are no longer all in the backlog
+    fn ops_after(
+        &self,
+        position: Position,
+        timeout: Duration,
+    ) -> Result<Option<Vec<(u64, LogOp)>>> {
+        let mut backlog = self.backlog()?;
+        if backlog.seq == position.seq && backlog.epoch == position.epoch {
+            backlog = self
+                .appended
+                .wait_timeout(backlog, timeout)
+                .map_err(|_| lock_poisoned())?
+                .0;
+        }
+        let first = backlog.ops.front().map_or(backlog.seq + 1, |&(seq, _)| seq);
+        if backlog.epoch != position.epoch || position.seq + 1 < first || position.seq > backlog.seq
+        {
+            return Ok(None);
+        }
+        Ok(Some(
+            backlog
+                .ops
+                .iter()
+                .filter(|&&(seq, _)| seq > position.seq)
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    fn backlog(&self) -> Result<MutexGuard<'_, Backlog>> {
+        self.state.lock().map_err(|_| lock_poisoned())
+    }
+}
+
+impl<E: KvEngine + Send + 'static> KvServer<E> {
+    /// Leader side: stream the writes after `position` until the follower goes away.
+    pub(super) fn replicate<W: Write>(
+        &self,
+        writer: &mut W,
+        position: Option<Position>,
+    ) -> Result<()> {
+        let mut position = match position {
+            Some(position)
+                if self
+                    .replication
+                    .ops_after(position, Duration::from_secs(0))?
+                    .is_some() =>
+            {
+                position
+            }
+            _ => self.send_snapshot(writer)?,
+        };
+        while !self.shutdown.is_shutdown() {
+            match self.replication.ops_after(position, HEARTBEAT_INTERVAL)? {
+                // the follower fell behind the backlog
+                None => position = self.send_snapshot(writer)?,
+                Some(ops) if ops.is_empty() => {
+                    send_resp(&mut *writer, ReplicationFrame::Heartbeat)?
+                }
+                Some(ops) => {
+                    for (seq, op) in ops {
+                        send_resp(&mut *writer, ReplicationFrame::Op { seq, op })?;
+                        position.seq = seq;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The pairs are read a batch at a time, with the engine locked only meanwhile.
+    // Writes made in between come after `position` and are sent next, so once the
+    // follower applied them too it holds the leader's data.
+    fn send_snapshot<W: Write>(&self, writer: &mut W) -> Result<Position> {
+        // writes are logged with the engine locked, so no write is missed
+        let position = {
+            let _engine = self.engine()?;
+            self.replication.position()?
+        };
+        info!("Sending snapshot at {:?}", position);
+        send_resp(&mut *writer, ReplicationFrame::Snapshot { position })?;
+        let mut after = None;
+        let mut count = 0;
+        loop {
+            let pairs = self.engine()?.scan(after.take(), SNAPSHOT_BATCH)?;
+            after = match pairs.last() {
+                Some((key, _)) => Some(key.clone()),
+                None => break,
+            };
+            count += pairs.len();
+            send_resp(&mut *writer, ReplicationFrame::Pairs(pairs))?;
+        }
+        send_resp(&mut *writer, ReplicationFrame::SnapshotEnd)?;
+        info!("Sent snapshot of {} pairs at {:?}", count, position);
+        Ok(position)
+    }
+
+    /// Follower side: apply the writes of `leader` until shutdown, reconnecting as needed.
+    pub(super) fn follow(&self, leader: &str) {
+        let state = self.config.replica_state.as_deref();
+        let mut position = match state.map(load_position).transpose() {
+            Ok(position) => position.flatten(),
+            Err(e) => {
+                warn!("Ignoring replication state: {}", e);
+                None
+            }
+        };
+        while !self.shutdown.is_shutdown() {
+            if let Err(e) = self.follow_once(leader, &mut position, state) {
+                warn!("Replication from {} interrupted: {}", leader, e);
+            }
+            let retry = Instant::now() + RECONNECT_INTERVAL;
+            while Instant::now() < retry && !self.shutdown.is_shutdown() {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn follow_once(
+        &self,
+        leader: &str,
+        position: &mut Option<Position>,
+        state: Option<&Path>,
+    ) -> Result<()> {
+        let stream = match &self.config.leader_tls {
+            Some(tls) => Stream::connect_tls(leader, tls)?,
+            None => Stream::connect(leader)?,
+        };
+        stream.tcp().set_read_timeout(Some(LEADER_TIMEOUT))?;
+        let mut writer = BufWriter::new(stream.clone());
+        let mut reader = Deserializer::from_reader(BufReader::new(stream));
+        if let Some(credentials) = &self.config.leader_credentials {
+            let envelope = Envelope {
+                request: Request::Auth {
+                    credentials: credentials.clone(),
+                },
+                deadline_unix_ms: None,
+            };
+            serde_json::to_writer(&mut writer, &envelope)?;
+            writer.flush()?;
+            if let SetResponse::Err(msg) = SetResponse::deserialize(&mut reader)? {
+                return Err(KvsErr::from_remote(msg));
+            }
+        }
+        let envelope = Envelope {
+            request: Request::Replicate {
+                position: *position,
+            },
+            deadline_unix_ms: None,
+        };
+        serde_json::to_writer(&mut writer, &envelope)?;
+        writer.flush()?;
+        info!("Replicating from {} after {:?}", leader, position);
+
+        // where the snapshot being received was taken, and its last key applied
+        let mut snapshot: Option<(Position, Option<String>)> = None;
+        let mut saved = *position;
+        let mut last_checkpoint = Instant::now();
+        while !self.shutdown.is_shutdown() {
+            let frame = match ReplicationFrame::deserialize(&mut reader) {
+                Ok(frame) => frame,
+                Err(e)
+                    if e.io_error_kind()
+                        .is_some_and(|kind| is_timeout(&kind.into())) =>
+                {
+                    return Err(KvsErr::StringErr("leader stopped responding".to_owned()))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            match frame {
+                ReplicationFrame::Snapshot { position: at } => {
+                    // the data is partly replaced until the snapshot ends
+                    if let Some(state) = state {
+                        remove_position(state)?;
+                    }
+                    *position = None;
+                    saved = None;
+                    snapshot = Some((at, None));
+                }
+                ReplicationFrame::Pairs(pairs) => {
+                    let (_, after) = snapshot.as_mut().ok_or_else(unexpected_frame)?;
+                    let last = pairs.last().map(|(key, _)| key.clone());
+                    replace_range(&mut *self.engine()?, after.as_deref(), pairs, false)?;
+                    *after = last.or(after.take());
+                }
+                ReplicationFrame::SnapshotEnd => {
+                    let (at, after) = snapshot.take().ok_or_else(unexpected_frame)?;
+                    self.finish_snapshot(at, after.as_deref())?;
+                    *position = Some(at);
+                }
+                ReplicationFrame::Op { seq, op } => {
+                    let at = position.as_mut().ok_or_else(unexpected_frame)?;
+                    self.apply(op)?;
+                    at.seq = seq;
+                }
+                ReplicationFrame::Heartbeat => {}
+                ReplicationFrame::Err(msg) => return Err(KvsErr::from_remote(msg)),
+            }
+            if let (Some(state), Some(at)) = (state, *position) {
+                if saved != Some(at) && last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                    self.checkpoint(state, &at)?;
+                    saved = Some(at);
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+        if let (Some(state), Some(at)) = (state, *position) {
+            self.checkpoint(state, &at)?;
+        }
+        Ok(())
+    }
+
+    // drop the keys after the last one of the snapshot, which the leader does not have
+    fn finish_snapshot(&self, position: Position, after: Option<&str>) -> Result<()> {
+        let mut engine = self.engine()?;
+        replace_range(&mut *engine, after, Vec::new(), true)?;
+        self.replication.reset(position)?;
+        info!("Applied snapshot at {:?}", position);
+        Ok(())
+    }
+
+    // sync the writes applied so far, so the position saved never runs ahead of them
+    fn checkpoint(&self, state: &Path, position: &Position) -> Result<()> {
+        self.engine()?.flush()?;
+        write_synced(state, &serde_json::to_vec(position)?)
+    }
+
+    fn apply(&self, op: LogOp) -> Result<()> {
+        let mut engine = self.engine()?;
+        debug!("Applying replicated {:?}", op);
+        match op.clone() {
+            LogOp::Set { key, value } => engine.set(key, value)?,
+            // replayed after a restart, the key may already be gone
+            LogOp::Remove { key } => match engine.remove(key) {
+                Ok(()) | Err(KvsErr::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+        self.replication.append(op)
+    }
+}
+
+fn load_position(path: &Path) -> Result<Option<Position>> {
+    match fs::read(path) {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// written aside, synced and renamed, so a crash leaves either the old or the new content
+fn write_synced(path: &Path, buf: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    fs::rename(tmp, path)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn remove_position(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn new_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn unexpected_frame() -> KvsErr {
+    KvsErr::StringErr("unexpected replication frame".to_owned())
+}
+
+fn lock_poisoned() -> KvsErr {
+    KvsErr::StringErr("replication log lock poisoned".to_owned())
+}
@@ -0,0 +1,405 @@
+use crate::auth::Credentials;
+use crate::engines::{EngineStats, KvEngine, Pairs};
+use crate::tls::ClientTls;
+use crate::{KvClient, KvsErr, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::thread;
+
+/// Points each server gets on the hash ring by default.
+pub const DEFAULT_VNODES: usize = 160;
+
+// pairs fetched per scan while rebalancing
+const REBALANCE_BATCH: usize = 256;
+
+/// Consistent hash ring mapping keys to server addresses. Each server owns
+/// `vnodes` points, so adding or removing one only moves about 1/n of the keys.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    ring: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize) -> HashRing {
+        HashRing {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.vnodes {
+            self.ring
+                .insert(hash(format!("{}#{}", node, i).as_bytes()), node.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, owner| owner != node);
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.ring.values().any(|owner| owner == node)
+    }
+
+    /// The server owning `key`: the first point at or after the key's hash, wrapping around.
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let h = hash(key.as_bytes());
+        self.ring
+            .range(h..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+}
+
+// FNV-1a with a final mix, stable across processes and Rust versions
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h
+}
+
+/// A client spreading keys over several `kvs-server`s with consistent hashing.
+///
+/// Multi-key operations are sent to every server involved at once. After
+/// `add_node` or `remove_node`, `rebalance` moves the keys that changed owner.
+pub struct ShardedKvClient {
+    ring: HashRing,
+    // every connected server, including the ones being drained
+    clients: BTreeMap<String, KvClient>,
+    credentials: Option<Credentials>,
+    tls: Option<ClientTls>,
+}
+
+impl ShardedKvClient {
+    pub fn connect<S: AsRef<str>>(addrs: &[S]) -> Result<ShardedKvClient> {
+        ShardedKvClient::with_vnodes(addrs, DEFAULT_VNODES)
+    }
+
+    /// Connect to servers requiring authentication, presenting `credentials` to each.
+    pub fn connect_with_auth<S: AsRef<str>>(
+        addrs: &[S],
+        credentials: Credentials,
+    ) -> Result<ShardedKvClient> {
+        ShardedKvClient::open(addrs, DEFAULT_VNODES, Some(credentials), None)
+    }
+
+    /// Connect over TLS to servers given as `host:port`, whose certificates must be
+    /// valid for `host`.
+    pub fn connect_tls<S: AsRef<str>>(addrs: &[S], tls: ClientTls) -> Result<ShardedKvClient> {
+        ShardedKvClient::open(addrs, DEFAULT_VNODES, None, Some(tls))
+    }
+
+    /// Connect over TLS and authenticate with `credentials` to each server.
+    pub fn connect_tls_with_auth<S: AsRef<str>>(
+        addrs: &[S],
+        tls: ClientTls,
+        credentials: Credentials,
+    ) -> Result<ShardedKvClient> {
+        ShardedKvClient::open(addrs, DEFAULT_VNODES, Some(credentials), Some(tls))
+    }
+
+    pub fn with_vnodes<S: AsRef<str>>(addrs: &[S], vnodes: usize) -> Result<ShardedKvClient> {
+        ShardedKvClient::open(addrs, vnodes, None, None)
+    }
+
+    fn open<S: AsRef<str>>(
+        addrs: &[S],
+        vnodes: usize,
+        credentials: Option<Credentials>,
+        tls: Option<ClientTls>,
+    ) -> Result<ShardedKvClient> {
+        let mut client = ShardedKvClient {
+            ring: HashRing::new(vnodes),
+            clients: BTreeMap::new(),
+            credentials,
+            tls,
+        };
+        for addr in addrs {
+            client.add_node(addr.as_ref())?;
+        }
+        Ok(client)
+    }
+
+    /// Servers currently owning keys.
+    pub fn nodes(&self) -> Vec<&str> {
+        self.clients
+            .keys()
+            .filter(|addr| self.ring.contains(addr))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Add a server to the ring. Keys it now owns stay where they were until `rebalance`.
+    pub fn add_node(&mut self, addr: &str) -> Result<()> {
+        if !self.clients.contains_key(addr) {
+            self.clients
+                .insert(addr.to_owned(), self.connect_node(addr)?);
+        }
+        self.ring.add(addr);
+        Ok(())
+    }
+
+    /// Take a server off the ring. It is still read from by `rebalance`, which
+    /// moves its keys to their new owners and then disconnects it.
+    pub fn remove_node(&mut self, addr: &str) -> Result<()> {
+        if !self.clients.contains_key(addr) {
+            self.clients
+                .insert(addr.to_owned(), self.connect_node(addr)?);
+        }
+        self.ring.remove(addr);
+        Ok(())
+    }
+
+    /// Move every key stored on a server other than its owner there, using plain
+    /// get/set/remove requests. Returns the number of keys moved.
+    ///
+    /// A key already on its owner was written there since the ring changed, so it is
+    /// kept and the stale copy dropped. Writes should still be stopped while it runs:
+    /// a key set or removed on its owner during the move may be overwritten, and a
+    /// key removed since the ring changed comes back.
+    pub fn rebalance(&mut self) -> Result<u64> {
+        let mut moved = 0;
+        let addrs: Vec<String> = self.clients.keys().cloned().collect();
+        for addr in addrs {
+            let mut misplaced = Vec::new();
+            let client = self.clients.get_mut(&addr).unwrap();
+            for pair in Pairs::new(client, REBALANCE_BATCH) {
+                let (key, _) = pair?;
+                if self.ring.node_for(&key) != Some(addr.as_str()) {
+                    misplaced.push(key);
+                }
+            }
+            for key in misplaced {
+                // copy before removing, so the key is never missing from both
+                let value = match self.client(&addr)?.get(key.clone())? {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let owner = self.owner(&key)?;
+                if owner.get(key.clone())?.is_none() {
+                    owner.set(key.clone(), value)?;
+                    moved += 1;
+                }
+                match self.client(&addr)?.remove(key) {
+                    Ok(()) | Err(KvsErr::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        let ring = &self.ring;
+        self.clients.retain(|addr, _| ring.contains(addr));
+        Ok(moved)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.owner(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.owner(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.owner(&key)?.remove(key)
+    }
+
+    /// Get several keys, asking every server involved in parallel.
+    /// Values are returned in the order of `keys`.
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut groups: BTreeMap<String, Vec<(usize, String)>> = BTreeMap::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            let node = self.owner_addr(&key)?;
+            groups.entry(node).or_default().push((i, key));
+        }
+        let mut values = vec![None; groups.values().map(Vec::len).sum()];
+        let fetched = self.fan_out(groups, |client, keys| {
+            keys.into_iter()
+                .map(|(i, key)| Ok((i, client.get(key)?)))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for (i, value) in fetched.into_iter().flatten() {
+            values[i] = value;
+        }
+        Ok(values)
+    }
+
+    /// Set several keys, with one batch per server, sent in parallel.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut groups: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+        for (key, value) in pairs {
+            let node = self.owner_addr(&key)?;
+            groups.entry(node).or_default().push((key, value));
+        }
+        self.fan_out(groups, |client, pairs| client.set_batch(pairs))?;
+        Ok(())
+    }
+
+    /// Return up to `limit` pairs in key order after `after`, merged from every server.
+    pub fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        let groups = self
+            .nodes()
+            .into_iter()
+            .map(|addr| (addr.to_owned(), after.clone()))
+            .collect();
+        let mut pairs: Vec<(String, String)> = self
+            .fan_out(groups, |client, after| scan_server(client, after, limit))?
+            .into_iter()
+            .flatten()
+            .collect();
+        pairs.sort();
+        pairs.truncate(limit);
+        Ok(pairs)
+    }
+
+    // run `call` on each server with its share of the work, all at once
+    fn fan_out<T, R, F>(&mut self, groups: BTreeMap<String, T>, call: F) -> Result<Vec<R>>
+    where
+        T: Send,
+        R: Send,
+        F: Fn(&mut KvClient, T) -> Result<R> + Sync,
+    {
+        let mut groups = groups;
+        let call = &call;
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .clients
+                .iter_mut()
+                .filter_map(|(addr, client)| groups.remove(addr).map(|work| (client, work)))
+                .map(|(client, work)| scope.spawn(move || call(client, work)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(KvsErr::StringErr("shard request panicked".to_owned()))
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn connect_node(&self, addr: &str) -> Result<KvClient> {
+        match (&self.credentials, &self.tls) {
+            (Some(credentials), Some(tls)) => {
+                KvClient::connect_tls_with_auth(addr, tls.clone(), credentials.clone())
+            }
+            (None, Some(tls)) => KvClient::connect_tls(addr, tls.clone()),
+            (Some(credentials), None) => KvClient::connect_with_auth(addr, credentials.clone()),
+            (None, None) => KvClient::connect(addr),
+        }
+    }
+
+    fn owner_addr(&self, key: &str) -> Result<String> {
+        self.ring
+            .node_for(key)
+            .map(str::to_owned)
+            .ok_or_else(|| KvsErr::StringErr("no servers to shard over".to_owned()))
+    }
+
+    fn owner(&mut self, key: &str) -> Result<&mut KvClient> {
+        let addr = self.owner_addr(key)?;
+        self.client(&addr)
+    }
+
+    fn client(&mut self, addr: &str) -> Result<&mut KvClient> {
+        self.clients
+            .get_mut(addr)
+            .ok_or_else(|| KvsErr::StringErr(format!("not connected to {}", addr)))
+    }
+}
+
+// up to `limit` pairs of one server, asking again while it returns fewer than
+// asked, as servers capping the size of scans do
+fn scan_server(
+    client: &mut KvClient,
+    mut after: Option<String>,
+    limit: usize,
+) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    while pairs.len() < limit {
+        let batch = client.scan(after, limit - pairs.len())?;
+        after = match batch.last() {
+            Some((key, _)) => Some(key.clone()),
+            None => break,
+        };
+        pairs.extend(batch);
+    }
+    Ok(pairs)
+}
+
+/// Lets code written against `KvEngine` run on a sharded cluster.
+impl KvEngine for ShardedKvClient {
+    fn name(&self) -> &'static str {
+        "sharded"
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        ShardedKvClient::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        ShardedKvClient::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        ShardedKvClient::remove(self, key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        // the servers persist every write before answering
+        Ok(())
+    }
+
+    fn scan(&mut self, after: Option<String>, limit: usize) -> Result<Vec<(String, String)>> {
+        ShardedKvClient::scan(self, after, limit)
+    }
+
+    fn set_batch(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.mset(pairs)
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        let groups = self
+            .nodes()
+            .into_iter()
+            .map(|addr| (addr.to_owned(), ()))
+            .collect();
+        self.fan_out(groups, |client, ()| client.compact())?;
+        Ok(())
+    }
+
+    /// Sums the statistics of every server.
+    fn stats(&mut self) -> Result<EngineStats> {
+        let groups = self
+            .nodes()
+            .into_iter()
+            .map(|addr| (addr.to_owned(), ()))
+            .collect();
+        let all = self.fan_out(groups, |client, ()| client.stats())?;
+        Ok(all
+            .into_iter()
+            .fold(EngineStats::default(), |sum, stats| EngineStats {
+                keys: sum.keys + stats.keys,
+                log_files: sum.log_files + stats.log_files,
+                uncompacted_bytes: sum.uncompacted_bytes + stats.uncompacted_bytes,
+                compactions: sum.compactions + stats.compactions,
+                compaction_seconds: sum.compaction_seconds + stats.compaction_seconds,
+                disk_bytes: sum.disk_bytes + stats.disk_bytes,
+            }))
+    }
+
+    fn backup_to(&mut self, _dir: &Path) -> Result<()> {
+        Err(KvsErr::StringErr(
+            "back up each server of a sharded cluster on its own".to_owned(),
+        ))
+    }
+}
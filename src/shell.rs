@@ -0,0 +1,214 @@
+use crate::engines::KvEngine;
+use crate::{KvsErr, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+
+const COMMANDS: &[&str] = &["get", "set", "rm", "scan", "help", "exit", "quit"];
+const DEFAULT_SCAN_LIMIT: usize = 20;
+const HELP: &str = "\
+get KEY                          Get the string value of a given string key
+set KEY VALUE                    Set the value of a string key to a string
+rm KEY                           Remove a given key
+scan [--after KEY] [--limit N]   List N key/value pairs (default 20) after the key KEY
+help                             Show this message
+exit | quit                      Leave the shell
+Use double quotes for keys or values containing spaces.";
+
+/// Interactive prompt running commands against any `KvEngine`.
+pub struct Shell<E: KvEngine> {
+    engine: E,
+    prompt: String,
+}
+
+enum Line {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+    Scan(Option<String>, usize),
+    Help,
+    Exit,
+    Empty,
+}
+
+// completes command names at the start of the line
+struct ShellHelper;
+
+impl<E: KvEngine> Shell<E> {
+    pub fn new(engine: E, prompt: impl Into<String>) -> Self {
+        Shell {
+            engine,
+            prompt: prompt.into(),
+        }
+    }
+
+    /// Read commands until `exit` or end of input.
+    pub fn run(&mut self) -> Result<()> {
+        let mut editor: Editor<ShellHelper, DefaultHistory> =
+            Editor::new().map_err(readline_err)?;
+        editor.set_helper(Some(ShellHelper));
+        loop {
+            let line = match editor.readline(&self.prompt) {
+                Ok(line) => line,
+                // Ctrl-C drops the current line, Ctrl-D leaves
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(readline_err(e)),
+            };
+            if !line.trim().is_empty() {
+                editor
+                    .add_history_entry(line.as_str())
+                    .map_err(readline_err)?;
+            }
+            match parse(&line) {
+                Ok(Line::Exit) => break,
+                Ok(line) => {
+                    if let Err(e) = self.execute(line) {
+                        println!("{}", e);
+                    }
+                }
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, line: Line) -> Result<()> {
+        match line {
+            Line::Get(key) => match self.engine.get(key)? {
+                Some(value) => println!("{}", value),
+                None => println!("Key not found"),
+            },
+            Line::Set(key, value) => self.engine.set(key, value)?,
+            Line::Remove(key) => self.engine.remove(key)?,
+            Line::Scan(after, limit) => {
+                for (key, value) in self.engine.scan(after, limit)? {
+                    println!("{}: {}", key, value);
+                }
+            }
+            Line::Help => println!("{}", HELP),
+            Line::Exit | Line::Empty => {}
+        }
+        Ok(())
+    }
+}
+
+fn parse(line: &str) -> std::result::Result<Line, String> {
+    let mut args = split(line)?.into_iter();
+    let command = match args.next() {
+        Some(command) => command,
+        None => return Ok(Line::Empty),
+    };
+    let args: Vec<String> = args.collect();
+    let expect = |n: usize, usage: &str| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("usage: {}", usage))
+        }
+    };
+    match command.as_str() {
+        "get" => expect(1, "get KEY").map(|_| Line::Get(args[0].clone())),
+        "set" => expect(2, "set KEY VALUE").map(|_| Line::Set(args[0].clone(), args[1].clone())),
+        "rm" => expect(1, "rm KEY").map(|_| Line::Remove(args[0].clone())),
+        "scan" => {
+            let usage = || "usage: scan [--after KEY] [--limit N]".to_owned();
+            let mut after = None;
+            let mut limit = DEFAULT_SCAN_LIMIT;
+            let mut args = args.iter();
+            while let Some(flag) = args.next() {
+                let value = args.next().ok_or_else(usage)?;
+                match flag.as_str() {
+                    "--after" => after = Some(value.clone()),
+                    "--limit" => limit = value.parse().map_err(|_| usage())?,
+                    _ => return Err(usage()),
+                }
+            }
+            Ok(Line::Scan(after, limit))
+        }
+        "help" => Ok(Line::Help),
+        "exit" | "quit" => Ok(Line::Exit),
+        other => Err(format!("unknown command: {}, type help for usage", other)),
+    }
+}
+
+// split a line on whitespace, keeping double-quoted text together
+fn split(line: &str) -> std::result::Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => arg.push(escaped),
+                        None => return Err("unterminated escape".to_owned()),
+                    },
+                    Some(c) => arg.push(c),
+                    None => return Err("unterminated quote".to_owned()),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn readline_err(err: ReadlineError) -> KvsErr {
+    match err {
+        ReadlineError::Io(e) => KvsErr::Io(e),
+        e => KvsErr::StringErr(e.to_string()),
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line.len() - line.trim_start().len();
+        let word = &line[start..pos.max(start)];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
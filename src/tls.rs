@@ -0,0 +1,253 @@
+//! TLS for the connections between `KvClient` and `KvServer`.
+//!
+//! Certificates and keys are read from PEM files. A server given a client CA
+//! only accepts clients presenting a certificate signed by it (mutual TLS).
+
+use crate::{KvsErr, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConnection, RootCertStore, ServerConnection};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Certificates a client trusts, and optionally the certificate it presents.
+#[derive(Debug, Clone)]
+pub struct ClientTls {
+    config: Arc<rustls::ClientConfig>,
+}
+
+impl ClientTls {
+    /// Trust servers whose certificate is signed by a CA of the PEM file `ca`.
+    pub fn new(ca: impl AsRef<Path>) -> Result<ClientTls> {
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots(ca.as_ref())?)
+            .with_no_client_auth();
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Like `new`, also presenting `cert` to servers requiring client certificates.
+    pub fn with_identity(
+        ca: impl AsRef<Path>,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<ClientTls> {
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots(ca.as_ref())?)
+            .with_client_auth_cert(certs(cert.as_ref())?, private_key(key.as_ref())?)?;
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+/// The certificate a server presents, and optionally the CA its clients must use.
+#[derive(Debug, Clone)]
+pub struct ServerTls {
+    config: Arc<rustls::ServerConfig>,
+}
+
+impl ServerTls {
+    pub fn new(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<ServerTls> {
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs(cert.as_ref())?, private_key(key.as_ref())?)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Like `new`, only accepting clients with a certificate signed by a CA of `client_ca`.
+    pub fn with_client_auth(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: impl AsRef<Path>,
+    ) -> Result<ServerTls> {
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots(client_ca.as_ref())?))
+            .build()
+            .map_err(|e| KvsErr::StringErr(format!("invalid client CA: {}", e)))?;
+        let config = rustls::ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs(cert.as_ref())?, private_key(key.as_ref())?)?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsErr::StringErr(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| KvsErr::StringErr(format!("no private key in {}", path.display())))
+}
+
+fn roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// A TCP connection, encrypted or not. Clones share the connection, so one
+/// can be read while another is written to.
+#[derive(Clone)]
+pub(crate) struct Stream {
+    tcp: Arc<TcpStream>,
+    tls: Option<Arc<Mutex<Session>>>,
+}
+
+struct Session {
+    conn: Conn,
+    tcp: Arc<TcpStream>,
+}
+
+enum Conn {
+    Client(ClientConnection),
+    Server(ServerConnection),
+}
+
+// tell the peer the connection ends on purpose, rather than being cut
+impl Drop for Session {
+    fn drop(&mut self) {
+        let mut tcp = &*self.tcp;
+        let _ = match &mut self.conn {
+            Conn::Client(conn) => {
+                conn.send_close_notify();
+                conn.write_tls(&mut tcp)
+            }
+            Conn::Server(conn) => {
+                conn.send_close_notify();
+                conn.write_tls(&mut tcp)
+            }
+        };
+    }
+}
+
+impl Stream {
+    pub(crate) fn connect<A: ToSocketAddrs>(addr: A) -> Result<Stream> {
+        Ok(Stream::plain(TcpStream::connect(addr)?))
+    }
+
+    /// Connect to `addr`, given as `host:port`, checking the server's certificate
+    /// is valid for `host`.
+    pub(crate) fn connect_tls(addr: &str, tls: &ClientTls) -> Result<Stream> {
+        let host = match addr.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => addr,
+        };
+        let name = ServerName::try_from(host.to_owned())
+            .map_err(|_| KvsErr::StringErr(format!("invalid server name: {}", host)))?;
+        let conn = ClientConnection::new(Arc::clone(&tls.config), name)?;
+        Ok(Stream::secured(
+            TcpStream::connect(addr)?,
+            Conn::Client(conn),
+        ))
+    }
+
+    /// Wrap an accepted connection, encrypting it when `tls` is given.
+    /// The handshake happens on first use.
+    pub(crate) fn accept(tcp: TcpStream, tls: Option<&ServerTls>) -> Result<Stream> {
+        match tls {
+            Some(tls) => {
+                let conn = ServerConnection::new(Arc::clone(&tls.config))?;
+                Ok(Stream::secured(tcp, Conn::Server(conn)))
+            }
+            None => Ok(Stream::plain(tcp)),
+        }
+    }
+
+    fn plain(tcp: TcpStream) -> Stream {
+        Stream {
+            tcp: Arc::new(tcp),
+            tls: None,
+        }
+    }
+
+    fn secured(tcp: TcpStream, conn: Conn) -> Stream {
+        let tcp = Arc::new(tcp);
+        Stream {
+            tls: Some(Arc::new(Mutex::new(Session {
+                conn,
+                tcp: Arc::clone(&tcp),
+            }))),
+            tcp,
+        }
+    }
+
+    /// The underlying socket, to set timeouts on.
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Stop writing to the connection, telling a TLS peer first.
+    pub(crate) fn shutdown(mut self) -> io::Result<()> {
+        // the session says goodbye when its last handle is dropped
+        drop(self.tls.take());
+        self.tcp.shutdown(Shutdown::Write)
+    }
+
+    fn session(tls: &Mutex<Session>) -> io::Result<MutexGuard<'_, Session>> {
+        tls.lock()
+            .map_err(|_| io::Error::other("TLS session lock poisoned"))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return (&*self.tcp).read(buf),
+        };
+        let mut session = Stream::session(tls)?;
+        let mut tcp = &*self.tcp;
+        match &mut session.conn {
+            Conn::Client(conn) => rustls::Stream::new(conn, &mut tcp).read(buf),
+            Conn::Server(conn) => rustls::Stream::new(conn, &mut tcp).read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return (&*self.tcp).write(buf),
+        };
+        let mut session = Stream::session(tls)?;
+        let mut tcp = &*self.tcp;
+        match &mut session.conn {
+            Conn::Client(conn) => rustls::Stream::new(conn, &mut tcp).write(buf),
+            Conn::Server(conn) => rustls::Stream::new(conn, &mut tcp).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let tls = match &self.tls {
+            Some(tls) => tls,
+            None => return (&*self.tcp).flush(),
+        };
+        let mut session = Stream::session(tls)?;
+        let mut tcp = &*self.tcp;
+        match &mut session.conn {
+            Conn::Client(conn) => rustls::Stream::new(conn, &mut tcp).flush(),
+            Conn::Server(conn) => rustls::Stream::new(conn, &mut tcp).flush(),
+        }
+    }
+}
@@ -0,0 +1,321 @@
+mod common;
+
+use common::{start, Node};
+use kvs::auth::{hash_password, Acl, AclFile, Auth, Credentials, Users};
+use kvs::{KvClient, KvsErr, Result, ServerConfig};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn token(token: &str) -> Credentials {
+    Credentials::Token(token.to_owned())
+}
+
+fn password(user: &str, password: &str) -> Credentials {
+    Credentials::Password {
+        user: user.to_owned(),
+        password: password.to_owned(),
+    }
+}
+
+// Without the right token, nothing but the handshake is served
+#[test]
+fn token_auth() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let config = ServerConfig {
+        auth: Some(Auth::Token("secret".to_owned())),
+        ..ServerConfig::default()
+    };
+    let node = start(temp_dir.path(), "127.0.0.1:4060", config)?;
+
+    let mut client = KvClient::connect("127.0.0.1:4060")?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsErr::Unauthenticated)
+    ));
+    // the server hangs up after refusing
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(matches!(
+        KvClient::connect_with_auth("127.0.0.1:4060", token("wrong")),
+        Err(KvsErr::AuthFailed)
+    ));
+    assert!(matches!(
+        KvClient::connect_with_auth("127.0.0.1:4060", password("secret", "secret")),
+        Err(KvsErr::AuthFailed)
+    ));
+
+    let mut client = KvClient::connect_with_auth("127.0.0.1:4060", token("secret"))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    node.stop()
+}
+
+// Users log in with the password matching their salted hash
+#[test]
+fn password_auth() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let users_file = temp_dir.path().join("users");
+    fs::write(
+        &users_file,
+        format!(
+            "# kvs users\n{}\n\n{}\n",
+            hash_password("alice", "wonderland")?,
+            hash_password("bob", "builder")?
+        ),
+    )?;
+    let users = Users::load(&users_file)?;
+    assert!(users.verify("alice", "wonderland"));
+    assert!(!users.verify("alice", "builder"));
+    assert!(!users.verify("carol", "wonderland"));
+    // the same password gets a different salt every time
+    assert_ne!(
+        hash_password("alice", "wonderland")?,
+        hash_password("alice", "wonderland")?
+    );
+
+    let config = ServerConfig {
+        auth: Some(Auth::Users(users)),
+        ..ServerConfig::default()
+    };
+    let node = start(&temp_dir.path().join("data"), "127.0.0.1:4061", config)?;
+    for credentials in &[
+        password("alice", "builder"),
+        password("carol", "wonderland"),
+        token("wonderland"),
+    ] {
+        assert!(matches!(
+            KvClient::connect_with_auth("127.0.0.1:4061", credentials.clone()),
+            Err(KvsErr::AuthFailed)
+        ));
+    }
+    let mut alice = KvClient::connect_with_auth("127.0.0.1:4061", password("alice", "wonderland"))?;
+    let mut bob = KvClient::connect_with_auth("127.0.0.1:4061", password("bob", "builder"))?;
+    alice.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(bob.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop((alice, bob));
+    node.stop()?;
+
+    // the line records how many rounds its hash took
+    let line = hash_password("alice", "wonderland")?;
+    assert_eq!(line.split(':').nth(1), Some("100000"));
+    fs::write(&users_file, "alice:nothex:00\n")?;
+    assert!(Users::load(&users_file).is_err());
+    fs::write(&users_file, "alice:0:00:00\n")?;
+    assert!(Users::load(&users_file).is_err());
+    Ok(())
+}
+
+// A replica presents its credentials to a leader requiring them
+#[test]
+fn replica_authenticates_to_leader() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start(
+        leader_dir.path(),
+        "127.0.0.1:4062",
+        ServerConfig {
+            auth: Some(Auth::Token("secret".to_owned())),
+            ..ServerConfig::default()
+        },
+    )?;
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4063",
+        ServerConfig {
+            auth: Some(Auth::Token("secret".to_owned())),
+            replica_of: Some("127.0.0.1:4062".to_owned()),
+            leader_credentials: Some(token("secret")),
+            ..ServerConfig::default()
+        },
+    )?;
+
+    let mut client = KvClient::connect_with_auth("127.0.0.1:4062", token("secret"))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let mut replica = KvClient::connect_with_auth("127.0.0.1:4063", token("secret"))?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while replica.get("key1".to_owned())?.is_none() {
+        assert!(Instant::now() < deadline, "replica did not catch up");
+        thread::sleep(Duration::from_millis(50));
+    }
+    drop((client, replica));
+    follower.stop()?;
+    leader.stop()
+}
+
+fn acl_server(dir: &Path, addr: &'static str, acl: &str) -> Result<Node> {
+    let users_file = dir.join("users");
+    fs::write(
+        &users_file,
+        format!(
+            "{}\n{}\n",
+            hash_password("alice", "a")?,
+            hash_password("bob", "b")?
+        ),
+    )?;
+    let acl_file = dir.join("acl.toml");
+    fs::write(&acl_file, acl)?;
+    let config = ServerConfig {
+        auth: Some(Auth::Users(Users::load(&users_file)?)),
+        acl: Some(Arc::new(AclFile::open(&acl_file)?)),
+        ..ServerConfig::default()
+    };
+    start(dir, addr, config)
+}
+
+fn denied<T>(result: Result<T>) -> bool {
+    matches!(result, Err(KvsErr::PermissionDenied(_)))
+}
+
+// Users only send the requests their rules allow, on the keys they allow
+#[test]
+fn acl_restricts_commands_and_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let acl = r#"
+        [[rule]]
+        user = "alice"
+        commands = ["get", "set", "rm", "scan", "admin"]
+
+        [[rule]]
+        user = "bob"
+        commands = ["get", "set"]
+        prefixes = ["bob/"]
+
+        [[rule]]
+        user = "*"
+        commands = ["get"]
+        prefixes = ["public/"]
+    "#;
+    let node = acl_server(temp_dir.path(), "127.0.0.1:4065", acl)?;
+
+    let mut alice = KvClient::connect_with_auth("127.0.0.1:4065", password("alice", "a"))?;
+    alice.set("public/motd".to_owned(), "hello".to_owned())?;
+    alice.set("alice/key".to_owned(), "a".to_owned())?;
+    alice.info()?;
+
+    let mut bob = KvClient::connect_with_auth("127.0.0.1:4065", password("bob", "b"))?;
+    bob.set("bob/key".to_owned(), "b".to_owned())?;
+    assert_eq!(bob.get("bob/key".to_owned())?, Some("b".to_owned()));
+    assert_eq!(bob.get("public/motd".to_owned())?, Some("hello".to_owned()));
+    assert!(denied(bob.get("alice/key".to_owned())));
+    assert!(denied(bob.set("alice/key".to_owned(), "b".to_owned())));
+    assert!(denied(bob.remove("bob/key".to_owned())));
+    assert!(denied(bob.scan(None, 10)));
+    assert!(denied(bob.info()));
+    assert!(denied(bob.set_batch(vec![
+        ("bob/other".to_owned(), "b".to_owned()),
+        ("alice/other".to_owned(), "b".to_owned()),
+    ])));
+    // the connection stays usable after a refusal
+    assert_eq!(bob.get("bob/other".to_owned())?, None);
+    assert_eq!(alice.get("alice/key".to_owned())?, Some("a".to_owned()));
+
+    drop(alice);
+    drop(bob);
+    node.stop()
+}
+
+// Scans only return the keys under the prefixes the user may scan, a page at a time
+#[test]
+fn acl_filters_scan() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let acl = r#"
+        [[rule]]
+        user = "alice"
+        commands = ["set"]
+
+        [[rule]]
+        user = "bob"
+        commands = ["scan"]
+        prefixes = ["d", "b/1", "b/"]
+    "#;
+    let node = acl_server(temp_dir.path(), "127.0.0.1:4066", acl)?;
+
+    let mut alice = KvClient::connect_with_auth("127.0.0.1:4066", password("alice", "a"))?;
+    for i in 0..20 {
+        alice.set(format!("a/{:02}", i), "a".to_owned())?;
+        alice.set(format!("b/{:02}", i), "b".to_owned())?;
+        alice.set(format!("c/{:02}", i), "c".to_owned())?;
+    }
+    for key in &["b", "d", "d/x", "dz", "e"] {
+        alice.set(key.to_string(), "x".to_owned())?;
+    }
+
+    let mut bob = KvClient::connect_with_auth("127.0.0.1:4066", password("bob", "b"))?;
+    let mut keys = Vec::new();
+    let mut after = None;
+    loop {
+        let page = bob.scan(after, 7)?;
+        assert!(page.len() <= 7);
+        if page.is_empty() {
+            break;
+        }
+        after = page.last().map(|(key, _)| key.clone());
+        keys.extend(page.into_iter().map(|(key, _)| key));
+    }
+    let mut expected: Vec<String> = (0..20).map(|i| format!("b/{:02}", i)).collect();
+    expected.extend(vec!["d".to_owned(), "d/x".to_owned(), "dz".to_owned()]);
+    assert_eq!(keys, expected);
+    assert_eq!(
+        bob.scan(Some("c".to_owned()), 2)?,
+        vec![
+            ("d".to_owned(), "x".to_owned()),
+            ("d/x".to_owned(), "x".to_owned())
+        ]
+    );
+
+    drop(alice);
+    drop(bob);
+    node.stop()
+}
+
+// Admin requests name no keys, so an ACL may not grant them on prefixes
+#[test]
+fn acl_rejects_admin_prefixes() {
+    let acl = r#"
+        [[rule]]
+        user = "alice"
+        commands = ["get", "admin"]
+        prefixes = ["alice/"]
+    "#;
+    assert!(Acl::parse(acl).is_err());
+    assert!(Acl::parse(&acl.replace(", \"admin\"", "")).is_ok());
+}
+
+// Changes to the ACL file apply without restarting the server
+#[test]
+fn acl_reloads() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let acl = r#"
+        [[rule]]
+        user = "bob"
+        commands = ["get"]
+    "#;
+    let node = acl_server(temp_dir.path(), "127.0.0.1:4067", acl)?;
+
+    let mut bob = KvClient::connect_with_auth("127.0.0.1:4067", password("bob", "b"))?;
+    assert!(denied(bob.set("key1".to_owned(), "value1".to_owned())));
+
+    let acl = r#"
+        [[rule]]
+        user = "bob"
+        commands = ["get", "set"]
+    "#;
+    fs::write(temp_dir.path().join("acl.toml"), acl)?;
+    let start = Instant::now();
+    while denied(bob.set("key1".to_owned(), "value1".to_owned())) {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(bob.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a broken file leaves the last good rules in place
+    fs::write(temp_dir.path().join("acl.toml"), "[[rule]\n")?;
+    thread::sleep(Duration::from_millis(1500));
+    bob.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(bob);
+    node.stop()
+}
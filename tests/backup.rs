@@ -0,0 +1,225 @@
+mod common;
+
+use common::{all_pairs, fill};
+use kvs::backup;
+use kvs::bulk;
+use kvs::engines::{fsck, BackupManifest, Keyring, SledKvsEngine};
+use kvs::{KvClient, KvEngine, KvStore, Result, ServerConfig};
+use std::fs;
+use tempfile::TempDir;
+
+// A backup taken while the store is open spans closed and active generations
+#[test]
+fn kvs_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    fill(&mut KvStore::open(&data)?, 100, "old")?;
+    let mut store = KvStore::open(&data)?;
+    store.set("key0100".to_owned(), "value100".to_owned())?;
+
+    store.backup_to(&backup_dir)?;
+    let expected = bulk::digest(&mut store)?;
+    store.set("key0101".to_owned(), "value101".to_owned())?;
+    assert!(store.backup_to(&backup_dir).is_err());
+    drop(store);
+
+    assert!(fsck(&backup_dir, false)?.is_clean());
+    assert_eq!(backup::restore(&backup_dir, &data, None)?, expected);
+    let mut store = KvStore::open(&data)?;
+    assert_eq!(
+        store.get("key0100".to_owned())?,
+        Some("value100".to_owned())
+    );
+    assert_eq!(store.get("key0101".to_owned())?, None);
+    assert_eq!(store.get("key0007".to_owned())?, None);
+    Ok(())
+}
+
+// Sled data is exported into the backup directory
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    let mut engine = SledKvsEngine::new(sled::open(&data)?);
+    fill(&mut engine, 100, "value")?;
+    engine.backup_to(&backup_dir)?;
+    let expected = bulk::digest(&mut engine)?;
+    drop(engine);
+
+    let restored = temp_dir.path().join("restored");
+    assert_eq!(backup::restore(&backup_dir, &restored, None)?, expected);
+    let mut engine = SledKvsEngine::new(sled::open(&restored)?);
+    assert_eq!(engine.get("key0008".to_owned())?, Some("value8".to_owned()));
+    Ok(())
+}
+
+// An encrypted store is checked and opened with its keys on restore
+#[test]
+fn encrypted_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    let keyring = Keyring::new(vec![vec![7; 32]])?;
+    let mut store = KvStore::open_encrypted(&data, keyring.clone())?;
+    fill(&mut store, 100, "value")?;
+    store.backup_to(&backup_dir)?;
+    let expected = bulk::digest(&mut store)?;
+    drop(store);
+
+    let restored = temp_dir.path().join("restored");
+    assert!(backup::restore(&backup_dir, &restored, None).is_err());
+    assert!(!restored.exists());
+    assert_eq!(
+        backup::restore(&backup_dir, &restored, Some(&keyring))?,
+        expected
+    );
+    let mut store = KvStore::open_encrypted(&restored, keyring)?;
+    assert_eq!(store.get("key0008".to_owned())?, Some("value8".to_owned()));
+    assert_eq!(store.get("key0007".to_owned())?, None);
+    Ok(())
+}
+
+// A running server writes the backup when asked by a client
+#[test]
+fn remote_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    let data = temp_dir.path().join("data");
+    let node = common::start(&data, "127.0.0.1:4021", ServerConfig::default())?;
+
+    let mut client = KvClient::connect("127.0.0.1:4021")?;
+    fill(&mut client, 100, "value")?;
+    client.backup(&backup_dir)?;
+    client.set("key0000".to_owned(), "changed".to_owned())?;
+    assert!(client.backup(&backup_dir).is_err());
+    drop(client);
+    node.stop()?;
+
+    let mut store = KvStore::open(&backup_dir)?;
+    assert_eq!(store.get("key0000".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key0099".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// A damaged backup is rejected and the data directory is left as it was
+#[test]
+fn restore_rejects_damaged_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backup");
+    let mut store = KvStore::open(&data)?;
+    fill(&mut store, 100, "value")?;
+    store.backup_to(&backup_dir)?;
+    store.set("key0000".to_owned(), "newer".to_owned())?;
+    drop(store);
+
+    let log = backup_dir.join("1.log");
+    let mut content = fs::read(&log)?;
+    content[40] = b'#';
+    fs::write(&log, &content)?;
+    assert!(backup::restore(&backup_dir, &data, None).is_err());
+    assert!(backup::restore(temp_dir.path().join("missing"), &data, None).is_err());
+
+    let mut store = KvStore::open(&data)?;
+    assert_eq!(store.get("key0000".to_owned())?, Some("newer".to_owned()));
+    assert!(!temp_dir.path().join("data.restoring").exists());
+    Ok(())
+}
+
+// Increments ship only new bytes, and a restored chain matches the source
+#[test]
+fn incremental_backup_chain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data = temp_dir.path().join("data");
+    let (base, inc1, inc2) = (
+        temp_dir.path().join("base"),
+        temp_dir.path().join("inc1"),
+        temp_dir.path().join("inc2"),
+    );
+    fill(&mut KvStore::open(&data)?, 100, "old")?;
+    let mut store = KvStore::open(&data)?;
+    store.set("key0100".to_owned(), "value100".to_owned())?;
+    store.backup_to(&base)?;
+
+    store.set("key0101".to_owned(), "value101".to_owned())?;
+    store.backup_incremental(&inc1, &base)?;
+    let manifest = BackupManifest::load(&inc1)?;
+    assert_eq!(manifest.parent, Some(BackupManifest::load(&base)?.id));
+    assert_eq!(manifest.segments.len(), 1);
+    assert!(manifest.segments[0].start > 0);
+
+    // overwrite enough to compact, which replaces every generation
+    let big = "x".repeat(4096);
+    for _ in 0..300 {
+        store.set("key0000".to_owned(), big.clone())?;
+    }
+    store.remove("key0001".to_owned())?;
+    store.backup_incremental(&inc2, &inc1)?;
+    let expected = bulk::digest(&mut store)?;
+    let pairs = all_pairs(&mut store)?;
+    store.set("key0102".to_owned(), "value102".to_owned())?;
+    drop(store);
+
+    let restored = temp_dir.path().join("restored");
+    assert_eq!(
+        backup::restore_chain(&[&base, &inc1, &inc2], &restored, None)?,
+        expected
+    );
+    let mut store = KvStore::open(&restored)?;
+    assert_eq!(all_pairs(&mut store)?, pairs);
+    assert_eq!(
+        store.get("key0101".to_owned())?,
+        Some("value101".to_owned())
+    );
+    assert_eq!(store.get("key0102".to_owned())?, None);
+    Ok(())
+}
+
+// A chain with a missing or misplaced link is rejected
+#[test]
+fn incremental_chain_out_of_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (base, inc1, inc2) = (
+        temp_dir.path().join("base"),
+        temp_dir.path().join("inc1"),
+        temp_dir.path().join("inc2"),
+    );
+    let mut store = KvStore::open(temp_dir.path().join("data"))?;
+    fill(&mut store, 100, "value")?;
+    store.backup_to(&base)?;
+    store.set("key0100".to_owned(), "value100".to_owned())?;
+    store.backup_incremental(&inc1, &base)?;
+    store.set("key0101".to_owned(), "value101".to_owned())?;
+    store.backup_incremental(&inc2, &inc1)?;
+
+    let restored = temp_dir.path().join("restored");
+    assert!(backup::restore(&inc1, &restored, None).is_err());
+    assert!(backup::restore_chain(&[&base, &inc2], &restored, None).is_err());
+    assert!(backup::restore_chain(&[&base, &inc2, &inc1], &restored, None).is_err());
+    assert!(!restored.exists());
+
+    backup::restore_chain(&[&base, &inc1], &restored, None)?;
+    let mut store = KvStore::open(&restored)?;
+    assert_eq!(
+        store.get("key0100".to_owned())?,
+        Some("value100".to_owned())
+    );
+    assert_eq!(store.get("key0101".to_owned())?, None);
+    Ok(())
+}
+
+// Engines without log generations refuse incremental backups
+#[test]
+fn sled_incremental_unsupported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let base = temp_dir.path().join("base");
+    let mut engine = SledKvsEngine::new(sled::open(temp_dir.path().join("data"))?);
+    fill(&mut engine, 100, "value")?;
+    engine.backup_to(&base)?;
+    assert!(engine
+        .backup_incremental(&temp_dir.path().join("inc"), &base)
+        .is_err());
+    Ok(())
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use common::all_pairs;
+use kvs::bulk::{self, Format};
+use kvs::engines::{MemoryEngine, SledKvsEngine};
+use kvs::{KvClient, KvEngine, KvStore, Result, ServerConfig};
+use tempfile::TempDir;
+
+// `common::fill`, with a pair that CSV has to quote
+fn fill<E: KvEngine>(engine: &mut E) -> Result<()> {
+    common::fill(engine, 500, "value")?;
+    engine.set(
+        "comma,key".to_owned(),
+        "quoted \"value\"\nwith newline".to_owned(),
+    )
+}
+
+fn round_trip(format: Format) -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut src = KvStore::open(src_dir.path())?;
+    fill(&mut src)?;
+
+    let mut dump = Vec::new();
+    assert_eq!(bulk::export(&mut src, &mut dump, format)?, 500);
+
+    let mut dst = SledKvsEngine::new(sled::open(dst_dir.path())?);
+    let mut batches = 0;
+    let count = bulk::import(&mut dst, dump.as_slice(), format, 100, |_| batches += 1)?;
+    assert_eq!(count, 500);
+    assert_eq!(batches, 5);
+    assert_eq!(all_pairs(&mut src)?, all_pairs(&mut dst)?);
+    Ok(())
+}
+
+// Pairs exported as JSON Lines should load into another engine unchanged
+#[test]
+fn jsonl_round_trip() -> Result<()> {
+    round_trip(Format::Jsonl)
+}
+
+// Pairs exported as CSV should load into another engine unchanged
+#[test]
+fn csv_round_trip() -> Result<()> {
+    round_trip(Format::Csv)
+}
+
+// Export writes pairs in key order, one object per line
+#[test]
+fn export_is_sorted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    let mut dump = Vec::new();
+    bulk::export(&mut store, &mut dump, Format::Jsonl)?;
+    assert_eq!(
+        String::from_utf8(dump)?,
+        "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n{\"key\":\"c\",\"value\":\"3\"}\n"
+    );
+
+    let mut dump = Vec::new();
+    bulk::export(&mut store, &mut dump, Format::Csv)?;
+    assert_eq!(String::from_utf8(dump)?, "key,value\na,1\nb,2\nc,3\n");
+    Ok(())
+}
+
+// The same export and import should work against a running server
+#[test]
+fn remote_round_trip() -> Result<()> {
+    let server_dir = TempDir::new().expect("unable to create temporary working directory");
+    let node = common::start(server_dir.path(), "127.0.0.1:4020", ServerConfig::default())?;
+
+    let local_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut local = KvStore::open(local_dir.path())?;
+    fill(&mut local)?;
+    let mut dump = Vec::new();
+    bulk::export(&mut local, &mut dump, Format::Jsonl)?;
+
+    let mut client = KvClient::connect("127.0.0.1:4020")?;
+    assert_eq!(
+        bulk::import(&mut client, dump.as_slice(), Format::Jsonl, 64, |_| {})?,
+        500
+    );
+    let mut remote_dump = Vec::new();
+    bulk::export(&mut client, &mut remote_dump, Format::Jsonl)?;
+    assert_eq!(dump, remote_dump);
+
+    drop(client);
+    node.stop()
+}
+
+// Migration should copy every pair and refuse a destination that already holds data
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut src = KvStore::open(src_dir.path())?;
+    fill(&mut src)?;
+
+    let mut dst = SledKvsEngine::new(sled::open(dst_dir.path())?);
+    let digest = bulk::migrate(&mut src, &mut dst, 64)?;
+    assert_eq!(digest.count, 500);
+    assert_eq!(digest, bulk::digest(&mut dst)?);
+    assert_eq!(all_pairs(&mut src)?, all_pairs(&mut dst)?);
+
+    assert!(bulk::migrate(&mut src, &mut dst, 64).is_err());
+    Ok(())
+}
+
+// The checksum is a fixed function of the pairs, comparable across builds
+#[test]
+fn digest_is_stable() -> Result<()> {
+    let mut engine = MemoryEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    let digest = bulk::digest(&mut engine)?;
+    assert_eq!(digest.count, 2);
+    assert_eq!(digest.checksum, 0xddda_3030_8ce5_500c);
+    Ok(())
+}
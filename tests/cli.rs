@@ -1,6 +1,10 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use predicates::boolean::PredicateBooleanExt;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +216,312 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server` should exit cleanly after SIGTERM
+#[cfg(unix)]
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .args(["--shutdown-timeout", "1000"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+}
+
+// `kvs shell` should run several commands against the same store
+#[test]
+fn cli_shell() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value1\nset \"key 2\" \"value 2\"\nget key1\nget \"key 2\"\nrm key1\nget key1\nrm key1\nunknown\nset 7 seven\nscan --after 7 --limit 1\nscan 7\n")
+        .assert()
+        .success()
+        .stdout(
+            "value1\nvalue 2\nKey not found\nKey not found\nunknown command: unknown, type help for usage\nkey 2: value 2\nusage: scan [--after KEY] [--limit N]\n",
+        );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key 2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value 2\n");
+}
+
+// `kvs-client shell` should keep one connection open for the whole session
+#[test]
+fn cli_client_shell() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shell", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value1\nget key1\nrm key1\nget key1\nexit\nget key1\n")
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs export` output should load into another directory with `kvs import`
+#[test]
+fn cli_export_import() {
+    let src_dir = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    for (key, value) in &[("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", key, value])
+            .current_dir(&src_dir)
+            .assert()
+            .success();
+    }
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv"])
+        .current_dir(&src_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"key,value\nkey1,value1\nkey2,value2\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv", "--engine", "sled"])
+        .current_dir(&dst_dir)
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success()
+        .stderr(contains("Imported 2 pairs"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--engine", "sled"])
+        .current_dir(&dst_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n",
+        );
+}
+
+// start `kvs-server` on `dir` with `engine`, run `kvs-client` with each of
+// `requests`, and return their outputs
+fn with_server(dir: &Path, engine: &str, addr: &str, requests: &[&[&str]]) -> Vec<String> {
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let outputs = requests
+        .iter()
+        .map(|args| {
+            let output = Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(*args)
+                .args(["--addr", addr])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        })
+        .collect();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    outputs
+}
+
+// `kvs migrate` should move the data of a kvs-server directory to another engine
+// and write the engine marker, where kvs-server finds them
+#[test]
+fn cli_migrate() {
+    let src_dir = TempDir::new().unwrap();
+    let sled_dir = TempDir::new().unwrap();
+    let dst_dir = TempDir::new().unwrap();
+    with_server(
+        src_dir.path(),
+        "kvs",
+        "127.0.0.1:4079",
+        &[&["set", "key1", "value1"], &["set", "key2", "value2"]],
+    );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(src_dir.path())
+        .arg(sled_dir.path())
+        .assert()
+        .success()
+        .stderr(contains("Migrated 2 pairs"));
+    assert_eq!(
+        fs::read_to_string(sled_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .arg(sled_dir.path())
+        .arg(dst_dir.path())
+        .assert()
+        .success()
+        .stderr(contains("Migrated 2 pairs"));
+    let outputs = with_server(
+        dst_dir.path(),
+        "kvs",
+        "127.0.0.1:4080",
+        &[&["get", "key1"], &["get", "key2"]],
+    );
+    assert_eq!(outputs, vec!["value1\n", "value2\n"]);
+
+    // the source marker must agree with --from
+    fs::write(src_dir.path().join("engine"), "sled").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .arg(src_dir.path())
+        .arg(TempDir::new().unwrap().path())
+        .assert()
+        .failure();
+}
+
+// `kvs backup` followed by `kvs restore` should bring back the backed up data
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let backup = temp_dir.path().join("backup");
+    fs::create_dir(&data).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&data)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&backup)
+        .current_dir(&data)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&data)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&backup)
+        .arg(&data)
+        .assert()
+        .success()
+        .stderr(contains("Restored 1 pairs"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&data)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// The bulk and backup commands read and write an encrypted store with --key-file
+#[test]
+fn cli_encrypted_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let backup = temp_dir.path().join("backup");
+    let key_file = temp_dir.path().join("keys");
+    fs::create_dir(&data).unwrap();
+    let key = Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("keygen")
+        .output()
+        .unwrap();
+    fs::write(&key_file, key.stdout).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "--format", "csv", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data)
+        .with_stdin()
+        .buffer("key,value\nkey1,value1\n")
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&backup)
+        .current_dir(&data)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&backup)
+        .arg("--key-file")
+        .arg(&key_file)
+        .current_dir(&data)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&backup)
+        .arg(&data)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("restore")
+        .arg(&backup)
+        .arg(&data)
+        .arg("--key-file")
+        .arg(&key_file)
+        .assert()
+        .success()
+        .stderr(contains("Restored 1 pairs"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--key-file"])
+        .arg(&key_file)
+        .current_dir(&data)
+        .assert()
+        .success()
+        .stdout("key,value\nkey1,value1\n");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -335,3 +645,294 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4074");
+}
+
+// the memory engine serves clients like the others, but keeps nothing on restart
+#[test]
+fn cli_access_server_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4076";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-server --config` should read its settings from a TOML file and keep
+// the engine marker inside the data directory
+#[test]
+fn cli_server_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config,
+        format!(
+            "addr = \"127.0.0.1:4008\"\nengine = \"sled\"\ndata_dir = {:?}\n\n\
+             [limits]\nmax_connections = 4\nidle_timeout = 0\n\n[sled]\ncache_capacity = 1000000\n",
+            data_dir.display().to_string()
+        ),
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["admin", "info", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("engine: sled").and(contains("keys: 1")));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert_eq!(fs::read_to_string(data_dir.join("engine")).unwrap(), "sled");
+    assert!(!temp_dir.path().join("engine").exists());
+
+    // flags override the file, and the marker in the data directory is checked
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .args(["--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    fs::write(&config, "engine = \"kvs\"\nunknown = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid config file"));
+}
+
+// Servers started from the same directory with different `--data-dir` keep their data apart
+#[test]
+fn cli_server_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut children = Vec::new();
+    for (dir, engine, addr) in &[
+        ("a", "kvs", "127.0.0.1:4009"),
+        ("b", "sled", "127.0.0.1:4019"),
+    ] {
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        children.push(
+            server
+                .args(["--data-dir", dir, "--engine", engine, "--addr", addr])
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap(),
+        );
+    }
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4019"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    for mut child in children {
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("a/engine")).unwrap(),
+        "kvs"
+    );
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("b/engine")).unwrap(),
+        "sled"
+    );
+    assert!(temp_dir.path().join("a/kvs").is_dir());
+    assert!(temp_dir.path().join("b/sled").is_dir());
+}
+
+// sled data in the root of the data directory, from older servers, is moved to its directory
+#[test]
+fn cli_server_moves_root_sled_data() {
+    let temp_dir = TempDir::new().unwrap();
+    let db = sled::open(temp_dir.path()).unwrap();
+    db.insert("key1", "value1").unwrap();
+    db.flush().unwrap();
+    drop(db);
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4029"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(temp_dir.path().join("sled/db").is_file());
+    assert!(!temp_dir.path().join("db").exists());
+}
+
+// `kvs passwd` lines should let `kvs-client --user` and `kvs-server --leader-user`
+// log in to `kvs-server --auth-users`
+#[test]
+fn cli_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["passwd", "alice"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("wonderland\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let users = temp_dir.path().join("users");
+    fs::write(&users, output.stdout).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4064", "--auth-users"])
+        .arg(&users)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4064"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication required"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4064"])
+        .args(["--user", "alice"])
+        .env("KVS_PASSWORD", "builder")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Authentication failed"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "--user",
+            "alice",
+            "set",
+            "key1",
+            "value1",
+            "--addr",
+            "127.0.0.1:4064",
+        ])
+        .env("KVS_PASSWORD", "wonderland")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4064", "--user", "alice"])
+        .env("KVS_PASSWORD", "wonderland")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // a replica logs in to the leader as a user too
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4089", "--replica-of", "127.0.0.1:4064"])
+        .args(["--leader-user", "alice"])
+        .env("KVS_LEADER_PASSWORD", "wonderland")
+        .arg("--data-dir")
+        .arg(temp_dir.path().join("replica"))
+        .spawn()
+        .unwrap();
+    let mut replicated = false;
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", "127.0.0.1:4089"])
+            .output()
+            .unwrap();
+        if output.stdout == b"value1\n" {
+            replicated = true;
+            break;
+        }
+    }
+    assert!(replicated, "the replica did not catch up");
+    replica.kill().expect("replica exited before killed");
+    replica.wait().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
@@ -0,0 +1,111 @@
+// each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use kvs::engines::{KvStoreOptions, LsmEngine, Pairs};
+use kvs::{KvEngine, KvServer, KvStore, KvsErr, Result, ServerConfig, ShutdownHandle};
+use std::fs;
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A `KvServer` running on its own thread.
+pub struct Node {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl Node {
+    pub fn stop(self) -> Result<()> {
+        self.handle.shutdown();
+        self.thread.join().expect("server thread panicked")
+    }
+}
+
+/// Serve the store in `dir` on `addr`, once the server accepts connections.
+pub fn start(dir: &Path, addr: &'static str, config: ServerConfig) -> Result<Node> {
+    let server = KvServer::with_config(KvStore::open(dir)?, config);
+    let handle = server.shutdown_handle();
+    let thread = thread::spawn(move || server.run(addr));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TcpStream::connect(addr).is_err() {
+        if thread.is_finished() {
+            thread.join().expect("server thread panicked")?;
+            return Err(KvsErr::StringErr(format!("server on {} stopped", addr)));
+        }
+        if Instant::now() > deadline {
+            return Err(KvsErr::StringErr(format!(
+                "server on {} did not start",
+                addr
+            )));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Ok(Node { handle, thread })
+}
+
+/// Set `count` keys from `key0000` to `<prefix><i>`, then remove `key0007`.
+pub fn fill<E: KvEngine>(engine: &mut E, count: usize, prefix: &str) -> Result<()> {
+    for i in 0..count {
+        engine.set(format!("key{:04}", i), format!("{}{}", prefix, i))?;
+    }
+    engine.remove("key0007".to_owned())
+}
+
+/// Every pair of `engine`, in key order.
+pub fn all_pairs<E: KvEngine>(engine: &mut E) -> Result<Vec<(String, String)>> {
+    Pairs::new(engine, 64).collect()
+}
+
+/// A `KvStore` in `dir` with its index on disk.
+pub fn open_disk_index(dir: &Path) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        disk_index: true,
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with(dir, options)
+}
+
+/// A `KvStore` in `dir` moving values over 1 KiB to the value log.
+pub fn open_value_log(dir: &Path) -> Result<KvStore> {
+    let mut store = KvStore::open(dir)?;
+    store.set_value_log_threshold(Some(1024));
+    Ok(store)
+}
+
+/// An `LsmEngine` in `dir` writing a table every 1 KiB, to test with many of them.
+pub fn open_lsm(dir: &Path) -> Result<LsmEngine> {
+    let mut engine = LsmEngine::open(dir)?;
+    engine.set_memtable_size(1024);
+    Ok(engine)
+}
+
+/// The sorted names of the files of `dir` with the given extension.
+pub fn file_names(dir: &Path, extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Every byte of every log file in `dir`.
+pub fn log_bytes(dir: &Path) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() == Some("log".as_ref()) {
+            bytes.extend(fs::read(path).unwrap());
+        }
+    }
+    bytes
+}
+
+pub fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
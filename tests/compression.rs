@@ -0,0 +1,125 @@
+mod common;
+
+use common::{contains, log_bytes};
+use kvs::engines::{dump, fsck, Keyring, LogOp};
+use kvs::{KvEngine, KvStore, Result};
+use tempfile::TempDir;
+
+fn long_value(c: char) -> String {
+    c.to_string().repeat(4096)
+}
+
+// Values over the threshold are compressed, and read back with the others
+#[test]
+fn compressed_and_plain_records() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("before".to_owned(), long_value('a'))?;
+    store.set_compression_threshold(Some(1024));
+    store.set("long".to_owned(), long_value('b'))?;
+    store.set("short".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("long".to_owned())?, Some(long_value('b')));
+    drop(store);
+
+    let bytes = log_bytes(temp_dir.path());
+    assert!(contains(&bytes, long_value('a').as_bytes()));
+    assert!(!contains(&bytes, long_value('b').as_bytes()));
+    assert!(contains(&bytes, b"\"value\""));
+
+    // reading needs no configuration
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("before".to_owned())?, Some(long_value('a')));
+    assert_eq!(store.get("long".to_owned())?, Some(long_value('b')));
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Values that would not shrink are written as is
+#[test]
+fn incompressible_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compression_threshold(Some(0));
+    let value: String = ('0'..='9').chain('a'..='z').chain('A'..='Z').collect();
+    store.set("key1".to_owned(), value.clone())?;
+    drop(store);
+
+    assert!(contains(&log_bytes(temp_dir.path()), value.as_bytes()));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Compaction rewrites old records with the current threshold
+#[test]
+fn compaction_recompresses() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), long_value('a'))?;
+    store.set("key2".to_owned(), long_value('b'))?;
+    drop(store);
+    let plain = log_bytes(temp_dir.path()).len();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compression_threshold(Some(1024));
+    KvEngine::compact(&mut store)?;
+    drop(store);
+    let bytes = log_bytes(temp_dir.path());
+    assert!(bytes.len() < plain / 10);
+    assert!(!contains(&bytes, long_value('a').as_bytes()));
+
+    // and back again once compression is off
+    let mut store = KvStore::open(temp_dir.path())?;
+    KvEngine::compact(&mut store)?;
+    assert_eq!(store.get("key1".to_owned())?, Some(long_value('a')));
+    assert_eq!(store.get("key2".to_owned())?, Some(long_value('b')));
+    Ok(())
+}
+
+// Offline tools see through compression
+#[test]
+fn fsck_and_dump_compressed() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compression_threshold(Some(1024));
+    store.set("key1".to_owned(), long_value('a'))?;
+    store.set("key1".to_owned(), long_value('b'))?;
+    drop(store);
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert!(report.is_clean());
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.superseded, 1);
+
+    let mut live = Vec::new();
+    dump(temp_dir.path(), |record| {
+        if record.live {
+            live.push(record.op);
+        }
+        Ok(())
+    })?;
+    assert_eq!(
+        live,
+        vec![LogOp::Set {
+            key: "key1".to_owned(),
+            value: long_value('b')
+        }]
+    );
+    Ok(())
+}
+
+// Compressed records are encrypted too, compression going first
+#[test]
+fn compressed_and_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let keyring = || Keyring::new(vec![vec![1; 32]]).unwrap();
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring())?;
+    store.set_compression_threshold(Some(1024));
+    store.set("key1".to_owned(), long_value('a'))?;
+    drop(store);
+    assert!(log_bytes(temp_dir.path()).len() < 1024);
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(long_value('a')));
+    Ok(())
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use common::{file_names, open_disk_index};
+use kvs::engines::{fsck, Keyring, KvStoreOptions};
+use kvs::{KvEngine, KvStore, Result};
+use std::fs;
+use tempfile::TempDir;
+
+// Closed generations get an index file, used again by the next open
+#[test]
+fn index_files_reused() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_disk_index(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    assert_eq!(file_names(temp_dir.path(), "idx"), vec!["1.idx"]);
+    let modified = fs::metadata(temp_dir.path().join("1.idx"))?.modified()?;
+    drop(store);
+
+    store = open_disk_index(temp_dir.path())?;
+    assert_eq!(
+        fs::metadata(temp_dir.path().join("1.idx"))?.modified()?,
+        modified
+    );
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key100".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 100);
+    Ok(())
+}
+
+// Overwrites and removals in later generations hide the older entries
+#[test]
+fn generations_merged() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_disk_index(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    store.set("key3".to_owned(), "new".to_owned())?;
+    store.remove("key5".to_owned())?;
+    store.set("key10".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 9);
+    let keys: Vec<String> = store
+        .scan(None, 100)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(
+        keys,
+        vec!["key1", "key10", "key2", "key3", "key4", "key6", "key7", "key8", "key9"]
+    );
+    assert_eq!(
+        store.scan(Some("key3".to_owned()), 2)?,
+        vec![
+            ("key4".to_owned(), "old".to_owned()),
+            ("key6".to_owned(), "old".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// The active generation is closed once it holds too many keys for memory
+#[test]
+fn active_generation_sealed() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_disk_index(temp_dir.path())?;
+    let pairs: Vec<(String, String)> = (0..70_000)
+        .map(|i| (format!("key{:05}", i), i.to_string()))
+        .collect();
+    for chunk in pairs.chunks(10_000) {
+        store.set_batch(chunk.to_vec())?;
+    }
+    assert_eq!(file_names(temp_dir.path(), "idx"), vec!["1.idx"]);
+    assert_eq!(store.get("key00042".to_owned())?, Some("42".to_owned()));
+    assert_eq!(store.get("key69999".to_owned())?, Some("69999".to_owned()));
+    assert_eq!(store.stats()?.keys, 70_000);
+    Ok(())
+}
+
+// A generation without an index file is read in parts, merged into one index file
+#[test]
+fn long_generation_indexed() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<(String, String)> = (0..70_000)
+        .map(|i| (format!("key{:05}", i), i.to_string()))
+        .collect();
+    for chunk in pairs.chunks(10_000) {
+        store.set_batch(chunk.to_vec())?;
+    }
+    store.remove("key00001".to_owned())?;
+    store.set("key00002".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    assert_eq!(file_names(temp_dir.path(), "idx"), vec!["1.idx"]);
+    assert!(fsck(temp_dir.path(), false)?.orphans.is_empty());
+    assert_eq!(store.get("key00001".to_owned())?, None);
+    assert_eq!(store.get("key00002".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key69999".to_owned())?, Some("69999".to_owned()));
+    assert_eq!(store.stats()?.keys, 69_999);
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    assert_eq!(store.get("key00001".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 69_999);
+    Ok(())
+}
+
+// Compaction leaves a single index file for the compacted generation
+#[test]
+fn compaction_rewrites_index() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_disk_index(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("value{}", round))?;
+        }
+        drop(store);
+        store = open_disk_index(temp_dir.path())?;
+    }
+    assert_eq!(file_names(temp_dir.path(), "idx").len(), 3);
+    store.remove("key7".to_owned())?;
+    KvEngine::compact(&mut store)?;
+    assert_eq!(file_names(temp_dir.path(), "idx").len(), 1);
+    drop(store);
+
+    let mut store = open_disk_index(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key7".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 49);
+    assert!(fsck(temp_dir.path(), false)?.orphans.is_empty());
+    Ok(())
+}
+
+// Missing or stale index files are written again from the logs
+#[test]
+fn index_files_rebuilt() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_disk_index(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = open_disk_index(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("1.idx"))?;
+    fs::write(temp_dir.path().join("2.idx"), "garbage")?;
+    let mut store = open_disk_index(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(file_names(temp_dir.path(), "idx"), vec!["1.idx", "2.idx"]);
+
+    // the index files are ignored by the in-memory index
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Index files would hold the keys of an encrypted store in the clear
+#[test]
+fn rejected_with_keyring() {
+    let temp_dir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        keyring: Some(Keyring::new(vec![vec![1; 32]]).unwrap()),
+        disk_index: true,
+    };
+    assert!(KvStore::open_with(temp_dir.path(), options).is_err());
+}
@@ -0,0 +1,77 @@
+use kvs::engines::{dump, LogOp, LogRecord};
+use kvs::{KvEngine, KvStore, Result};
+use tempfile::TempDir;
+
+fn records(dir: &TempDir) -> Result<Vec<LogRecord>> {
+    let mut records = Vec::new();
+    dump(dir.path(), |record| {
+        records.push(record);
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+// Every record is listed with its position, and only the latest write of a key is live
+#[test]
+fn dump_marks_live_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let records = records(&temp_dir)?;
+    let summary: Vec<(u64, &str, bool)> = records
+        .iter()
+        .map(|record| (record.version, record.op.key(), record.live))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, "key1", false),
+            (1, "key2", false),
+            (1, "key1", true),
+            (1, "key2", false),
+        ]
+    );
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(records[1].offset, records[0].len);
+    assert_eq!(
+        records[3].op,
+        LogOp::Remove {
+            key: "key2".to_owned()
+        }
+    );
+    Ok(())
+}
+
+// The history of a key spans generations, and survives only until compaction
+#[test]
+fn dump_key_history_across_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for value in &["value1", "value2", "value3"] {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), value.to_string())?;
+    }
+
+    let history: Vec<(u64, LogOp, bool)> = records(&temp_dir)?
+        .into_iter()
+        .filter(|record| record.op.key() == "key1")
+        .map(|record| (record.version, record.op, record.live))
+        .collect();
+    let set = |value: &str| LogOp::Set {
+        key: "key1".to_owned(),
+        value: value.to_owned(),
+    };
+    assert_eq!(
+        history,
+        vec![
+            (1, set("value1"), false),
+            (2, set("value2"), false),
+            (3, set("value3"), true),
+        ]
+    );
+    Ok(())
+}
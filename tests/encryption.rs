@@ -0,0 +1,154 @@
+mod common;
+
+use common::{contains, log_bytes};
+use kvs::engines::{dump_with_keyring, fsck, fsck_with_keyring, Keyring};
+use kvs::{KvEngine, KvStore, KvsErr, Result};
+use std::fs;
+use tempfile::TempDir;
+
+fn keyring(keys: &[u8]) -> Keyring {
+    Keyring::new(keys.iter().map(|&byte| vec![byte; 32]).collect()).unwrap()
+}
+
+// Records are written encrypted and read back after reopening with the key
+#[test]
+fn encrypted_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    store.set("key1".to_owned(), "secret-value".to_owned())?;
+    store.set("key2".to_owned(), "other-value".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let bytes = log_bytes(temp_dir.path());
+    assert!(!contains(&bytes, b"secret-value"));
+    assert!(!contains(&bytes, b"key1"));
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Opening with another key, or none, fails with a dedicated error
+#[test]
+fn wrong_key() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open_encrypted(temp_dir.path(), keyring(&[2])),
+        Err(KvsErr::WrongKey)
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsErr::WrongKey)
+    ));
+    Ok(())
+}
+
+// Compaction rewrites records with the first key, after which older keys can go
+#[test]
+fn key_rotation_on_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value0".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[2, 1]))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    KvEngine::compact(&mut store)?;
+    drop(store);
+    assert!(!contains(&log_bytes(temp_dir.path()), b"value0"));
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[2]))?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Values in the value log are sealed again with the first key by a compaction too
+#[test]
+fn key_rotation_of_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let value = "v".repeat(100);
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    store.set_value_log_threshold(Some(64));
+    store.set("key1".to_owned(), value.clone())?;
+    drop(store);
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[2, 1]))?;
+    store.set_value_log_threshold(Some(64));
+    KvEngine::compact(&mut store)?;
+    drop(store);
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[2]))?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Offline tools read encrypted stores given the keys, and refuse to guess without them
+#[test]
+fn fsck_and_dump_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let report = fsck_with_keyring(temp_dir.path(), Some(&keyring(&[1])), false)?;
+    assert!(report.is_clean());
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.superseded, 1);
+    assert!(matches!(fsck(temp_dir.path(), true), Err(KvsErr::WrongKey)));
+
+    let mut live = Vec::new();
+    dump_with_keyring(temp_dir.path(), Some(&keyring(&[1])), |record| {
+        if record.live {
+            live.push(record.op);
+        }
+        Ok(())
+    })?;
+    assert_eq!(live.len(), 1);
+    assert_eq!(live[0].key(), "key1");
+
+    // the refused repair left the data alone
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring(&[1]))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A key file holds one hex key per line, the first one current
+#[test]
+fn key_file() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("keys");
+    fs::write(
+        &key_file,
+        format!(
+            "# current\n{}\n{}\n",
+            Keyring::generate_key()?,
+            Keyring::generate_key()?
+        ),
+    )?;
+    let mut store =
+        KvStore::open_encrypted(temp_dir.path().join("data"), Keyring::load(&key_file)?)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store =
+        KvStore::open_encrypted(temp_dir.path().join("data"), Keyring::load(&key_file)?)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    fs::write(&key_file, "not a key\n")?;
+    assert!(Keyring::load(&key_file).is_err());
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use kvs::engines::fsck;
+use kvs::{KvEngine, KvStore, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// write some keys and return the path of the log holding them
+fn write_log(dir: &Path) -> Result<PathBuf> {
+    let mut store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+    Ok(dir.join("1.log"))
+}
+
+// A healthy store reports its live keys and the space compaction would free
+#[test]
+fn fsck_clean_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_log(temp_dir.path())?;
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert!(report.is_clean());
+    assert_eq!(report.live_keys, 2);
+    assert_eq!(report.superseded, 1);
+    assert_eq!(report.dangling_removes, 0);
+    assert!(report.reclaimable > 0);
+    assert!(report.orphans.is_empty());
+    assert_eq!(report.generations[0].records, 5);
+    assert_eq!(report.generations[0].live_records, 2);
+    Ok(())
+}
+
+// Garbage in the middle of a log is reported with its offset, and repair salvages the rest
+#[test]
+fn fsck_repairs_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = write_log(temp_dir.path())?;
+    let mut content = fs::read(&log)?;
+    let offset = content
+        .windows(6)
+        .position(|w| w == b"value3")
+        .expect("record not found")
+        - 24;
+    content[offset + 2] = b'#';
+    fs::write(&log, &content)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert!(!report.is_clean());
+    let corrupt = &report.generations[0].corrupt;
+    assert_eq!(corrupt.len(), 1);
+    assert!(corrupt[0].offset <= offset as u64);
+    assert_eq!(report.generations[0].records, 4);
+
+    let report = fsck(temp_dir.path(), true)?;
+    assert!(report.repaired_into.is_some());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value4".to_owned()));
+    drop(store);
+    assert!(fsck(temp_dir.path(), false)?.is_clean());
+    Ok(())
+}
+
+// A record cut off by a crash is reported as truncated
+#[test]
+fn fsck_truncated_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = write_log(temp_dir.path())?;
+    let size = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(br#"{"Set":{"key":"key4","val"#)?;
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert_eq!(report.generations[0].truncated_at, Some(size));
+    assert_eq!(report.live_keys, 2);
+
+    fsck(temp_dir.path(), true)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Files that are not log generations are listed as orphans
+#[test]
+fn fsck_orphan_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_log(temp_dir.path())?;
+    fs::write(temp_dir.path().join("engine"), "kvs")?;
+    fs::write(temp_dir.path().join("stale.log"), "")?;
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert!(report.is_clean());
+    assert_eq!(report.orphans, vec![temp_dir.path().join("stale.log")]);
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvEngine, Result};
+use kvs::{KvEngine, KvStore, Result};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -124,3 +124,59 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Scan should page through live keys in order
+#[test]
+fn scan_pages_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["d", "a", "c", "b", "e"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let first = store.scan(None, 2)?;
+    assert_eq!(
+        first,
+        vec![
+            ("a".to_owned(), "value-a".to_owned()),
+            ("b".to_owned(), "value-b".to_owned())
+        ]
+    );
+    let second = store.scan(Some("b".to_owned()), 2)?;
+    assert_eq!(
+        second,
+        vec![
+            ("d".to_owned(), "value-d".to_owned()),
+            ("e".to_owned(), "value-e".to_owned())
+        ]
+    );
+    assert!(store.scan(Some("e".to_owned()), 2)?.is_empty());
+
+    // batched writes are visible after reopening
+    store.set_batch(vec![
+        ("a".to_owned(), "batch-a".to_owned()),
+        ("f".to_owned(), "batch-f".to_owned()),
+    ])?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("batch-a".to_owned()));
+    assert_eq!(store.get("f".to_owned())?, Some("batch-f".to_owned()));
+    Ok(())
+}
+
+// A lower compaction threshold should compact sooner
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_threshold(1024);
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    assert!(stats.uncompacted_bytes <= 1024);
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,203 @@
+mod common;
+
+use common::{file_names, open_lsm};
+use kvs::backup;
+use kvs::bulk;
+use kvs::engines::LsmEngine;
+use kvs::{KvEngine, KvsErr, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+// the table ids of every level, as listed by levels.json
+fn levels(dir: &Path) -> Vec<Vec<u64>> {
+    let levels: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("levels.json")).unwrap()).unwrap();
+    serde_json::from_value(levels["levels"].clone()).unwrap()
+}
+
+// Writes are read back, from the write-ahead log after a restart
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(
+        engine.remove("key2".to_owned()),
+        Err(KvsErr::KeyNotFound)
+    ));
+    drop(engine);
+
+    assert_eq!(file_names(temp_dir.path(), "sst").len(), 0);
+    let mut engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// A write torn by a crash is dropped, and the log appended to after it
+#[test]
+fn torn_write_ahead_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("wal".as_ref()))
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(wal)?
+        .write_all(b"[\"key2\",\"val")?;
+
+    let mut engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    drop(engine);
+    let mut engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Full memtables become tables, merged down the levels as they fill up
+#[test]
+fn tables_and_levels() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open_lsm(temp_dir.path())?;
+    for round in 0..10 {
+        for i in 0..500 {
+            engine.set(format!("key{:03}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    for i in (0..500).step_by(2) {
+        engine.remove(format!("key{:03}", i))?;
+    }
+    let levels = levels(temp_dir.path());
+    assert!(levels[0].len() < 4);
+    assert!(levels.len() >= 3);
+    assert!(engine.stats()?.compactions > 0);
+    drop(engine);
+
+    let mut engine = open_lsm(temp_dir.path())?;
+    for i in 0..500 {
+        let expected = Some(format!("value{}-9", i)).filter(|_| i % 2 == 1);
+        assert_eq!(engine.get(format!("key{:03}", i))?, expected);
+    }
+    assert_eq!(engine.get("key500".to_owned())?, None);
+    assert_eq!(engine.scan(None, 1000)?.len(), 250);
+    Ok(())
+}
+
+// Keys are estimated from the table footers, exactly again after a full compaction
+#[test]
+fn keys_estimated() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open_lsm(temp_dir.path())?;
+    for i in 0..200 {
+        engine.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    for i in 100..150 {
+        engine.remove(format!("key{:03}", i))?;
+    }
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 150);
+    assert_eq!(
+        stats.log_files as usize,
+        file_names(temp_dir.path(), "sst").len()
+    );
+    drop(engine);
+
+    let mut engine = open_lsm(temp_dir.path())?;
+    assert_eq!(engine.stats()?.keys, 150);
+    for i in 0..50 {
+        engine.set(format!("key{:03}", i), "new".to_owned())?;
+    }
+    assert!(engine.stats()?.keys >= 150);
+    KvEngine::compact(&mut engine)?;
+    assert_eq!(engine.stats()?.keys, 150);
+    Ok(())
+}
+
+// Scans merge the memtable and the tables, skipping removed keys
+#[test]
+fn scan_merges_tables() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open_lsm(temp_dir.path())?;
+    for i in 0..300 {
+        engine.set(format!("key{:03}", i), "old".to_owned())?;
+    }
+    engine.set("key100".to_owned(), "new".to_owned())?;
+    engine.remove("key101".to_owned())?;
+    assert!(file_names(temp_dir.path(), "sst").len() > 1);
+
+    let pairs = engine.scan(None, 1000)?;
+    assert_eq!(pairs.len(), 299);
+    assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(
+        engine.scan(Some("key099".to_owned()), 2)?,
+        vec![
+            ("key100".to_owned(), "new".to_owned()),
+            ("key102".to_owned(), "old".to_owned())
+        ]
+    );
+    assert!(engine.scan(Some("key299".to_owned()), 10)?.is_empty());
+    Ok(())
+}
+
+// A full compaction leaves the live keys in the tables of a single level
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open_lsm(temp_dir.path())?;
+    for round in 0..3 {
+        for i in 0..200 {
+            engine.set(format!("key{:03}", i), format!("value{}", round))?;
+        }
+    }
+    for i in 0..100 {
+        engine.remove(format!("key{:03}", i))?;
+    }
+    KvEngine::compact(&mut engine)?;
+    let levels = levels(temp_dir.path());
+    assert_eq!(levels.iter().filter(|level| !level.is_empty()).count(), 1);
+    assert_eq!(
+        file_names(temp_dir.path(), "sst").len(),
+        levels.iter().flatten().count()
+    );
+    drop(engine);
+
+    let mut engine = open_lsm(temp_dir.path())?;
+    assert_eq!(engine.get("key050".to_owned())?, None);
+    assert_eq!(engine.get("key150".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.stats()?.keys, 100);
+    Ok(())
+}
+
+// Backups copy the tables and the write-ahead log, and restore like the other engines
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let mut engine = open_lsm(&data)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let backup_dir = temp_dir.path().join("backup");
+    engine.backup_to(&backup_dir)?;
+    let expected = bulk::digest(&mut engine)?;
+    engine.set("key0".to_owned(), "changed".to_owned())?;
+    drop(engine);
+
+    let restored = temp_dir.path().join("restored");
+    assert_eq!(backup::restore(&backup_dir, &restored, None)?, expected);
+    let mut engine = LsmEngine::open(&restored)?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(engine.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
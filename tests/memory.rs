@@ -0,0 +1,93 @@
+use kvs::engines::MemoryEngine;
+use kvs::{KvClient, KvEngine, KvServer, KvsErr, Result};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Writes are read back, by the clones of the engine too
+#[test]
+fn get_set_remove() -> Result<()> {
+    let mut engine = MemoryEngine::new();
+    let mut clone = engine.clone();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    clone.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    engine.remove("key2".to_owned())?;
+    assert_eq!(clone.get("key2".to_owned())?, None);
+    assert!(matches!(
+        clone.remove("key2".to_owned()),
+        Err(KvsErr::KeyNotFound)
+    ));
+    assert_eq!(engine.bytes(), 10);
+    assert_eq!(engine.stats()?.keys, 1);
+    Ok(())
+}
+
+#[test]
+fn scan_in_key_order() -> Result<()> {
+    let mut engine = MemoryEngine::new();
+    engine.set_batch(
+        (0..100)
+            .rev()
+            .map(|i| (format!("key{:02}", i), i.to_string()))
+            .collect(),
+    )?;
+    let pairs = engine.scan(None, 10)?;
+    assert_eq!(pairs.len(), 10);
+    assert_eq!(pairs[0], ("key00".to_owned(), "0".to_owned()));
+    assert_eq!(
+        engine.scan(Some("key97".to_owned()), 10)?,
+        vec![
+            ("key98".to_owned(), "98".to_owned()),
+            ("key99".to_owned(), "99".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Past the budget the least recently read or written keys go first
+#[test]
+fn budget_evicts_least_recently_used() -> Result<()> {
+    let mut engine = MemoryEngine::with_budget(100);
+    for i in 0..5 {
+        engine.set(format!("key{}", i), "v".repeat(16))?;
+    }
+    assert_eq!(engine.bytes(), 100);
+    engine.get("key0".to_owned())?;
+    engine.set("key1".to_owned(), "v".repeat(16))?;
+    engine.set("key5".to_owned(), "v".repeat(16))?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    for key in &["key0", "key1", "key3", "key4", "key5"] {
+        assert!(engine.get(key.to_string())?.is_some());
+    }
+
+    engine.set("large".to_owned(), "v".repeat(200))?;
+    assert_eq!(engine.stats()?.keys, 0);
+    assert_eq!(engine.bytes(), 0);
+    Ok(())
+}
+
+#[test]
+fn backup_unsupported() {
+    let temp_dir = TempDir::new().unwrap();
+    assert!(MemoryEngine::new().backup_to(temp_dir.path()).is_err());
+}
+
+// A server can be tested without touching the disk
+#[test]
+fn serves_clients() -> Result<()> {
+    let mut engine = MemoryEngine::new();
+    let server = KvServer::new(engine.clone());
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run("127.0.0.1:4075"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::connect("127.0.0.1:4075")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    handle.shutdown();
+    server.join().expect("server thread panicked")
+}
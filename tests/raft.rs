@@ -0,0 +1,598 @@
+use kvs::engines::LogOp;
+use kvs::raft::{
+    Command, Entry, HardState, Message, NodeId, Peer, RaftConfig, RaftEngine, RaftMessage,
+    RaftNode, Role, Snapshot, SnapshotChunk,
+};
+use kvs::{KvClient, KvServer, KvStore, KvsErr, Result, ShutdownHandle};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// applied entries kept before a node replaces its log with a snapshot
+const COMPACT_ENTRIES: u64 = 20;
+// pairs sent in one snapshot chunk
+const SNAPSHOT_BATCH: usize = 8;
+// presented by the members of the clusters over TCP to each other
+const TOKEN: &str = "raft secret";
+
+// A cluster of in-process nodes on a simulated network. Everything happens in
+// a fixed order, so a test behaves the same on every run.
+struct Cluster {
+    nodes: BTreeMap<NodeId, Option<RaftNode>>,
+    // what each node persisted, which survives a crash along with its state machine
+    disks: BTreeMap<NodeId, Disk>,
+    machines: BTreeMap<NodeId, BTreeMap<String, String>>,
+    applied: BTreeMap<NodeId, BTreeMap<u64, Command>>,
+    // every snapshot chunk sent, with the node it was sent to
+    chunks: Vec<(NodeId, SnapshotChunk)>,
+    network: VecDeque<RaftMessage>,
+    // nodes cut off from the others
+    partition: BTreeSet<NodeId>,
+}
+
+#[derive(Clone, Default)]
+struct Disk {
+    hard_state: HardState,
+    entries: Vec<Entry>,
+    snapshot: Snapshot,
+}
+
+impl Cluster {
+    fn new(size: u64) -> Cluster {
+        let ids: Vec<NodeId> = (1..=size).collect();
+        let mut cluster = Cluster {
+            nodes: BTreeMap::new(),
+            disks: BTreeMap::new(),
+            machines: BTreeMap::new(),
+            applied: BTreeMap::new(),
+            chunks: Vec::new(),
+            network: VecDeque::new(),
+            partition: BTreeSet::new(),
+        };
+        for &id in &ids {
+            cluster
+                .nodes
+                .insert(id, Some(RaftNode::new(id, ids.clone(), id)));
+            cluster.disks.insert(id, Default::default());
+            cluster.machines.insert(id, BTreeMap::new());
+            cluster.applied.insert(id, BTreeMap::new());
+        }
+        cluster
+    }
+
+    fn node(&mut self, id: NodeId) -> &mut RaftNode {
+        self.nodes
+            .get_mut(&id)
+            .and_then(Option::as_mut)
+            .expect("node is down")
+    }
+
+    fn crash(&mut self, id: NodeId) {
+        self.nodes.insert(id, None);
+    }
+
+    // bring a node back from its disk; the entries after the snapshot are applied again
+    fn restart(&mut self, id: NodeId) {
+        let disk = self.disks[&id].clone();
+        let ids = self.nodes.keys().cloned().collect();
+        let node = RaftNode::restart(
+            id,
+            ids,
+            disk.hard_state,
+            disk.entries,
+            disk.snapshot,
+            id + 100,
+        );
+        self.nodes.insert(id, Some(node));
+    }
+
+    fn leaders(&self) -> Vec<NodeId> {
+        self.nodes
+            .values()
+            .flatten()
+            .filter(|node| node.role() == Role::Leader)
+            .map(|node| node.id())
+            .collect()
+    }
+
+    // the leader with the highest term, which is the one the majority follows
+    fn leader(&self) -> NodeId {
+        self.nodes
+            .values()
+            .flatten()
+            .filter(|node| node.role() == Role::Leader)
+            .max_by_key(|node| node.term())
+            .map(|node| node.id())
+            .expect("no leader")
+    }
+
+    fn set(&mut self, id: NodeId, key: &str, value: &str) -> bool {
+        let command = Command::Op(LogOp::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+        let accepted = self.node(id).propose(command).is_ok();
+        self.process(id);
+        accepted
+    }
+
+    fn connected(&self, a: NodeId, b: NodeId) -> bool {
+        self.nodes[&a].is_some()
+            && self.nodes[&b].is_some()
+            && self.partition.contains(&a) == self.partition.contains(&b)
+    }
+
+    // persist, apply and send what the node produced, the way `RaftEngine` does over TCP
+    fn process(&mut self, id: NodeId) {
+        let ready = self.node(id).ready();
+        let disk = self.disks.get_mut(&id).unwrap();
+        if let Some(hard_state) = ready.hard_state {
+            disk.hard_state = hard_state;
+        }
+        if let Some(from) = ready.log_from {
+            disk.entries.retain(|entry| entry.index < from);
+            disk.entries.extend(ready.entries);
+        }
+        let machine = self.machines.get_mut(&id).unwrap();
+        for chunk in ready.snapshot_chunks {
+            let end = match (chunk.done, chunk.pairs.last()) {
+                (true, _) | (false, None) => Bound::Unbounded,
+                (false, Some((key, _))) => Bound::Included(key.clone()),
+            };
+            let start = chunk.after.map_or(Bound::Unbounded, Bound::Excluded);
+            let stale: Vec<String> = machine
+                .range((start, end))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in stale {
+                machine.remove(&key);
+            }
+            machine.extend(chunk.pairs);
+        }
+        if let Some(snapshot) = ready.snapshot {
+            disk.snapshot = snapshot;
+        }
+        for entry in ready.committed {
+            match &entry.command {
+                Command::Op(LogOp::Set { key, value }) => {
+                    machine.insert(key.clone(), value.clone());
+                }
+                Command::Op(LogOp::Remove { key }) => {
+                    machine.remove(key);
+                }
+                _ => {}
+            }
+            self.applied
+                .get_mut(&id)
+                .unwrap()
+                .insert(entry.index, entry.command);
+        }
+        for mut msg in ready.messages {
+            if let Message::InstallSnapshot { chunk, .. } = &mut msg.body {
+                let start = chunk
+                    .after
+                    .clone()
+                    .map_or(Bound::Unbounded, Bound::Excluded);
+                chunk.pairs = machine
+                    .range((start, Bound::Unbounded))
+                    .take(SNAPSHOT_BATCH)
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                chunk.done = chunk.pairs.len() < SNAPSHOT_BATCH;
+                self.chunks.push((msg.to, chunk.clone()));
+            }
+            self.network.push_back(msg);
+        }
+
+        let node = self.node(id);
+        let applied = node.commit_index();
+        if applied - node.snapshot().index > COMPACT_ENTRIES {
+            node.compact(applied);
+            let snapshot = node.snapshot().clone();
+            let disk = self.disks.get_mut(&id).unwrap();
+            disk.entries.retain(|entry| entry.index > snapshot.index);
+            disk.snapshot = snapshot;
+        }
+    }
+
+    // deliver messages until the network is quiet
+    fn deliver(&mut self) {
+        while let Some(msg) = self.network.pop_front() {
+            if self.connected(msg.from, msg.to) {
+                let to = msg.to;
+                self.node(to).step(msg);
+                self.process(to);
+            }
+        }
+    }
+
+    fn run(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            let ids: Vec<NodeId> = self.nodes.keys().cloned().collect();
+            for id in ids {
+                if self.nodes[&id].is_some() {
+                    self.node(id).tick();
+                    self.process(id);
+                }
+            }
+            self.deliver();
+        }
+    }
+
+    // no two nodes ever applied different commands at the same index
+    fn check_safety(&self) {
+        for (a, applied_a) in &self.applied {
+            for (b, applied_b) in &self.applied {
+                for (index, command) in applied_a {
+                    if let Some(other) = applied_b.get(index) {
+                        assert_eq!(
+                            command, other,
+                            "nodes {} and {} disagree at {}",
+                            a, b, index
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn assert_converged(&self) {
+        self.check_safety();
+        let first = self.machines.values().next().unwrap();
+        for (id, machine) in &self.machines {
+            assert_eq!(machine, first, "node {} diverged", id);
+        }
+    }
+}
+
+// A single leader is elected and every node agrees on it
+#[test]
+fn elects_one_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    assert_eq!(cluster.leaders().len(), 1);
+    let leader = cluster.leader();
+    for id in 1..=3 {
+        assert_eq!(cluster.node(id).leader(), Some(leader));
+    }
+}
+
+// Writes proposed to the leader reach every state machine, and followers refuse them
+#[test]
+fn replicates_writes() {
+    let mut cluster = Cluster::new(5);
+    cluster.run(50);
+    let leader = cluster.leader();
+    for i in 0..10 {
+        assert!(cluster.set(leader, &format!("key{}", i), "value"));
+    }
+    let follower = (1..=5).find(|&id| id != leader).unwrap();
+    assert!(!cluster.set(follower, "key", "value"));
+    cluster.run(10);
+    cluster.assert_converged();
+    assert_eq!(cluster.machines[&follower].len(), 10);
+}
+
+// A partitioned leader can not commit; the majority elects a new leader and the
+// old leader's uncommitted write is dropped once the partition heals
+#[test]
+fn partitioned_leader_is_replaced() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let old = cluster.leader();
+    cluster.set(old, "key1", "value1");
+    cluster.run(5);
+
+    cluster.partition.insert(old);
+    assert!(cluster.set(old, "lost", "value"));
+    cluster.run(50);
+    let new = cluster.leader();
+    assert_ne!(new, old);
+    assert!(cluster.set(new, "key2", "value2"));
+    cluster.run(5);
+    assert!(!cluster.machines[&old].contains_key("lost"));
+    assert!(!cluster.machines[&old].contains_key("key2"));
+
+    cluster.partition.clear();
+    cluster.run(20);
+    assert_eq!(cluster.node(old).role(), Role::Follower);
+    cluster.assert_converged();
+    assert!(!cluster.machines[&old].contains_key("lost"));
+    assert_eq!(cluster.machines[&old]["key2"], "value2");
+}
+
+// Without a majority nothing commits
+#[test]
+fn minority_can_not_commit() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader();
+    for id in (1..=3).filter(|&id| id != leader) {
+        cluster.crash(id);
+    }
+    let commit = cluster.node(leader).commit_index();
+    assert!(cluster.set(leader, "key", "value"));
+    cluster.run(50);
+    assert_eq!(cluster.node(leader).commit_index(), commit);
+    assert!(!cluster.machines[&leader].contains_key("key"));
+}
+
+// A node that crashed catches up after restarting, through a snapshot once the
+// leader compacted the entries it missed
+#[test]
+fn crashed_node_catches_up_from_snapshot() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader();
+    let follower = (1..=3).find(|&id| id != leader).unwrap();
+    cluster.set(leader, "key0", "value0");
+    cluster.run(5);
+    cluster.crash(follower);
+
+    for i in 1..100 {
+        assert!(cluster.set(leader, &format!("key{}", i % 30), &format!("value{}", i)));
+        cluster.run(1);
+    }
+    assert!(cluster.node(leader).snapshot().index > 50);
+
+    cluster.restart(follower);
+    cluster.run(20);
+    cluster.assert_converged();
+    assert_eq!(cluster.machines[&follower]["key9"], "value99");
+
+    // the leader crashing as well leaves a node with the whole history in charge
+    cluster.crash(leader);
+    cluster.run(50);
+    let new = cluster.leader();
+    assert_ne!(new, leader);
+    assert!(cluster.set(new, "after", "crash"));
+    cluster.restart(leader);
+    cluster.run(20);
+    cluster.assert_converged();
+    assert_eq!(cluster.machines[&leader]["after"], "crash");
+}
+
+// A follower behind the leader's snapshot receives the data a chunk at a time,
+// and the heartbeats meanwhile do not start the transfer over
+#[test]
+fn snapshot_is_sent_in_chunks() {
+    let mut cluster = Cluster::new(3);
+    cluster.run(50);
+    let leader = cluster.leader();
+    let follower = (1..=3).find(|&id| id != leader).unwrap();
+    cluster.crash(follower);
+    for i in 0..100 {
+        assert!(cluster.set(leader, &format!("key{:02}", i % 40), &format!("value{}", i)));
+        cluster.run(1);
+    }
+    assert!(cluster.node(leader).snapshot().index > 50);
+
+    cluster.chunks.clear();
+    cluster.restart(follower);
+    cluster.run(20);
+    cluster.assert_converged();
+    let sent: Vec<&SnapshotChunk> = cluster
+        .chunks
+        .iter()
+        .filter(|(to, _)| *to == follower)
+        .map(|(_, chunk)| chunk)
+        .collect();
+    // five full chunks and an empty last one
+    assert_eq!(sent.len(), 40 / SNAPSHOT_BATCH + 1);
+    assert!(sent[0].after.is_none());
+    assert!(sent[1..].iter().all(|chunk| chunk.after.is_some()));
+    assert!(sent.last().unwrap().done);
+}
+
+struct Member {
+    handle: ShutdownHandle,
+    thread: JoinHandle<Result<()>>,
+}
+
+fn start_member(dir: &TempDir, id: NodeId, peers: &[Peer]) -> Result<Member> {
+    let config = RaftConfig {
+        id,
+        peers: peers.to_vec(),
+        state_dir: dir.path().join("raft"),
+        token: Some(TOKEN.to_owned()),
+    };
+    let engine = RaftEngine::start(KvStore::open(dir.path().join("kvs"))?, config)?;
+    let server = KvServer::new(engine);
+    let handle = server.shutdown_handle();
+    let addr = peers[id as usize - 1].client_addr.clone();
+    let thread = thread::spawn(move || server.run(addr));
+    Ok(Member { handle, thread })
+}
+
+fn stop_member(member: Member) -> Result<()> {
+    member.handle.shutdown();
+    member.thread.join().expect("server thread panicked")
+}
+
+// retry until the cluster has a leader to take the request
+fn retry<T>(mut call: impl FnMut() -> Result<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        match call() {
+            Ok(value) => return value,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+            Err(e) => panic!("request kept failing: {}", e),
+        }
+    }
+}
+
+// leader of a running cluster, found by asking every member
+fn find_leader(peers: &[Peer], down: &[NodeId]) -> NodeId {
+    retry(|| {
+        for peer in peers.iter().filter(|peer| !down.contains(&peer.id)) {
+            let mut client = KvClient::connect(peer.client_addr.as_str())?;
+            match client.get("probe".to_owned()) {
+                Ok(_) => return Ok(peer.id),
+                Err(KvsErr::NotLeader(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(KvsErr::StringErr("no leader yet".to_owned()))
+    })
+}
+
+// Servers replicate through Raft over TCP, redirect clients to the leader and
+// survive the loss of the leader
+#[test]
+fn cluster_over_tcp() -> Result<()> {
+    let peers: Vec<Peer> = (1..=3)
+        .map(|id| Peer {
+            id,
+            client_addr: format!("127.0.0.1:{}", 4040 + id),
+            raft_addr: format!("127.0.0.1:{}", 4140 + id),
+        })
+        .collect();
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut members: Vec<Option<Member>> = Vec::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        members.push(Some(start_member(dir, i as NodeId + 1, &peers)?));
+    }
+
+    let leader = find_leader(&peers, &[]);
+    let follower = peers.iter().find(|peer| peer.id != leader).unwrap();
+    let mut client = KvClient::connect(follower.client_addr.as_str())?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsErr::NotLeader(_))
+    ));
+    client.set_follow_redirects(true);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    stop_member(members[leader as usize - 1].take().unwrap())?;
+
+    let new = find_leader(&peers, &[leader]);
+    assert_ne!(new, leader);
+    let mut client = KvClient::connect(peers[new as usize - 1].client_addr.as_str())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    drop(client);
+
+    for member in members.into_iter().flatten() {
+        stop_member(member)?;
+    }
+    Ok(())
+}
+
+// number of keys in the local copy of a member, once it reached `expected`
+fn wait_for_keys(peer: &Peer, expected: u64) {
+    retry(|| {
+        let keys = KvClient::connect(peer.client_addr.as_str())?.stats()?.keys;
+        if keys == expected {
+            Ok(())
+        } else {
+            Err(KvsErr::StringErr(format!("{} keys so far", keys)))
+        }
+    })
+}
+
+// Members keep their vote, log and snapshot on disk: a follower that was down
+// catches up from a snapshot, and the whole cluster comes back after a restart
+#[test]
+fn cluster_restarts_from_disk() -> Result<()> {
+    let peers: Vec<Peer> = (1..=3)
+        .map(|id| Peer {
+            id,
+            client_addr: format!("127.0.0.1:{}", 4082 + id),
+            raft_addr: format!("127.0.0.1:{}", 4182 + id),
+        })
+        .collect();
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let mut members: Vec<Option<Member>> = Vec::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        members.push(Some(start_member(dir, i as NodeId + 1, &peers)?));
+    }
+
+    let leader = find_leader(&peers, &[]);
+    let follower = peers.iter().find(|peer| peer.id != leader).unwrap();
+    stop_member(members[follower.id as usize - 1].take().unwrap())?;
+
+    // enough entries for the leader to replace its log with a snapshot
+    let mut client = KvClient::connect(peers[leader as usize - 1].client_addr.as_str())?;
+    for chunk in 0..11 {
+        let pairs = (chunk * 100..(chunk + 1) * 100)
+            .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+            .collect();
+        client.set_batch(pairs)?;
+    }
+    drop(client);
+
+    members[follower.id as usize - 1] = Some(start_member(
+        &dirs[follower.id as usize - 1],
+        follower.id,
+        &peers,
+    )?);
+    wait_for_keys(follower, 1100);
+
+    for member in members.iter_mut() {
+        stop_member(member.take().unwrap())?;
+    }
+    for (i, dir) in dirs.iter().enumerate() {
+        members[i] = Some(start_member(dir, i as NodeId + 1, &peers)?);
+    }
+    let leader = find_leader(&peers, &[]);
+    let mut client = KvClient::connect(peers[leader as usize - 1].client_addr.as_str())?;
+    assert_eq!(client.get("key0000".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(
+        client.get("key1099".to_owned())?,
+        Some("value1099".to_owned())
+    );
+    client.set("after".to_owned(), "restart".to_owned())?;
+    drop(client);
+    for peer in &peers {
+        wait_for_keys(peer, 1101);
+    }
+
+    for member in members.into_iter().flatten() {
+        stop_member(member)?;
+    }
+    Ok(())
+}
+
+// Connections to the Raft port are dropped unless they present the token first
+#[test]
+fn raft_port_needs_the_token() -> Result<()> {
+    let peers: Vec<Peer> = (1..=3)
+        .map(|id| Peer {
+            id,
+            client_addr: format!("127.0.0.1:{}", 4085 + id),
+            raft_addr: format!("127.0.0.1:{}", 4185 + id),
+        })
+        .collect();
+    let dir = TempDir::new().unwrap();
+    let member = start_member(&dir, 1, &peers)?;
+
+    let present = |token: &str| -> Result<bool> {
+        let mut stream = retry(|| Ok(TcpStream::connect(peers[0].raft_addr.as_str())?));
+        write!(stream, "{{\"token\":{:?}}}", token)?;
+        stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+        // a refused connection is closed, an accepted one waits for messages
+        Ok(match stream.read(&mut [0; 16]) {
+            Ok(0) => false,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => false,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                true
+            }
+            other => panic!("unexpected read: {:?}", other),
+        })
+    };
+    assert!(!present("wrong")?);
+    assert!(present(TOKEN)?);
+
+    stop_member(member)
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use common::Node;
+use kvs::{KvClient, KvEngine, KvStore, KvsErr, Result, ServerConfig};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// start a server on the store in `dir`, following `leader` when given
+fn start(dir: &Path, addr: &'static str, leader: Option<&str>) -> Result<Node> {
+    let config = ServerConfig {
+        replica_of: leader.map(str::to_owned),
+        replica_state: Some(dir.join("replication.json")),
+        backlog_file: Some(dir.join("backlog.json")),
+        ..ServerConfig::default()
+    };
+    common::start(&dir.join("data"), addr, config)
+}
+
+// poll `addr` until `key` has the expected value
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    let mut client = KvClient::connect(addr)?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let value = client.get(key.to_owned())?;
+        if value.as_deref() == expected {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            panic!(
+                "{} is {:?} on {}, expected {:?}",
+                key, value, addr, expected
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// A follower copies the leader's data, then keeps up with every kind of write
+#[test]
+fn follower_receives_snapshot_and_writes() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start(leader_dir.path(), "127.0.0.1:4030", None)?;
+    let mut client = KvClient::connect("127.0.0.1:4030")?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4031",
+        Some("127.0.0.1:4030"),
+    )?;
+    wait_for("127.0.0.1:4031", "key99", Some("value99"))?;
+
+    client.set("key1".to_owned(), "changed".to_owned())?;
+    client.remove("key2".to_owned())?;
+    client.set_batch(vec![
+        ("batch1".to_owned(), "value1".to_owned()),
+        ("batch2".to_owned(), "value2".to_owned()),
+    ])?;
+    wait_for("127.0.0.1:4031", "batch2", Some("value2"))?;
+    wait_for("127.0.0.1:4031", "key1", Some("changed"))?;
+    wait_for("127.0.0.1:4031", "key2", None)?;
+
+    drop(client);
+    follower.stop()?;
+    leader.stop()
+}
+
+// Writes sent to a follower are refused with the address of the leader
+#[test]
+fn follower_rejects_writes() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start(leader_dir.path(), "127.0.0.1:4032", None)?;
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4033",
+        Some("127.0.0.1:4032"),
+    )?;
+
+    let mut client = KvClient::connect("127.0.0.1:4033")?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(KvsErr::ReadOnly(leader)) => assert_eq!(leader, "127.0.0.1:4032"),
+        other => panic!("expected a read-only error, got {:?}", other),
+    }
+    assert!(client.remove("key1".to_owned()).is_err());
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    drop(client);
+    follower.stop()?;
+    leader.stop()
+}
+
+// A restarted follower resumes from its last applied write instead of taking a new snapshot
+#[test]
+fn follower_catches_up_after_restart() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start(leader_dir.path(), "127.0.0.1:4034", None)?;
+    let mut client = KvClient::connect("127.0.0.1:4034")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4035",
+        Some("127.0.0.1:4034"),
+    )?;
+    wait_for("127.0.0.1:4035", "key1", Some("value1"))?;
+    follower.stop()?;
+
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    // a snapshot would drop this key, resuming from the saved position keeps it
+    {
+        let mut store = KvStore::open(follower_dir.path().join("data"))?;
+        store.set("local".to_owned(), "only".to_owned())?;
+    }
+
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4035",
+        Some("127.0.0.1:4034"),
+    )?;
+    wait_for("127.0.0.1:4035", "key2", Some("value2"))?;
+    wait_for("127.0.0.1:4035", "key1", None)?;
+    wait_for("127.0.0.1:4035", "local", Some("only"))?;
+    follower.stop()?;
+
+    // a leader shut down cleanly keeps its backlog, so the follower resumes again
+    drop(client);
+    leader.stop()?;
+    let leader = start(leader_dir.path(), "127.0.0.1:4034", None)?;
+    KvClient::connect("127.0.0.1:4034")?.set("key3".to_owned(), "value3".to_owned())?;
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4035",
+        Some("127.0.0.1:4034"),
+    )?;
+    wait_for("127.0.0.1:4035", "key3", Some("value3"))?;
+    wait_for("127.0.0.1:4035", "local", Some("only"))?;
+    follower.stop()?;
+
+    // without its backlog, as after a crash, the follower copies everything again
+    leader.stop()?;
+    std::fs::remove_file(leader_dir.path().join("backlog.json"))?;
+    let leader = start(leader_dir.path(), "127.0.0.1:4034", None)?;
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4035",
+        Some("127.0.0.1:4034"),
+    )?;
+    wait_for("127.0.0.1:4035", "local", None)?;
+    wait_for("127.0.0.1:4035", "key3", Some("value3"))?;
+
+    follower.stop()?;
+    leader.stop()
+}
+
+// A snapshot larger than a batch replaces the follower's data, stale keys included
+#[test]
+fn large_snapshot_replaces_follower_data() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let leader = start(leader_dir.path(), "127.0.0.1:4081", None)?;
+    let mut client = KvClient::connect("127.0.0.1:4081")?;
+    client.set_batch(
+        (0..3000)
+            .map(|i| (format!("key{:04}", i), format!("value{}", i)))
+            .collect(),
+    )?;
+    {
+        let mut store = KvStore::open(follower_dir.path().join("data"))?;
+        store.set("key1500a".to_owned(), "stale".to_owned())?;
+        store.set("key9999".to_owned(), "stale".to_owned())?;
+    }
+
+    let follower = start(
+        follower_dir.path(),
+        "127.0.0.1:4082",
+        Some("127.0.0.1:4081"),
+    )?;
+    wait_for("127.0.0.1:4082", "key9999", None)?;
+    wait_for("127.0.0.1:4082", "key1500a", None)?;
+    wait_for("127.0.0.1:4082", "key2999", Some("value2999"))?;
+    let mut replica = KvClient::connect("127.0.0.1:4082")?;
+    assert_eq!(replica.stats()?.keys, 3000);
+
+    drop(client);
+    drop(replica);
+    follower.stop()?;
+    leader.stop()
+}
@@ -0,0 +1,327 @@
+use kvs::engines::SledKvsEngine;
+use kvs::{KvClient, KvEngine, KvServer, KvStore, KvsErr, Result, ServerConfig};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Shutting down should stop the server and leave every acknowledged write on disk
+#[test]
+fn shutdown_flushes_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvServer::new(KvStore::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run("127.0.0.1:4010"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::connect("127.0.0.1:4010")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the connection is still open while the server shuts down
+    handle.shutdown();
+    server.join().expect("server thread panicked")?;
+    drop(client);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A client that keeps the connection busy is cut off once the shutdown deadline passes
+#[test]
+fn shutdown_respects_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        shutdown_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let server = KvServer::with_config(KvStore::open(temp_dir.path())?, config);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run("127.0.0.1:4011"));
+    thread::sleep(Duration::from_millis(500));
+
+    let client = thread::spawn(|| -> Result<()> {
+        let mut client = KvClient::connect("127.0.0.1:4011")?;
+        for i in 0.. {
+            client.set(format!("key{}", i), "value".to_owned())?;
+        }
+        Ok(())
+    });
+    thread::sleep(Duration::from_millis(200));
+    handle.shutdown();
+    server.join().expect("server thread panicked")?;
+    assert!(client.join().expect("client thread panicked").is_err());
+    Ok(())
+}
+
+fn start_server(temp_dir: &TempDir, addr: &'static str, config: ServerConfig) -> Result<()> {
+    let server = KvServer::with_config(KvStore::open(temp_dir.path())?, config);
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+    Ok(())
+}
+
+// A silent connection should neither block other clients nor stay open forever
+#[test]
+fn idle_connection_is_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..ServerConfig::default()
+    };
+    start_server(&temp_dir, "127.0.0.1:4012", config)?;
+
+    let mut idle = TcpStream::connect("127.0.0.1:4012")?;
+    let mut client = KvClient::connect("127.0.0.1:4012")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let start = Instant::now();
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0; 16])?, 0);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+// A request that never finishes arriving is dropped after the read timeout
+#[test]
+fn partial_request_times_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        read_timeout: Some(Duration::from_millis(300)),
+        ..ServerConfig::default()
+    };
+    start_server(&temp_dir, "127.0.0.1:4013", config)?;
+
+    let mut stream = TcpStream::connect("127.0.0.1:4013")?;
+    stream.write_all(br#"{"request":{"Get":"#)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let start = Instant::now();
+    assert_eq!(stream.read(&mut [0; 16])?, 0);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    Ok(())
+}
+
+// Connections above the limit get an error instead of a response
+#[test]
+fn max_connections_rejects_excess_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_connections: 1,
+        ..ServerConfig::default()
+    };
+    start_server(&temp_dir, "127.0.0.1:4014", config)?;
+
+    let mut first = KvClient::connect("127.0.0.1:4014")?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut second = KvClient::connect("127.0.0.1:4014")?;
+    thread::sleep(Duration::from_millis(200));
+    match second.get("key1".to_owned()) {
+        Err(KvsErr::TooManyConnections) => {}
+        res => panic!("expected too many connections, got {:?}", res),
+    }
+
+    // the slot is given back once the first client leaves
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut third = KvClient::connect("127.0.0.1:4014")?;
+    assert_eq!(third.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Scans are cut to the server's limit, and paging through them still sees every key
+#[test]
+fn scan_limit_is_capped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        max_scan_limit: 10,
+        ..ServerConfig::default()
+    };
+    start_server(&temp_dir, "127.0.0.1:4078", config)?;
+
+    let mut client = KvClient::connect("127.0.0.1:4078")?;
+    let pairs: Vec<_> = (0..25)
+        .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+        .collect();
+    client.set_batch(pairs.clone())?;
+    assert_eq!(client.scan(None, 1000)?, pairs[..10].to_vec());
+    let mut exported = Vec::new();
+    kvs::bulk::export(&mut client, &mut exported, kvs::bulk::Format::Jsonl)?;
+    assert_eq!(String::from_utf8(exported).unwrap().lines().count(), 25);
+    Ok(())
+}
+
+// send `frame` on a connection of its own and return the response
+fn raw_request(addr: &str, frame: &str) -> serde_json::Value {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(frame.as_bytes()).unwrap();
+    serde_json::Deserializer::from_reader(stream)
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap()
+}
+
+// A request whose deadline has already passed is answered with an error and not applied
+#[test]
+fn expired_request_is_not_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server(&temp_dir, "127.0.0.1:4015", ServerConfig::default())?;
+
+    let expired = r#"{"request":{"Set":{"key":"key1","value":"value1"}},"deadline_unix_ms":1}"#;
+    assert_eq!(
+        raw_request("127.0.0.1:4015", expired),
+        serde_json::json!({"Err": "Deadline exceeded"})
+    );
+
+    let mut client = KvClient::connect("127.0.0.1:4015")?;
+    client.set_deadline(Some(Duration::from_secs(5)));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Requests sent without an envelope, as by older clients, are still served
+#[test]
+fn bare_request_is_served() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_server(&temp_dir, "127.0.0.1:4077", ServerConfig::default())?;
+
+    let set = r#"{"Set":{"key":"key1","value":"value1"}}"#;
+    assert_eq!(
+        raw_request("127.0.0.1:4077", set),
+        serde_json::json!({"Ok": null})
+    );
+    assert_eq!(
+        raw_request("127.0.0.1:4077", r#"{"Get":{"key":"key1"}}"#),
+        serde_json::json!({"Ok": "value1"})
+    );
+    Ok(())
+}
+
+// fetch `path` from the HTTP endpoint at `addr`, returning the whole response
+fn http_get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+// The metrics endpoint counts requests and errors and reports the engine's state
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        metrics_addr: Some("127.0.0.1:4017".parse().unwrap()),
+        ..ServerConfig::default()
+    };
+    let server = KvServer::with_config(KvStore::open(temp_dir.path())?, config);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run("127.0.0.1:4016"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::connect("127.0.0.1:4016")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("missing".to_owned()),
+        Err(KvsErr::KeyNotFound)
+    ));
+
+    let response = http_get("127.0.0.1:4017", "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    for line in &[
+        "kvs_requests_total{command=\"set\"} 3",
+        "kvs_requests_total{command=\"get\"} 1",
+        "kvs_requests_total{command=\"remove\"} 1",
+        "kvs_request_duration_seconds_bucket{command=\"set\",le=\"+Inf\"} 3",
+        "kvs_request_duration_seconds_count{command=\"get\"} 1",
+        "kvs_errors_total{kind=\"key_not_found\"} 1",
+        "kvs_open_connections 1",
+        "kvs_keys 2",
+        "kvs_log_files 1",
+        "kvs_compactions_total 0",
+    ] {
+        assert!(response.lines().any(|l| l == *line), "{} missing", line);
+    }
+    let bytes_read = response
+        .lines()
+        .find_map(|l| l.strip_prefix("kvs_read_bytes_total "))
+        .unwrap();
+    assert!(bytes_read.parse::<u64>().unwrap() > 0);
+    assert!(http_get("127.0.0.1:4017", "/other").starts_with("HTTP/1.1 404"));
+
+    drop(client);
+    handle.shutdown();
+    server.join().expect("server thread panicked")?;
+    Ok(())
+}
+
+// Admin requests describe the server and compact and flush its engine
+#[test]
+fn admin_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = ServerConfig {
+        data_dir: Some(temp_dir.path().to_owned()),
+        ..ServerConfig::default()
+    };
+    let server = KvServer::with_config(KvStore::open(temp_dir.path())?, config);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || server.run("127.0.0.1:4018"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvClient::connect("127.0.0.1:4018")?;
+    for i in 0..100 {
+        client.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    let info = client.info()?;
+    assert_eq!(info.engine, "kvs");
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.data_dir, Some(temp_dir.path().display().to_string()));
+    assert_eq!(info.keys, 10);
+    assert!(info.disk_bytes > 0);
+
+    let before = client.stats()?;
+    assert_eq!(before.keys, 10);
+    assert!(before.uncompacted_bytes > 0);
+    assert_eq!(before.compactions, 0);
+    assert_eq!(before.disk_bytes, info.disk_bytes);
+
+    client.compact()?;
+    let after = client.stats()?;
+    assert_eq!(after.keys, 10);
+    assert_eq!(after.uncompacted_bytes, 0);
+    assert_eq!(after.compactions, 1);
+    assert!(after.disk_bytes < before.disk_bytes);
+    client.flush()?;
+    assert_eq!(client.get("key3".to_owned())?, Some("value93".to_owned()));
+
+    drop(client);
+    handle.shutdown();
+    server.join().expect("server thread panicked")?;
+    Ok(())
+}
+
+// sled reports its own statistics and can not be compacted on request
+#[test]
+fn sled_stats_and_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(engine.compact().is_err());
+    let stats = engine.stats()?;
+    assert_eq!(engine.name(), "sled");
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.disk_bytes > 0);
+    Ok(())
+}
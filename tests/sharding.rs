@@ -0,0 +1,145 @@
+mod common;
+
+use common::start;
+use kvs::{HashRing, KvClient, Result, ServerConfig, ShardedKvClient};
+use std::collections::BTreeMap;
+use tempfile::TempDir;
+
+// every key stored on `addr`
+fn keys_on(addr: &str) -> Result<Vec<String>> {
+    let mut client = KvClient::connect(addr)?;
+    Ok(client
+        .scan(None, usize::MAX)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect())
+}
+
+// Keys spread over every node, and adding a node only takes keys from the others
+#[test]
+fn ring_is_balanced_and_stable() {
+    let mut ring = HashRing::new(160);
+    for node in &["a", "b", "c"] {
+        ring.add(node);
+    }
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+    let before: Vec<String> = keys
+        .iter()
+        .map(|key| ring.node_for(key).unwrap().to_owned())
+        .collect();
+    let mut counts = BTreeMap::new();
+    for node in &before {
+        *counts.entry(node.as_str()).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 3);
+    assert!(counts.values().all(|&count| count > 700), "{:?}", counts);
+
+    ring.add("d");
+    for (key, old) in keys.iter().zip(&before) {
+        let new = ring.node_for(key).unwrap();
+        assert!(new == old || new == "d");
+    }
+    ring.remove("d");
+    for (key, old) in keys.iter().zip(&before) {
+        assert_eq!(ring.node_for(key).unwrap(), old);
+    }
+}
+
+// Single and multi-key operations reach the owning server and merge back in order
+#[test]
+fn sharded_operations() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let addrs = ["127.0.0.1:4050", "127.0.0.1:4051", "127.0.0.1:4052"];
+    let nodes = dirs
+        .iter()
+        .zip(&addrs)
+        .map(|(dir, addr)| start(dir.path(), addr, ServerConfig::default()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut client = ShardedKvClient::connect(&addrs)?;
+
+    let pairs: Vec<(String, String)> = (0..300)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+    client.mset(pairs.clone())?;
+    client.set("single".to_owned(), "one".to_owned())?;
+    assert_eq!(client.get("single".to_owned())?, Some("one".to_owned()));
+    client.remove("single".to_owned())?;
+    assert_eq!(client.get("single".to_owned())?, None);
+
+    // every key sits on its owner and every server got some
+    let ring = {
+        let mut ring = HashRing::new(kvs::DEFAULT_VNODES);
+        addrs.iter().for_each(|addr| ring.add(addr));
+        ring
+    };
+    for addr in &addrs {
+        let keys = keys_on(addr)?;
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|key| ring.node_for(key) == Some(*addr)));
+    }
+
+    let keys = vec![
+        "key007".to_owned(),
+        "missing".to_owned(),
+        "key123".to_owned(),
+    ];
+    assert_eq!(
+        client.mget(keys)?,
+        vec![Some("value7".to_owned()), None, Some("value123".to_owned())]
+    );
+    assert_eq!(client.scan(None, 10)?, pairs[..10].to_vec());
+    assert_eq!(
+        client.scan(Some("key100".to_owned()), 5)?,
+        pairs[101..106].to_vec()
+    );
+
+    for node in nodes {
+        node.stop()?;
+    }
+    Ok(())
+}
+
+// Rebalancing after adding then removing a server moves only the affected keys,
+// without overwriting the ones written to their new owner in the meantime
+#[test]
+fn rebalance_after_membership_changes() -> Result<()> {
+    let dirs: Vec<TempDir> = (0..3).map(|_| TempDir::new().unwrap()).collect();
+    let addrs = ["127.0.0.1:4053", "127.0.0.1:4054", "127.0.0.1:4055"];
+    let nodes = dirs
+        .iter()
+        .zip(&addrs)
+        .map(|(dir, addr)| start(dir.path(), addr, ServerConfig::default()))
+        .collect::<Result<Vec<_>>>()?;
+    let pairs: Vec<(String, String)> = (0..300)
+        .map(|i| (format!("key{:03}", i), format!("value{}", i)))
+        .collect();
+
+    let mut client = ShardedKvClient::connect(&addrs[..2])?;
+    client.mset(pairs.clone())?;
+    assert_eq!(client.rebalance()?, 0);
+
+    client.add_node(addrs[2])?;
+    let mut pairs = pairs;
+    for (key, value) in pairs.iter_mut().take(20) {
+        *value = format!("new {}", value);
+        client.set(key.clone(), value.clone())?;
+    }
+    let written = keys_on(addrs[2])?.len() as u64;
+    assert!(written > 0, "no updated key moved");
+    let moved = client.rebalance()?;
+    assert!(moved > 0 && moved < 300, "moved {}", moved);
+    assert_eq!(keys_on(addrs[2])?.len() as u64, written + moved);
+    assert_eq!(client.scan(None, 1000)?, pairs);
+
+    client.remove_node(addrs[0])?;
+    let on_first = keys_on(addrs[0])?.len() as u64;
+    assert_eq!(client.rebalance()?, on_first);
+    assert!(keys_on(addrs[0])?.is_empty());
+    assert_eq!(client.nodes(), vec![addrs[1], addrs[2]]);
+    assert_eq!(client.scan(None, 1000)?, pairs);
+
+    for node in nodes {
+        node.stop()?;
+    }
+    Ok(())
+}
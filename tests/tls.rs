@@ -0,0 +1,244 @@
+mod common;
+
+use common::start;
+use kvs::auth::{Auth, Credentials};
+use kvs::tls::{ClientTls, ServerTls};
+use kvs::{KvClient, Result, ServerConfig, ShardedKvClient};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// A throwaway CA and certificates it signed for 127.0.0.1, made with the openssl tool.
+struct Certs {
+    dir: PathBuf,
+}
+
+impl Certs {
+    fn generate(dir: &Path) -> Certs {
+        let certs = Certs {
+            dir: dir.to_owned(),
+        };
+        certs.openssl(
+            "req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+             -keyout ca.key -out ca.pem -days 1 -subj /CN=kvs-test-ca \
+             -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign",
+        );
+        certs.sign("server", "serverAuth");
+        certs.sign("client", "clientAuth");
+        certs
+    }
+
+    fn sign(&self, name: &str, usage: &str) {
+        let ext = format!(
+            "subjectAltName=IP:127.0.0.1\nbasicConstraints=CA:FALSE\nextendedKeyUsage={}\n",
+            usage
+        );
+        fs::write(self.path(&format!("{}.ext", name)), ext).unwrap();
+        self.openssl(&format!(
+            "req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+             -keyout {0}.key -out {0}.csr -subj /CN=127.0.0.1",
+            name
+        ));
+        self.openssl(&format!(
+            "x509 -req -in {0}.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days 1 \
+             -out {0}.pem -extfile {0}.ext",
+            name
+        ));
+    }
+
+    fn openssl(&self, args: &str) {
+        let output = Command::new("openssl")
+            .args(args.split_whitespace())
+            .current_dir(&self.dir)
+            .output()
+            .expect("openssl must be installed to generate test certificates");
+        assert!(
+            output.status.success(),
+            "openssl {} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+}
+
+// Clients trusting the server's CA talk to it over TLS, others cannot
+#[test]
+fn tls_connections() -> Result<()> {
+    let (temp_dir, cert_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let certs = Certs::generate(cert_dir.path());
+    let config = ServerConfig {
+        tls: Some(ServerTls::new(
+            certs.path("server.pem"),
+            certs.path("server.key"),
+        )?),
+        ..ServerConfig::default()
+    };
+    let node = start(temp_dir.path(), "127.0.0.1:4070", config)?;
+
+    let tls = ClientTls::new(certs.path("ca.pem"))?;
+    let mut client = KvClient::connect_tls("127.0.0.1:4070", tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // plaintext requests are not understood
+    let mut plain = KvClient::connect("127.0.0.1:4070")?;
+    assert!(plain.get("key1".to_owned()).is_err());
+    // a certificate from another CA is not trusted
+    let other_dir = TempDir::new().unwrap();
+    let other = Certs::generate(other_dir.path());
+    let tls = ClientTls::new(other.path("ca.pem"))?;
+    let mut untrusting = KvClient::connect_tls("127.0.0.1:4070", tls)?;
+    assert!(untrusting.get("key1".to_owned()).is_err());
+
+    drop(client);
+    node.stop()
+}
+
+// With a client CA, only clients presenting a certificate it signed are served
+#[test]
+fn mutual_tls() -> Result<()> {
+    let (temp_dir, cert_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let certs = Certs::generate(cert_dir.path());
+    let config = ServerConfig {
+        tls: Some(ServerTls::with_client_auth(
+            certs.path("server.pem"),
+            certs.path("server.key"),
+            certs.path("ca.pem"),
+        )?),
+        ..ServerConfig::default()
+    };
+    let node = start(temp_dir.path(), "127.0.0.1:4071", config)?;
+
+    let tls = ClientTls::new(certs.path("ca.pem"))?;
+    let mut anonymous = KvClient::connect_tls("127.0.0.1:4071", tls)?;
+    assert!(anonymous
+        .set("key1".to_owned(), "value1".to_owned())
+        .is_err());
+
+    let tls = ClientTls::with_identity(
+        certs.path("ca.pem"),
+        certs.path("client.pem"),
+        certs.path("client.key"),
+    )?;
+    let mut client = KvClient::connect_tls("127.0.0.1:4071", tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    node.stop()
+}
+
+// A replica follows a leader serving TLS
+#[test]
+fn replica_of_tls_leader() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let cert_dir = TempDir::new().unwrap();
+    let certs = Certs::generate(cert_dir.path());
+    let server_tls = ServerTls::new(certs.path("server.pem"), certs.path("server.key"))?;
+    let client_tls = ClientTls::new(certs.path("ca.pem"))?;
+    let config = ServerConfig {
+        tls: Some(server_tls.clone()),
+        ..ServerConfig::default()
+    };
+    let leader = start(leader_dir.path(), "127.0.0.1:4072", config)?;
+    let mut client = KvClient::connect_tls("127.0.0.1:4072", client_tls.clone())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    let config = ServerConfig {
+        tls: Some(server_tls),
+        replica_of: Some("127.0.0.1:4072".to_owned()),
+        leader_tls: Some(client_tls.clone()),
+        ..ServerConfig::default()
+    };
+    let follower = start(follower_dir.path(), "127.0.0.1:4073", config)?;
+    let mut replica = KvClient::connect_tls("127.0.0.1:4073", client_tls)?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while replica.get("key1".to_owned())?.is_none() {
+        assert!(Instant::now() < deadline, "key1 never reached the replica");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    drop(replica);
+    follower.stop()?;
+    leader.stop()
+}
+
+// A redirect to a leader that is down leaves the client its TLS config and credentials
+#[test]
+fn failed_redirect_keeps_tls() -> Result<()> {
+    let (leader_dir, follower_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let cert_dir = TempDir::new().unwrap();
+    let certs = Certs::generate(cert_dir.path());
+    let client_tls = ClientTls::new(certs.path("ca.pem"))?;
+    let credentials = Credentials::Token("secret".to_owned());
+    let config = ServerConfig {
+        tls: Some(ServerTls::new(
+            certs.path("server.pem"),
+            certs.path("server.key"),
+        )?),
+        auth: Some(Auth::Token("secret".to_owned())),
+        ..ServerConfig::default()
+    };
+    let follower_config = ServerConfig {
+        replica_of: Some("127.0.0.1:4090".to_owned()),
+        leader_tls: Some(client_tls.clone()),
+        leader_credentials: Some(credentials.clone()),
+        ..config.clone()
+    };
+    let follower = start(follower_dir.path(), "127.0.0.1:4091", follower_config)?;
+    let mut client = KvClient::connect_tls_with_auth("127.0.0.1:4091", client_tls, credentials)?;
+    client.set_follow_redirects(true);
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+
+    let leader = start(leader_dir.path(), "127.0.0.1:4090", config)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(client);
+    follower.stop()?;
+    leader.stop()
+}
+
+// Keys are sharded over servers serving TLS, and rebalanced between them
+#[test]
+fn sharding_over_tls() -> Result<()> {
+    let (dir1, dir2) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let cert_dir = TempDir::new().unwrap();
+    let certs = Certs::generate(cert_dir.path());
+    let config = ServerConfig {
+        tls: Some(ServerTls::new(
+            certs.path("server.pem"),
+            certs.path("server.key"),
+        )?),
+        ..ServerConfig::default()
+    };
+    let node1 = start(dir1.path(), "127.0.0.1:4092", config.clone())?;
+    let node2 = start(dir2.path(), "127.0.0.1:4093", config)?;
+    let tls = ClientTls::new(certs.path("ca.pem"))?;
+
+    let mut client = ShardedKvClient::connect_tls(&["127.0.0.1:4092"], tls)?;
+    for i in 0..50 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.add_node("127.0.0.1:4093")?;
+    assert!(client.rebalance()? > 0);
+    for i in 0..50 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    drop(client);
+    node1.stop()?;
+    node2.stop()
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use common::{file_names, open_value_log};
+use kvs::backup;
+use kvs::bulk;
+use kvs::engines::{dump, fsck, Keyring, LogOp};
+use kvs::{KvEngine, KvStore, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+// total size of the files of `dir` with the given extension
+fn file_bytes(dir: &Path, extension: &str) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+fn large_value(i: usize) -> String {
+    format!("{:04}", i).repeat(1024)
+}
+
+// Large values go to the value log, small ones stay in the log files
+#[test]
+fn large_values_separated() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_value_log(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("large{}", i), large_value(i))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    assert!(file_bytes(temp_dir.path(), "vlog") > 10 * 4096);
+    assert!(file_bytes(temp_dir.path(), "log") < 4096);
+
+    // reading needs no configuration
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("large{}", i))?, Some(large_value(i)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    let pairs = store.scan(None, 100)?;
+    assert_eq!(pairs.len(), 11);
+    assert_eq!(pairs[0], ("large0".to_owned(), large_value(0)));
+    Ok(())
+}
+
+// Compacting the log files leaves the value log alone
+#[test]
+fn compaction_skips_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_value_log(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("large{}", i), large_value(i))?;
+    }
+    for i in 0..1000 {
+        store.set("small".to_owned(), format!("value{}", i))?;
+    }
+    let value_log = fs::read(
+        temp_dir
+            .path()
+            .join(&file_names(temp_dir.path(), "vlog")[0]),
+    )?;
+    KvEngine::compact(&mut store)?;
+    assert_eq!(
+        fs::read(
+            temp_dir
+                .path()
+                .join(&file_names(temp_dir.path(), "vlog")[0])
+        )?,
+        value_log
+    );
+    assert!(file_bytes(temp_dir.path(), "log") < 4096);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large3".to_owned())?, Some(large_value(3)));
+    assert_eq!(store.get("small".to_owned())?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Compaction moves large values written before the threshold was set
+#[test]
+fn compaction_separates_old_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("large".to_owned(), large_value(1))?;
+    store.set_value_log_threshold(Some(1024));
+    assert!(file_names(temp_dir.path(), "vlog").is_empty());
+    KvEngine::compact(&mut store)?;
+    assert!(file_bytes(temp_dir.path(), "log") < 1024);
+    assert_eq!(store.get("large".to_owned())?, Some(large_value(1)));
+    Ok(())
+}
+
+// Stale values are collected once past the threshold, moving the live ones
+#[test]
+fn value_log_collection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_value_log(temp_dir.path())?;
+    store.set_value_log_gc_threshold(64 * 1024);
+    store.set("kept".to_owned(), large_value(0))?;
+    for i in 0..10 {
+        store.set("overwritten".to_owned(), large_value(i))?;
+    }
+    assert_eq!(file_names(temp_dir.path(), "vlog"), vec!["1.vlog"]);
+    for i in 10..20 {
+        store.set("overwritten".to_owned(), large_value(i))?;
+    }
+    store.remove("overwritten".to_owned())?;
+    // the first file was collected once mostly stale, its live values moved
+    assert!(!file_names(temp_dir.path(), "vlog").contains(&"1.vlog".to_owned()));
+    assert!(file_bytes(temp_dir.path(), "vlog") < 64 * 1024);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("kept".to_owned())?, Some(large_value(0)));
+    assert_eq!(store.get("overwritten".to_owned())?, None);
+    store.collect_value_log()?;
+    assert_eq!(store.get("kept".to_owned())?, Some(large_value(0)));
+    Ok(())
+}
+
+// The value log is compressed and encrypted like the log files
+#[test]
+fn value_log_compressed_and_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let keyring = || Keyring::new(vec![vec![1; 32]]).unwrap();
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring())?;
+    store.set_value_log_threshold(Some(1024));
+    store.set_compression_threshold(Some(1024));
+    store.set("large".to_owned(), large_value(1))?;
+    drop(store);
+    assert!(file_bytes(temp_dir.path(), "vlog") > 0);
+    assert!(file_bytes(temp_dir.path(), "vlog") < 1024);
+    assert!(!fs::read_to_string(temp_dir.path().join("1.vlog"))?.contains("large"));
+
+    let mut store = KvStore::open_encrypted(temp_dir.path(), keyring())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large_value(1)));
+    Ok(())
+}
+
+// Offline tools follow the pointers, and fsck reports values gone missing
+#[test]
+fn fsck_and_dump_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_value_log(temp_dir.path())?;
+    store.set("large".to_owned(), large_value(1))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let report = fsck(temp_dir.path(), false)?;
+    assert!(report.is_clean());
+    assert!(report.orphans.is_empty());
+    let mut ops = Vec::new();
+    dump(temp_dir.path(), |record| {
+        ops.push(record.op);
+        Ok(())
+    })?;
+    assert_eq!(
+        ops[0],
+        LogOp::Set {
+            key: "large".to_owned(),
+            value: large_value(1)
+        }
+    );
+
+    fs::write(temp_dir.path().join("1.vlog"), "")?;
+    let report = fsck(temp_dir.path(), true)?;
+    assert_eq!(report.missing_values, vec!["large".to_owned()]);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, None);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Dead pointers are not followed, their values may have been collected
+#[test]
+fn dump_after_collection() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = open_value_log(temp_dir.path())?;
+    store.set("large".to_owned(), large_value(0))?;
+    store.set("large".to_owned(), large_value(1))?;
+    store.collect_value_log()?;
+    assert!(!file_names(temp_dir.path(), "vlog").contains(&"1.vlog".to_owned()));
+    drop(store);
+
+    let mut records = Vec::new();
+    dump(temp_dir.path(), |record| {
+        records.push(record);
+        Ok(())
+    })?;
+    let dead = records.iter().find(|record| !record.live).unwrap();
+    assert!(dead.value_collected);
+    assert!(dead
+        .to_string()
+        .ends_with("dead set \"large\" (value collected)"));
+    let live = records.iter().find(|record| record.live).unwrap();
+    assert!(!live.value_collected);
+    assert_eq!(
+        live.op,
+        LogOp::Set {
+            key: "large".to_owned(),
+            value: large_value(1)
+        }
+    );
+    Ok(())
+}
+
+// Backups carry the value log, incremental ones only what was appended to it
+#[test]
+fn backup_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let data = temp_dir.path().join("data");
+    let (full, increment) = (temp_dir.path().join("full"), temp_dir.path().join("inc"));
+    let mut store = open_value_log(&data)?;
+    store.set("large1".to_owned(), large_value(1))?;
+    store.backup_to(&full)?;
+    store.set("large2".to_owned(), large_value(2))?;
+    store.backup_incremental(&increment, &full)?;
+    let expected = bulk::digest(&mut store)?;
+    drop(store);
+    assert!(file_bytes(&increment, "vlog") < 2 * 4096);
+
+    let restored = temp_dir.path().join("restored");
+    assert_eq!(
+        backup::restore_chain(&[&full, &increment], &restored, None)?,
+        expected
+    );
+    let mut store = KvStore::open(&restored)?;
+    assert_eq!(store.get("large1".to_owned())?, Some(large_value(1)));
+    assert_eq!(store.get("large2".to_owned())?, Some(large_value(2)));
+    Ok(())
+}